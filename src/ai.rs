@@ -0,0 +1,727 @@
+use crate::building::Structure;
+use crate::combat::AttackHitbox;
+use crate::enemies::{spawn_goblin_attack_sensor, Goblin, Waypoint};
+use crate::perception::{line_of_sight, Memory, Perception};
+use crate::physics::{MovementIntent, Physics};
+use crate::prelude::*;
+use crate::spatial::SpatialIndex;
+use crate::squad::SquadCoordinator;
+use crate::world::{MapPart, WorldTiles};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How close to a remembered spot counts as being there.
+const SEARCH_ARRIVAL: f32 = 8.0;
+/// Seconds between quarter turns while looking around.
+const LOOK_AROUND: f32 = 0.75;
+/// How far ahead of itself a goblin checks for a fence in the way.
+const FENCE_LOOKAHEAD: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Success,
+    Failure,
+    Running,
+}
+
+/// A behaviour tree node. Trees are re-evaluated from the root every frame, so anything that
+/// has to last longer than a frame lives on the `Blackboard`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Node {
+    /// Runs children in order until one doesn't fail.
+    Selector(Vec<Node>),
+    /// Runs children in order until one doesn't succeed.
+    Sequence(Vec<Node>),
+    /// Swaps success and failure.
+    Not(Box<Node>),
+    Check(Condition),
+    Do(Action),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Condition {
+    Staggered,
+    Attacking,
+    /// More than this many seconds are left on the rest timer.
+    RestingLongerThan(f32),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Action {
+    /// Plays the stagger animation until the hit wears off, then rests.
+    Stagger { rest: f32 },
+    /// Carries the current attack through, lunging once it's `lunge_after` seconds in.
+    Swing { lunge_after: f32, rest: f32 },
+    /// Stands still while the rest timer runs down.
+    Rest,
+    /// Keeps the current target while it stays in sight, or picks the first of `names` that
+    /// comes into view. Rests for `lost_rest` seconds when the target disappears for good.
+    FindTarget { names: Vec<String>, lost_rest: f32 },
+    /// Walks to where a target was last seen or heard and looks around until the memory fades.
+    Search,
+    /// Starts an attack if the target is in reach.
+    Attack,
+    /// Gets past a fence in the way: walks round through a gap at most `max_detour` tiles to
+    /// either side, or breaks through when there isn't one.
+    GetPast { max_detour: usize },
+    /// Walks towards the target, or to the squad's spot for us when going for the pylon.
+    Approach,
+    /// Follows the waypoint chain.
+    Patrol,
+}
+
+/// What an enemy remembers between frames.
+#[derive(Debug, Clone)]
+pub struct Blackboard {
+    pub waypoint: Entity,
+    pub target: Option<Entity>,
+    /// Seconds left to stand around.
+    pub rest: f32,
+    /// Seconds left staggered from a hit.
+    pub stagger: f32,
+    /// Seconds into the current attack.
+    pub attack: Option<f32>,
+    /// Where we're headed to get round a fence.
+    pub detour: Option<Vector2<f32>>,
+}
+
+impl Blackboard {
+    pub fn resting_at(waypoint: Entity, rest: f32) -> Self {
+        Blackboard {
+            waypoint,
+            target: None,
+            rest,
+            stagger: 0.0,
+            attack: None,
+            detour: None,
+        }
+    }
+
+    /// Interrupts whatever we were doing.
+    pub fn stagger(&mut self, seconds: f32) {
+        self.stagger = seconds;
+        self.attack = None;
+        self.target = None;
+        self.detour = None;
+    }
+}
+
+/// Behaviour trees by archetype name, read from `ai.ron`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AiConfig {
+    pub trees: HashMap<String, Node>,
+}
+
+impl Default for AiConfig {
+    fn default() -> Self {
+        let mut trees = HashMap::new();
+        trees.insert("goblin".to_string(), goblin_tree());
+        AiConfig { trees }
+    }
+}
+
+fn goblin_tree() -> Node {
+    Node::Selector(vec![
+        Node::Sequence(vec![
+            Node::Check(Condition::Staggered),
+            Node::Do(Action::Stagger { rest: 3.0 }),
+        ]),
+        Node::Sequence(vec![
+            Node::Check(Condition::Attacking),
+            Node::Do(Action::Swing {
+                lunge_after: 0.375,
+                rest: 4.0,
+            }),
+        ]),
+        Node::Sequence(vec![
+            Node::Check(Condition::RestingLongerThan(2.0)),
+            Node::Do(Action::Rest),
+        ]),
+        Node::Sequence(vec![
+            Node::Do(Action::FindTarget {
+                names: vec!["pylon".to_string(), "player".to_string()],
+                lost_rest: 4.0,
+            }),
+            Node::Selector(vec![
+                Node::Do(Action::Attack),
+                Node::Do(Action::GetPast { max_detour: 6 }),
+                Node::Do(Action::Approach),
+            ]),
+        ]),
+        Node::Sequence(vec![
+            Node::Check(Condition::RestingLongerThan(0.0)),
+            Node::Do(Action::Rest),
+        ]),
+        Node::Do(Action::Search),
+        Node::Do(Action::GetPast { max_detour: 6 }),
+        Node::Do(Action::Patrol),
+    ])
+}
+
+/// The world as the behaviour tree sees it. It's read-only, so trees for different goblins
+/// can run in parallel; movement goes out through each agent's `MovementIntent`.
+pub struct AiContext<'a, 's> {
+    pub physics: &'a Physics,
+    pub delta: f32,
+    pub entities: &'a Entities<'s>,
+    pub handles: &'a ReadStorage<'s, PhysicsHandle>,
+    pub waypoints: &'a ReadStorage<'s, Waypoint>,
+    pub index: &'a SpatialIndex,
+    pub attacks: &'a ReadStorage<'s, AttackHitbox>,
+    pub map_parts: &'a ReadStorage<'s, MapPart>,
+    pub structures: &'a ReadStorage<'s, Structure>,
+    pub squad: &'a SquadCoordinator,
+    pub tiles: Option<&'a WorldTiles>,
+    pub lazy: &'a LazyUpdate,
+}
+
+/// The enemy the tree is running for.
+pub struct Agent<'a> {
+    pub entity: Entity,
+    pub handle: &'a PhysicsHandle,
+    pub goblin: &'a mut Goblin,
+    pub perception: &'a mut Perception,
+    pub intent: &'a mut MovementIntent,
+    pub control_set: &'a mut AnimationControlSet<AnimationId, SpriteRender>,
+    pub animation_set: &'a AnimationSet<AnimationId, SpriteRender>,
+}
+
+impl<'a> Agent<'a> {
+    fn animate(&mut self, id: AnimationId, end: EndControl) {
+        set_active_animation(self.control_set, id, self.animation_set, end, 1.0);
+    }
+
+    fn walk(&mut self, direction: Direction) {
+        self.goblin.facing = direction;
+        self.intent.velocity = Some(direction.tilts() * self.goblin.walk_speed);
+        self.animate(AnimationId::Walk(direction), EndControl::Loop(None));
+    }
+
+    fn stand(&mut self) {
+        self.intent.velocity = Some(Vector2::zeros());
+        self.animate(
+            AnimationId::Idle(self.goblin.facing),
+            EndControl::Loop(None),
+        );
+    }
+}
+
+pub fn tick(node: &Node, ctx: &AiContext, agent: &mut Agent) -> Status {
+    match node {
+        Node::Selector(children) => {
+            for child in children.iter() {
+                let status = tick(child, ctx, agent);
+                if status != Status::Failure {
+                    return status;
+                }
+            }
+            Status::Failure
+        }
+        Node::Sequence(children) => {
+            for child in children.iter() {
+                let status = tick(child, ctx, agent);
+                if status != Status::Success {
+                    return status;
+                }
+            }
+            Status::Success
+        }
+        Node::Not(child) => match tick(child, ctx, agent) {
+            Status::Success => Status::Failure,
+            Status::Failure => Status::Success,
+            Status::Running => Status::Running,
+        },
+        Node::Check(condition) => {
+            if check(condition, &agent.goblin.blackboard) {
+                Status::Success
+            } else {
+                Status::Failure
+            }
+        }
+        Node::Do(action) => act(action, ctx, agent),
+    }
+}
+
+fn check(condition: &Condition, blackboard: &Blackboard) -> bool {
+    match condition {
+        Condition::Staggered => blackboard.stagger > 0.0,
+        Condition::Attacking => blackboard.attack.is_some(),
+        Condition::RestingLongerThan(seconds) => blackboard.rest > *seconds,
+    }
+}
+
+fn act(action: &Action, ctx: &AiContext, agent: &mut Agent) -> Status {
+    match action {
+        Action::Stagger { rest } => {
+            let blackboard = &mut agent.goblin.blackboard;
+            if blackboard.stagger < ctx.delta {
+                blackboard.stagger = 0.0;
+                blackboard.rest = *rest;
+                Status::Success
+            } else {
+                blackboard.stagger -= ctx.delta;
+                let facing = agent.goblin.facing;
+                agent.animate(AnimationId::Staggered(facing), EndControl::Stay);
+                Status::Running
+            }
+        }
+        Action::Swing { lunge_after, rest } => {
+            let progress = agent.goblin.blackboard.attack.unwrap_or(0.0);
+            if let Some(AnimationId::Attack(_)) = get_active_animation(agent.control_set) {
+                let velocity = if progress > *lunge_after {
+                    agent.goblin.facing.tilts() * agent.goblin.lunge_speed
+                } else {
+                    Vector2::zeros()
+                };
+                agent.intent.velocity = Some(velocity);
+                agent.goblin.blackboard.attack = Some(progress + ctx.delta);
+                Status::Running
+            } else {
+                let blackboard = &mut agent.goblin.blackboard;
+                blackboard.attack = None;
+                blackboard.target = None;
+                blackboard.rest = *rest;
+                Status::Success
+            }
+        }
+        Action::Rest => {
+            for (_, sensor) in ctx.index.sensors_of(agent.entity) {
+                let sensor = *sensor;
+                if ctx.attacks.contains(sensor) {
+                    ctx.lazy.exec(move |world| {
+                        world.delete_entity(sensor).ok();
+                    });
+                }
+            }
+            agent.stand();
+            let blackboard = &mut agent.goblin.blackboard;
+            blackboard.rest = f32::max(blackboard.rest - ctx.delta, 0.0);
+            Status::Running
+        }
+        Action::FindTarget { names, lost_rest } => {
+            if let Some(target) = agent.goblin.blackboard.target {
+                let target_handle = match ctx.handles.get(target) {
+                    Some(handle) => handle,
+                    None => {
+                        agent.goblin.blackboard.target = None;
+                        agent.goblin.blackboard.rest = *lost_rest;
+                        agent.perception.memory = None;
+                        return Status::Failure;
+                    }
+                };
+                // Once we're onto a target we keep track of it whichever way we face.
+                let in_sight = sight_of(ctx, agent, target, target_handle)
+                    .map_or(false, |(_, distance)| {
+                        distance <= agent.perception.sight_range
+                    });
+                if in_sight {
+                    remember(ctx, agent, target_handle);
+                    return Status::Success;
+                }
+                debug!(target: "ai", "Goblin {:?} lost sight of {:?}", agent.entity, target);
+                agent.goblin.blackboard.target = None;
+                return Status::Failure;
+            }
+            let found = names
+                .iter()
+                .filter_map(|name| ctx.index.named(name))
+                .filter_map(|target| ctx.handles.get(target).map(|handle| (target, handle)))
+                .find(|(target, target_handle)| {
+                    sight_of(ctx, agent, *target, target_handle).map_or(
+                        false,
+                        |(offset, distance)| {
+                            agent
+                                .perception
+                                .notices(agent.goblin.facing, offset, distance)
+                        },
+                    )
+                });
+            match found {
+                Some((target, target_handle)) => {
+                    debug!(target: "ai", "Goblin {:?} spotted {:?}", agent.entity, target);
+                    agent.goblin.blackboard.target = Some(target);
+                    remember(ctx, agent, target_handle);
+                    Status::Success
+                }
+                None => Status::Failure,
+            }
+        }
+        Action::Search => {
+            let memory = match agent.perception.memory {
+                Some(memory) if memory.time_left > 0.0 => memory,
+                _ => {
+                    agent.perception.memory = None;
+                    return Status::Failure;
+                }
+            };
+            let location = match ctx.physics.get_location(agent.handle) {
+                Some(location) => location.coords,
+                None => return Status::Failure,
+            };
+            let offset = memory.position - location;
+            if offset.norm() > SEARCH_ARRIVAL {
+                agent.walk(Direction::short_seek(offset, 4.0));
+            } else {
+                let time_left = memory.time_left - ctx.delta;
+                if (memory.time_left / LOOK_AROUND) as usize != (time_left / LOOK_AROUND) as usize {
+                    agent.goblin.facing = agent.goblin.facing.clockwise();
+                }
+                agent.stand();
+            }
+            agent.perception.memory = Some(Memory {
+                time_left: memory.time_left - ctx.delta,
+                ..memory
+            });
+            Status::Running
+        }
+        Action::Attack => {
+            let (target, target_handle) = match target_handle(ctx, agent) {
+                Some(target) => target,
+                None => return Status::Failure,
+            };
+            // Goblins still waiting on a slot around the pylon hold off.
+            if let Some(order) = ctx.squad.order(agent.entity) {
+                let in_position = ctx
+                    .physics
+                    .get_location(agent.handle)
+                    .map_or(false, |location| order.in_position(location.coords));
+                if !in_position {
+                    return Status::Failure;
+                }
+            }
+            let direction = match ctx.physics.get_between(agent.handle, target_handle) {
+                Some(offset) => Direction::long_seek(offset),
+                None => return Status::Failure,
+            };
+            let in_reach = ctx
+                .physics
+                .ray_cast(agent.handle, direction.tilts())
+                .iter()
+                .any(|(seen, distance)| {
+                    *seen == target && *distance < agent.goblin.attack_distance
+                });
+            if !in_reach {
+                return Status::Failure;
+            }
+            agent.goblin.facing = direction;
+            agent.goblin.blackboard.attack = Some(0.0);
+            agent.animate(AnimationId::Attack(direction), EndControl::Stay);
+            spawn_goblin_attack_sensor(
+                ctx.lazy.create_entity(ctx.entities),
+                agent.entity,
+                direction,
+            );
+            Status::Success
+        }
+        Action::GetPast { max_detour } => {
+            let (tiles, location) = match (ctx.tiles, ctx.physics.get_location(agent.handle)) {
+                (Some(tiles), Some(location)) => (tiles, location.coords),
+                _ => return Status::Failure,
+            };
+            if let Some(detour) = agent.goblin.blackboard.detour {
+                let offset = detour - location;
+                if offset.norm() > SEARCH_ARRIVAL {
+                    agent.walk(Direction::short_seek(offset, 4.0));
+                    return Status::Running;
+                }
+                agent.goblin.blackboard.detour = None;
+            }
+            let facing = agent.goblin.facing;
+            let ahead = location + facing.tilts() * FENCE_LOOKAHEAD;
+            let (here, fence) = match (
+                tiles.tile_at(location.x, location.y),
+                tiles.tile_at(ahead.x, ahead.y),
+            ) {
+                (Some(here), Some(fence)) if here != fence && tiles.is_fence(fence) => {
+                    (here, fence)
+                }
+                _ => return Status::Failure,
+            };
+            if let Some(gap) = find_gap(tiles, here, fence, *max_detour) {
+                let (x, y) = tiles.tile_center(gap);
+                debug!(target: "ai", "Goblin {:?} going round the fence at {:?}", agent.entity, fence);
+                agent.goblin.blackboard.detour = Some(Vector2::new(x, y));
+                agent.walk(Direction::short_seek(Vector2::new(x, y) - location, 4.0));
+                return Status::Running;
+            }
+            debug!(target: "ai", "Goblin {:?} breaking through the fence at {:?}", agent.entity, fence);
+            agent.goblin.blackboard.attack = Some(0.0);
+            agent.animate(AnimationId::Attack(facing), EndControl::Stay);
+            spawn_goblin_attack_sensor(ctx.lazy.create_entity(ctx.entities), agent.entity, facing);
+            Status::Success
+        }
+        Action::Approach => {
+            let (_, target_handle) = match target_handle(ctx, agent) {
+                Some(target) => target,
+                None => return Status::Failure,
+            };
+            let location = ctx.physics.get_location(agent.handle).map(|at| at.coords);
+            let offset = match (ctx.squad.order(agent.entity), location) {
+                (Some(order), Some(location)) => Some(order.point() - location),
+                _ => ctx.physics.get_between(agent.handle, target_handle),
+            };
+            match offset {
+                Some(offset) => {
+                    agent.walk(Direction::short_seek(offset, 4.0));
+                    Status::Running
+                }
+                None => Status::Failure,
+            }
+        }
+        Action::Patrol => {
+            let waypoint = agent.goblin.blackboard.waypoint;
+            let (waypoint_handle, current) =
+                match (ctx.handles.get(waypoint), ctx.waypoints.get(waypoint)) {
+                    (Some(handle), Some(current)) => (handle, current),
+                    _ => return Status::Failure,
+                };
+            let offset = match ctx.physics.get_between(agent.handle, waypoint_handle) {
+                Some(offset) => offset,
+                None => return Status::Failure,
+            };
+            if offset.norm_squared() < current.margin {
+                if let Some(next) = current.next {
+                    agent.goblin.blackboard.waypoint = next;
+                }
+            }
+            agent.walk(Direction::short_seek(offset, 4.0));
+            Status::Running
+        }
+    }
+}
+
+/// The nearest tile beside `here` to walk to so that the fence at `fence` is out of the way,
+/// looking up to `max_detour` tiles along the fence each way.
+fn find_gap(
+    tiles: &WorldTiles,
+    here: (usize, usize),
+    fence: (usize, usize),
+    max_detour: usize,
+) -> Option<(usize, usize)> {
+    let ahead = (
+        fence.0 as isize - here.0 as isize,
+        fence.1 as isize - here.1 as isize,
+    );
+    let across = if ahead.0 != 0 { (0, 1) } else { (1, 0) };
+    let tile = |from: (usize, usize), step: isize| {
+        let x = from.0 as isize + across.0 * step;
+        let y = from.1 as isize + across.1 * step;
+        if x < 0 || y < 0 {
+            None
+        } else {
+            Some((x as usize, y as usize))
+        }
+    };
+    let mut sides = [true, true];
+    for distance in 1..=max_detour as isize {
+        for (side, step) in [distance, -distance].iter().enumerate() {
+            if !sides[side] {
+                continue;
+            }
+            match (tile(here, *step), tile(fence, *step)) {
+                (Some(beside), Some(gap)) if tiles.is_open(beside) => {
+                    if tiles.is_open(gap) {
+                        return Some(beside);
+                    }
+                }
+                // Something's in the way along our side, so there's no getting round this way.
+                _ => sides[side] = false,
+            }
+        }
+    }
+    None
+}
+
+fn sight_of(
+    ctx: &AiContext,
+    agent: &Agent,
+    target: Entity,
+    target_handle: &PhysicsHandle,
+) -> Option<(Vector2<f32>, f32)> {
+    line_of_sight(
+        ctx.physics,
+        agent.handle,
+        target_handle,
+        target,
+        ctx.map_parts,
+        ctx.structures,
+    )
+}
+
+fn remember(ctx: &AiContext, agent: &mut Agent, target_handle: &PhysicsHandle) {
+    if let Some(location) = ctx.physics.get_location(target_handle) {
+        agent.perception.remember(location.coords);
+    }
+}
+
+fn target_handle<'a>(
+    ctx: &AiContext<'a, '_>,
+    agent: &Agent,
+) -> Option<(Entity, &'a PhysicsHandle)> {
+    let target = agent.goblin.blackboard.target?;
+    ctx.handles.get(target).map(|handle| (target, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{GridBackend, PhysicsDesc};
+    use ncollide2d::shape::{Ball, ShapeHandle};
+    use nphysics2d::object::{BodyStatus, ColliderDesc, RigidBodyDesc};
+
+    fn spawn_marker(physics: &mut Physics, entity: Entity, x: f32, y: f32) -> PhysicsHandle {
+        let desc = PhysicsDesc::new(
+            RigidBodyDesc::new().status(BodyStatus::Static),
+            ColliderDesc::new(ShapeHandle::new(Ball::new(4.0))).sensor(true),
+        );
+        physics.spawn(entity, &desc, x, y)
+    }
+
+    /// Runs `tree` once for a goblin at the origin headed for a waypoint at `waypoint`, and
+    /// returns the status with the velocity it asked for.
+    fn tick_goblin(tree: &Node, waypoint: (f32, f32), rest: f32) -> (Status, Option<Vector2<f32>>) {
+        let mut world = World::new();
+        world.register::<PhysicsHandle>();
+        world.register::<Waypoint>();
+        world.register::<AttackHitbox>();
+        world.register::<MapPart>();
+        world.register::<Structure>();
+        let mut physics = Physics::with_backend(Box::new(GridBackend::default()));
+        let waypoint_entity = world
+            .create_entity()
+            .with(Waypoint {
+                next: None,
+                margin: 64.0,
+            })
+            .build();
+        let waypoint_handle = spawn_marker(&mut physics, waypoint_entity, waypoint.0, waypoint.1);
+        world
+            .write_storage::<PhysicsHandle>()
+            .insert(waypoint_entity, waypoint_handle)
+            .unwrap();
+        let entity = world.create_entity().build();
+        let handle = spawn_marker(&mut physics, entity, 0.0, 0.0);
+
+        let (entities, handles, waypoints, attacks, map_parts, structures, lazy) = world
+            .system_data::<(
+                Entities<'_>,
+                ReadStorage<'_, PhysicsHandle>,
+                ReadStorage<'_, Waypoint>,
+                ReadStorage<'_, AttackHitbox>,
+                ReadStorage<'_, MapPart>,
+                ReadStorage<'_, Structure>,
+                Read<'_, LazyUpdate>,
+            )>();
+        let index = SpatialIndex::default();
+        let squad = SquadCoordinator::default();
+        let ctx = AiContext {
+            physics: &physics,
+            delta: 0.1,
+            entities: &entities,
+            handles: &handles,
+            waypoints: &waypoints,
+            index: &index,
+            attacks: &attacks,
+            map_parts: &map_parts,
+            structures: &structures,
+            squad: &squad,
+            tiles: None,
+            lazy: &lazy,
+        };
+        let mut goblin = Goblin {
+            walk_speed: 40.0,
+            lunge_speed: 120.0,
+            archetype: "goblin".to_string(),
+            blackboard: Blackboard::resting_at(waypoint_entity, rest),
+            facing: Direction::South,
+            attack_distance: 70.0,
+        };
+        let mut perception = Perception::default();
+        let mut intent = MovementIntent::default();
+        let mut control_set = AnimationControlSet::default();
+        let animation_set = AnimationSet::new();
+        let status = tick(
+            tree,
+            &ctx,
+            &mut Agent {
+                entity,
+                handle: &handle,
+                goblin: &mut goblin,
+                perception: &mut perception,
+                intent: &mut intent,
+                control_set: &mut control_set,
+                animation_set: &animation_set,
+            },
+        );
+        (status, intent.velocity)
+    }
+
+    #[test]
+    fn gap_to_the_right() {
+        let tiles = WorldTiles::from_rows(&[".....", "fff.f", "....."]);
+        assert_eq!(find_gap(&tiles, (2, 2), (2, 1), 3), Some((3, 2)));
+    }
+
+    #[test]
+    fn gap_to_the_left() {
+        let tiles = WorldTiles::from_rows(&[".....", "f.fff", "....."]);
+        assert_eq!(find_gap(&tiles, (2, 2), (2, 1), 3), Some((1, 2)));
+    }
+
+    #[test]
+    fn gap_across_a_vertical_fence() {
+        let tiles = WorldTiles::from_rows(&["..f", "..f", "..f", "...", "..f"]);
+        assert_eq!(find_gap(&tiles, (1, 1), (2, 1), 3), Some((1, 3)));
+    }
+
+    #[test]
+    fn wall_on_our_side_blocks_that_way_round() {
+        // The gap on the right is as close, but the fence beside us is in the way.
+        let tiles = WorldTiles::from_rows(&[".....", ".fff.", "...f."]);
+        assert_eq!(find_gap(&tiles, (2, 2), (2, 1), 3), Some((0, 2)));
+    }
+
+    #[test]
+    fn no_gap_in_reach() {
+        let tiles = WorldTiles::from_rows(&[".......", "f.fff.f", "......."]);
+        assert_eq!(find_gap(&tiles, (3, 2), (3, 1), 1), None);
+    }
+
+    #[test]
+    fn patrol_walks_to_waypoint() {
+        let (status, velocity) = tick_goblin(&Node::Do(Action::Patrol), (100.0, 0.0), 0.0);
+        assert_eq!(status, Status::Running);
+        assert_eq!(velocity, Some(Vector2::new(40.0, 0.0)));
+    }
+
+    #[test]
+    fn resting_stands_still() {
+        let tree = Node::Sequence(vec![
+            Node::Check(Condition::RestingLongerThan(0.0)),
+            Node::Do(Action::Rest),
+        ]);
+        let (status, velocity) = tick_goblin(&tree, (100.0, 0.0), 1.0);
+        assert_eq!(status, Status::Running);
+        assert_eq!(velocity, Some(Vector2::zeros()));
+    }
+
+    #[test]
+    fn failed_check_leaves_intent_alone() {
+        let tree = Node::Sequence(vec![
+            Node::Check(Condition::Staggered),
+            Node::Do(Action::Patrol),
+        ]);
+        let (status, velocity) = tick_goblin(&tree, (100.0, 0.0), 0.0);
+        assert_eq!(status, Status::Failure);
+        assert_eq!(velocity, None);
+    }
+
+    #[test]
+    fn goblin_tree_patrols_with_nothing_around() {
+        let (status, velocity) = tick_goblin(&goblin_tree(), (0.0, 100.0), 0.0);
+        assert_eq!(status, Status::Running);
+        assert_eq!(velocity, Some(Vector2::new(0.0, 40.0)));
+    }
+}
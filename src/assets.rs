@@ -1,3 +1,4 @@
+use crate::error::GameError;
 use amethyst::{
     animation::*,
     assets::*,
@@ -13,8 +14,12 @@ use amethyst::{
     },
     utils::{application_root_dir, scene::BasicScenePrefab},
 };
+use log::{debug, info};
 use na::Vector2;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub fn get_resource(str: &str) -> String {
     format!("{}", str)
@@ -59,37 +64,20 @@ pub fn load_prefab<'a>(
     world.exec(|loader: PrefabLoader<'_, MyPrefabData>| loader.load(path, RonFormat, progress))
 }
 
-#[derive(Clone)]
-pub struct PrefabStorage {
-    pub player: Handle<Prefab<MyPrefabData>>,
-    pub crab: Handle<Prefab<MyPrefabData>>,
-}
-
 pub fn load_sound_file<'a>(
     world: &mut World,
     path: String,
     progress: &'a mut ProgressCounter,
 ) -> SourceHandle {
     let loader = world.read_resource::<Loader>();
-    loader.load(path, WavFormat, (), &world.read_resource())
-}
-
-#[derive(Clone)]
-pub struct SpriteStorage {
-    pub tile_spritesheet: SpriteSheetHandle,
-}
-
-#[derive(Clone)]
-pub struct SoundStorage {
-    pub goblin_hit: SourceHandle,
-    pub player_hit: SourceHandle,
-    pub pylon_hit: SourceHandle,
-    pub sword_slash: SourceHandle,
-    pub main_theme: SourceHandle,
+    loader.load(path, WavFormat, progress, &world.read_resource())
 }
 
 #[derive(Clone, Debug)]
-pub struct TiledMap(pub tiled::Map);
+pub struct TiledMap(pub tiled::Map, pub usize);
+
+/// Bumped every time a map finishes processing, so hot-reloaded maps can be told apart.
+static MAP_REVISION: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct TiledFormat;
@@ -100,9 +88,9 @@ impl Format<tiled::Map> for TiledFormat {
     }
 
     fn import_simple(&self, bytes: Vec<u8>) -> Result<tiled::Map, Error> {
-        println!("LOADING FORMAT");
+        debug!(target: "assets", "Parsing Tiled map ({} bytes)", bytes.len());
         let reader = bytes.as_slice();
-        Ok(tiled::parse(reader).unwrap())
+        tiled::parse(reader).map_err(|err| GameError::MapParse(format!("{}", err)).into())
     }
 }
 
@@ -115,41 +103,137 @@ impl Asset for TiledMap {
 
 impl ProcessableAsset for TiledMap {
     fn process(tiled_map: Self::Data) -> Result<ProcessingState<Self>, Error> {
-        Ok(ProcessingState::Loaded(Self(tiled_map)))
+        let revision = MAP_REVISION.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(ProcessingState::Loaded(Self(tiled_map, revision)))
     }
 }
 
-#[derive(Clone)]
-pub struct MapStorage {
-    pub village_map: Handle<TiledMap>,
-}
-
 pub fn load_map<'a>(
     world: &mut World,
     path: String,
     progress: &'a mut ProgressCounter,
 ) -> Handle<TiledMap> {
-    println!("LOADING MAP");
+    info!(target: "assets", "Loading map {}", path);
     let loader = world.read_resource::<Loader>();
     let map_storage = world.read_resource::<AssetStorage<TiledMap>>();
     loader.load(path, TiledFormat::default(), progress, &map_storage)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum AssetKind {
+    /// A `<path>.png` texture with its `<path>.ron` sprite sheet.
+    SpriteSheet,
+    Prefab,
+    Sound,
+    Map,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AssetEntry {
+    pub kind: AssetKind,
+    pub path: String,
+}
+
+/// Logical asset names and the files behind them, loaded from `assets.ron`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AssetManifest {
+    pub assets: Vec<(String, AssetEntry)>,
+}
+
+impl AssetManifest {
+    /// The logical name for a file the loader reported, including a sprite sheet's `.png`/`.ron`.
+    pub fn name_of(&self, file: &str) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|(_, entry)| {
+                let path = get_resource(&entry.path);
+                file == path || file.starts_with(&format!("{}.", path))
+            })
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Handles for everything in the manifest, looked up by logical name.
+#[derive(Clone, Default)]
+pub struct Assets {
+    sprite_sheets: HashMap<String, SpriteSheetHandle>,
+    prefabs: HashMap<String, Handle<Prefab<MyPrefabData>>>,
+    sounds: HashMap<String, SourceHandle>,
+    maps: HashMap<String, Handle<TiledMap>>,
+}
+
+impl Assets {
+    pub fn load(
+        world: &mut World,
+        manifest: &AssetManifest,
+        progress: &mut ProgressCounter,
+    ) -> Self {
+        let mut assets = Assets::default();
+        for (name, entry) in manifest.assets.iter() {
+            let path = get_resource(&entry.path);
+            let name = name.clone();
+            match entry.kind {
+                AssetKind::SpriteSheet => {
+                    let handle = load_spritesheet(world, path, progress);
+                    assets.sprite_sheets.insert(name, handle);
+                }
+                AssetKind::Prefab => {
+                    let handle = load_prefab(world, path, progress);
+                    assets.prefabs.insert(name, handle);
+                }
+                AssetKind::Sound => {
+                    let handle = load_sound_file(world, path, progress);
+                    assets.sounds.insert(name, handle);
+                }
+                AssetKind::Map => {
+                    let handle = load_map(world, path, progress);
+                    assets.maps.insert(name, handle);
+                }
+            }
+        }
+        assets
+    }
+
+    pub fn sprite_sheet(&self, name: &str) -> Option<SpriteSheetHandle> {
+        self.sprite_sheets.get(name).cloned()
+    }
+
+    pub fn prefab(&self, name: &str) -> Option<Handle<Prefab<MyPrefabData>>> {
+        self.prefabs.get(name).cloned()
+    }
+
+    pub fn sound(&self, name: &str) -> Option<SourceHandle> {
+        self.sounds.get(name).cloned()
+    }
+
+    pub fn map(&self, name: &str) -> Option<Handle<TiledMap>> {
+        self.maps.get(name).cloned()
+    }
+}
+
 #[derive(Eq, PartialOrd, PartialEq, Hash, Debug, Copy, Clone, Deserialize, Serialize)]
 pub enum Direction {
     East,
     North,
     West,
     South,
+    NorthEast,
+    NorthWest,
+    SouthWest,
+    SouthEast,
 }
 
 impl Direction {
     pub fn vec() -> Vec<Self> {
         vec![
             Direction::East,
+            Direction::NorthEast,
             Direction::North,
+            Direction::NorthWest,
             Direction::West,
+            Direction::SouthWest,
             Direction::South,
+            Direction::SouthEast,
         ]
     }
 
@@ -159,66 +243,77 @@ impl Direction {
             Direction::North => Vector2::new(0.0, 1.0),
             Direction::West => Vector2::new(-1.0, 0.0),
             Direction::South => Vector2::new(0.0, -1.0),
+            Direction::NorthEast => Vector2::new(FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+            Direction::NorthWest => Vector2::new(-FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+            Direction::SouthWest => Vector2::new(-FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+            Direction::SouthEast => Vector2::new(FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
         }
     }
 
+    pub fn angle(&self) -> f32 {
+        let tilts = self.tilts();
+        f32::atan2(tilts.y, tilts.x)
+    }
+
+    /// A quarter turn clockwise, used to offset hitboxes to the weapon side.
     pub fn clockwise(&self) -> Self {
         match self {
             Direction::East => Direction::South,
             Direction::South => Direction::West,
             Direction::West => Direction::North,
             Direction::North => Direction::East,
+            Direction::NorthEast => Direction::SouthEast,
+            Direction::SouthEast => Direction::SouthWest,
+            Direction::SouthWest => Direction::NorthWest,
+            Direction::NorthWest => Direction::NorthEast,
+        }
+    }
+
+    pub fn is_diagonal(&self) -> bool {
+        match self {
+            Direction::NorthEast
+            | Direction::NorthWest
+            | Direction::SouthWest
+            | Direction::SouthEast => true,
+            _ => false,
+        }
+    }
+
+    /// The closest of the four original directions, for sprites without diagonals.
+    pub fn cardinal(&self) -> Self {
+        match self {
+            Direction::NorthEast | Direction::SouthEast => Direction::East,
+            Direction::NorthWest | Direction::SouthWest => Direction::West,
+            other => *other,
         }
     }
 
     pub fn pick() -> Self {
-        if rand::random() {
-            if rand::random() {
-                Direction::East
-            } else {
-                Direction::North
-            }
-        } else {
-            if rand::random() {
-                Direction::West
-            } else {
-                Direction::South
-            }
+        let directions = Direction::vec();
+        directions[rand::random::<usize>() % directions.len()]
+    }
+
+    fn quadrant(offset: Vector2<f32>) -> Self {
+        match (offset.x > 0.0, offset.y > 0.0) {
+            (true, true) => Direction::NorthEast,
+            (false, true) => Direction::NorthWest,
+            (false, false) => Direction::SouthWest,
+            (true, false) => Direction::SouthEast,
         }
     }
 
     pub fn short_seek(offset: Vector2<f32>, margin: f32) -> Self {
         if f32::abs(offset.x) < margin || f32::abs(offset.y) < margin {
             Direction::long_seek(offset)
-        } else if f32::abs(offset.x) > f32::abs(offset.y) {
-            if offset.y > 0.0 {
-                Direction::North
-            } else {
-                Direction::South
-            }
         } else {
-            if offset.x > 0.0 {
-                Direction::East
-            } else {
-                Direction::West
-            }
+            Direction::quadrant(offset)
         }
     }
 
     pub fn long_seek(offset: Vector2<f32>) -> Self {
-        if f32::abs(offset.x) < f32::abs(offset.y) {
-            if offset.y > 0.0 {
-                Direction::North
-            } else {
-                Direction::South
-            }
-        } else {
-            if offset.x > 0.0 {
-                Direction::East
-            } else {
-                Direction::West
-            }
-        }
+        let sector = f32::atan2(offset.y, offset.x) / FRAC_PI_4;
+        let directions = Direction::vec();
+        directions[(sector.round() as i32).rem_euclid(8) as usize]
     }
 }
 
@@ -240,6 +335,20 @@ impl AnimationId {
         }
     }
 
+    pub fn with_direction(&self, direction: Direction) -> Self {
+        match self {
+            AnimationId::Walk(_) => AnimationId::Walk(direction),
+            AnimationId::Attack(_) => AnimationId::Attack(direction),
+            AnimationId::Idle(_) => AnimationId::Idle(direction),
+            AnimationId::Staggered(_) => AnimationId::Staggered(direction),
+        }
+    }
+
+    /// The four-way animation to play when a diagonal one isn't in the sprite sheet.
+    pub fn fallback(&self) -> Self {
+        self.with_direction(self.direction().cardinal())
+    }
+
     pub fn is_attack(&self) -> bool {
         match self {
             AnimationId::Attack(_) => true,
@@ -247,3 +356,78 @@ impl AnimationId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cardinals_stay_put() {
+        for direction in [
+            Direction::East,
+            Direction::North,
+            Direction::West,
+            Direction::South,
+        ]
+        .iter()
+        {
+            assert!(!direction.is_diagonal());
+            assert_eq!(direction.cardinal(), *direction);
+        }
+    }
+
+    #[test]
+    fn diagonals_fall_back_to_the_side_they_lean() {
+        let expected = [
+            (Direction::NorthEast, Direction::East),
+            (Direction::SouthEast, Direction::East),
+            (Direction::NorthWest, Direction::West),
+            (Direction::SouthWest, Direction::West),
+        ];
+        for (diagonal, cardinal) in expected.iter() {
+            assert!(diagonal.is_diagonal());
+            assert_eq!(diagonal.cardinal(), *cardinal);
+            assert_eq!(
+                AnimationId::Walk(*diagonal).fallback(),
+                AnimationId::Walk(*cardinal)
+            );
+            assert_eq!(
+                AnimationId::Attack(*diagonal).fallback(),
+                AnimationId::Attack(*cardinal)
+            );
+        }
+    }
+
+    #[test]
+    fn long_seek_finds_all_eight_ways() {
+        for direction in Direction::vec() {
+            assert_eq!(Direction::long_seek(direction.tilts() * 10.0), direction);
+        }
+        assert_eq!(
+            Direction::long_seek(Vector2::new(10.0, 3.0)),
+            Direction::East
+        );
+    }
+
+    #[test]
+    fn short_seek_goes_diagonal_only_off_axis() {
+        assert_eq!(
+            Direction::short_seek(Vector2::new(10.0, 3.0), 4.0),
+            Direction::East
+        );
+        assert_eq!(
+            Direction::short_seek(Vector2::new(-10.0, 5.0), 4.0),
+            Direction::NorthWest
+        );
+    }
+
+    #[test]
+    fn clockwise_turns_a_quarter() {
+        for direction in Direction::vec() {
+            let turned = direction.clockwise();
+            assert_eq!(turned.is_diagonal(), direction.is_diagonal());
+            let tilts = direction.tilts();
+            assert!((turned.tilts() - Vector2::new(tilts.y, -tilts.x)).norm() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Checks Tiled levels for missing markers and unreachable waypoints without opening a window.
+//!
+//! Usage: `cargo run --bin validate-map -- resources/Village.tmx`
+use amethyst::assets::Format;
+use std::process;
+use topdown_physics::assets::TiledFormat;
+use topdown_physics::level::*;
+
+fn describe(marker: &Marker) -> String {
+    match marker.kind {
+        MarkerKind::Waypoint(order) => format!("Waypoint{} {:?}", order, marker.tile),
+        kind => format!("{:?} {:?}", kind, marker.tile),
+    }
+}
+
+/// Prints the report for one map and returns whether it had any errors.
+fn validate_file(path: &str) -> bool {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("{}: could not read: {}", path, err);
+            return true;
+        }
+    };
+    let map = match TiledFormat.import_simple(bytes) {
+        Ok(map) => map,
+        Err(err) => {
+            println!("{}: {}", path, err);
+            return true;
+        }
+    };
+    let layout = match scan_level(&map) {
+        Ok(layout) => layout,
+        Err(err) => {
+            println!("{}: {}", path, err);
+            return true;
+        }
+    };
+    println!(
+        "{}: {}x{} tiles, {} fence tiles, {} with drawn shapes, {} markers",
+        path,
+        layout.map_size.0,
+        layout.map_size.1,
+        layout.fences.len(),
+        layout.shaped.len(),
+        layout.markers.len()
+    );
+    let markers = layout.waypoint_markers();
+    let waypoints = layout.waypoints();
+    if !waypoints.is_empty() {
+        for goblin in layout.of_kind(MarkerKind::Goblin).iter() {
+            let (first, _) =
+                closest_waypoint(goblin.position.0, goblin.position.1, &waypoints, None);
+            let mut route = vec![describe(goblin)];
+            for index in waypoint_chain(&waypoints, first) {
+                route.push(describe(&markers[index]));
+            }
+            println!("  {}", route.join(" -> "));
+        }
+    }
+    let problems = validate_layout(&layout);
+    for problem in problems.iter() {
+        match problem.severity {
+            Severity::Error => println!("  error: {}", problem.message),
+            Severity::Warning => println!("  warning: {}", problem.message),
+        }
+    }
+    problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+}
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        println!("usage: validate-map <map.tmx>...");
+        process::exit(2);
+    }
+    let mut failed = false;
+    for path in paths.iter() {
+        if validate_file(path) {
+            failed = true;
+        }
+    }
+    process::exit(if failed { 1 } else { 0 });
+}
@@ -0,0 +1,436 @@
+use crate::assets::{get_resource, Assets};
+use crate::combat::Health;
+use crate::enemies::Goblin;
+use crate::mixer::SoundEvent;
+use crate::physics::*;
+use crate::player::{Player, Pylon};
+use crate::prelude::*;
+use crate::spatial::SpatialIndex;
+use crate::world::WorldTiles;
+use amethyst::{
+    config::Config,
+    core::{shrev::EventChannel, transform::Transform},
+    input::{InputHandler, StringBindings},
+    renderer::{debug_drawing::DebugLines, palette::Srgba, sprite::SpriteSheetHandle},
+    ui::{UiText, UiTransform},
+};
+use log::{info, warn};
+use ncollide2d::shape::*;
+use nphysics2d::object::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum StructureKind {
+    Turret,
+    Barricade,
+}
+
+impl StructureKind {
+    /// The next choice when "interact" is pressed, ending with leaving build mode.
+    fn next(selected: Option<StructureKind>) -> Option<StructureKind> {
+        match selected {
+            None => Some(StructureKind::Turret),
+            Some(StructureKind::Turret) => Some(StructureKind::Barricade),
+            Some(StructureKind::Barricade) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StructureDef {
+    pub cost: usize,
+    pub health: usize,
+    /// Sprite number in the tile sheet.
+    pub sprite: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    /// Coins earned for each goblin killed.
+    pub bounty: usize,
+    pub turret: StructureDef,
+    pub barricade: StructureDef,
+    pub turret_range: f32,
+    pub turret_cooldown: f32,
+    pub turret_damage: usize,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        BuildConfig {
+            bounty: 1,
+            turret: StructureDef {
+                cost: 10,
+                health: 8,
+                sprite: 5,
+            },
+            barricade: StructureDef {
+                cost: 3,
+                health: 12,
+                sprite: 2,
+            },
+            turret_range: 120.0,
+            turret_cooldown: 1.0,
+            turret_damage: 1,
+        }
+    }
+}
+
+impl BuildConfig {
+    pub fn def(&self, kind: StructureKind) -> &StructureDef {
+        match kind {
+            StructureKind::Turret => &self.turret,
+            StructureKind::Barricade => &self.barricade,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Wallet {
+    pub coins: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct BuildMode {
+    pub selected: Option<StructureKind>,
+    interact_held: bool,
+    attack_held: bool,
+    message: String,
+}
+
+impl BuildMode {
+    pub fn is_active(&self) -> bool {
+        self.selected.is_some()
+    }
+}
+
+#[derive(Component, Debug)]
+#[storage(VecStorage)]
+pub struct Structure {
+    pub kind: StructureKind,
+    pub tile: (usize, usize),
+}
+
+#[derive(Component, Debug, Default)]
+#[storage(VecStorage)]
+pub struct Turret {
+    pub cooldown: f32,
+    /// The last shot, drawn for a moment after firing.
+    shot: Option<(Vector2<f32>, Vector2<f32>, f32)>,
+}
+
+/// Structures can't go on the pylon or this close to its edge.
+const PYLON_CLEARANCE: f32 = 44.0 + 16.0;
+const SHOT_TIME: f32 = 0.1;
+/// The widest character collider, how far past a tile's edge to look for someone standing on it.
+const CHARACTER_REACH: f32 = 8.0;
+
+fn spawn_structure(
+    builder: impl Builder,
+    kind: StructureKind,
+    def: &StructureDef,
+    tile: (usize, usize),
+    (x, y): (f32, f32),
+    sprite_sheet: Option<SpriteSheetHandle>,
+) -> Entity {
+    let body = RigidBodyDesc::new().status(BodyStatus::Static);
+    let shape = ShapeHandle::new(Cuboid::new(Vector2::new(16.0, 16.0)));
+    let collider = ColliderDesc::new(shape);
+    let mut transform = Transform::default();
+    transform.set_translation_xyz(x, y, 0.5);
+    let mut builder = builder
+        .with(PhysicsDesc::new(body, collider))
+        .with(transform)
+        .with(Structure { kind, tile })
+        .with(Health::new(true, def.health));
+    if let Some(sprite_sheet) = sprite_sheet {
+        builder = builder.with(SpriteRender {
+            sprite_sheet,
+            sprite_number: def.sprite,
+        });
+    }
+    if kind == StructureKind::Turret {
+        builder = builder.with(Turret::default());
+    }
+    builder.build()
+}
+
+struct BuildSystem;
+
+impl BuildSystem {
+    /// Why a structure can't go on `tile`, if it can't.
+    fn blocked(
+        &self,
+        world_tiles: &WorldTiles,
+        tile: (usize, usize),
+        structures: &ReadStorage<'_, Structure>,
+        pylon: Option<Vector2<f32>>,
+        characters: (&Physics, &SpatialIndex, &ReadStorage<'_, PhysicsHandle>),
+    ) -> Option<&'static str> {
+        let (x, y) = world_tiles.tile_center(tile);
+        let (physics, index, handles) = characters;
+        let half_tile = world_tiles.tile_size().0 as f32 / 2.0;
+        let center = Vector2::new(x, y);
+        if !world_tiles.is_open(tile) {
+            Some("Blocked by a fence")
+        } else if structures.join().any(|structure| structure.tile == tile) {
+            Some("Already built here")
+        } else if pylon.map_or(false, |pylon| (pylon - center).norm() < PYLON_CLEARANCE) {
+            Some("Too close to the pylon")
+        } else if index
+            .within(center, half_tile + CHARACTER_REACH)
+            .into_iter()
+            .any(|(entity, position)| {
+                handles
+                    .get(entity)
+                    .and_then(|handle| physics.character_radius(handle))
+                    .map_or(false, |radius| {
+                        (position - center).norm() < half_tile + radius
+                    })
+            })
+        {
+            Some("Someone is standing here")
+        } else {
+            None
+        }
+    }
+}
+
+impl<'s> System<'s> for BuildSystem {
+    type SystemData = (
+        Read<'s, InputHandler<StringBindings>>,
+        Write<'s, BuildMode>,
+        Write<'s, Wallet>,
+        Read<'s, BuildConfig>,
+        Option<Read<'s, WorldTiles>>,
+        Option<Read<'s, Assets>>,
+        Read<'s, Physics>,
+        Read<'s, SpatialIndex>,
+        ReadStorage<'s, PhysicsHandle>,
+        ReadStorage<'s, Player>,
+        ReadStorage<'s, Pylon>,
+        ReadStorage<'s, Structure>,
+        ReadStorage<'s, Transform>,
+        ReadStorage<'s, UiTransform>,
+        WriteStorage<'s, UiText>,
+        Write<'s, DebugLines>,
+        Read<'s, LazyUpdate>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            input,
+            mut build,
+            mut wallet,
+            config,
+            world_tiles,
+            assets,
+            physics,
+            index,
+            handles,
+            players,
+            pylons,
+            structures,
+            transforms,
+            ui_transforms,
+            mut ui_texts,
+            mut debug_lines,
+            lazy,
+            entities,
+        ): Self::SystemData,
+    ) {
+        let interact = input.action_is_down("interact").unwrap_or(false);
+        if interact && !build.interact_held {
+            build.selected = StructureKind::next(build.selected);
+            build.message = "".to_string();
+        }
+        build.interact_held = interact;
+        let attack = input.action_is_down("attack").unwrap_or(false);
+        let placing = attack && !build.attack_held;
+        build.attack_held = attack;
+
+        let position = |transform: &Transform| {
+            let translation = transform.translation();
+            Vector2::new(translation.x, translation.y)
+        };
+        let pylon = (&pylons, &transforms)
+            .join()
+            .next()
+            .map(|(_, transform)| position(transform));
+        if let (Some(kind), Some(world_tiles), Some((player, transform))) = (
+            build.selected,
+            world_tiles.as_ref(),
+            (&players, &transforms).join().next(),
+        ) {
+            let tile_width = world_tiles.tile_size().0 as f32;
+            let ahead = position(transform) + player.facing.tilts() * tile_width;
+            if let Some(tile) = world_tiles.tile_at(ahead.x, ahead.y) {
+                let blocked = self.blocked(
+                    world_tiles,
+                    tile,
+                    &structures,
+                    pylon,
+                    (&physics, &index, &handles),
+                );
+                let (x, y) = world_tiles.tile_center(tile);
+                let half = tile_width / 2.0;
+                debug_lines.draw_rectangle(
+                    [x - half, y - half].into(),
+                    [x + half, y + half].into(),
+                    0.5,
+                    if blocked.is_some() {
+                        Srgba::new(1.0, 0.2, 0.2, 1.0)
+                    } else {
+                        Srgba::new(0.2, 1.0, 0.2, 1.0)
+                    },
+                );
+                if placing {
+                    let def = config.def(kind);
+                    if let Some(reason) = blocked {
+                        build.message = reason.to_string();
+                    } else if wallet.coins < def.cost {
+                        build.message = format!("Need {} coins", def.cost);
+                    } else {
+                        wallet.coins -= def.cost;
+                        build.message = "".to_string();
+                        info!(target: "world", "Built {:?} at {:?}", kind, tile);
+                        spawn_structure(
+                            lazy.create_entity(&entities),
+                            kind,
+                            def,
+                            tile,
+                            (x, y),
+                            assets
+                                .as_ref()
+                                .and_then(|assets| assets.sprite_sheet("tiles")),
+                        );
+                    }
+                }
+            }
+        }
+
+        let status = match build.selected {
+            Some(kind) => format!(
+                "Coins: {}  Building {:?} ({})  {}",
+                wallet.coins,
+                kind,
+                config.def(kind).cost,
+                build.message
+            ),
+            None => format!("Coins: {}", wallet.coins),
+        };
+        for (transform, text) in (&ui_transforms, &mut ui_texts).join() {
+            if transform.id.eq("build_status") {
+                text.text = status.clone();
+            }
+        }
+    }
+}
+
+/// Fires at the nearest goblin in range that the turret can see.
+struct TurretSystem;
+
+impl<'s> System<'s> for TurretSystem {
+    type SystemData = (
+        Read<'s, Time>,
+        Read<'s, BuildConfig>,
+        Read<'s, Physics>,
+        ReadStorage<'s, PhysicsHandle>,
+        ReadStorage<'s, Goblin>,
+        Read<'s, SpatialIndex>,
+        WriteStorage<'s, Turret>,
+        WriteStorage<'s, Health>,
+        Write<'s, EventChannel<SoundEvent>>,
+        Write<'s, DebugLines>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            time,
+            config,
+            physics,
+            handles,
+            goblins,
+            index,
+            mut turrets,
+            mut healths,
+            mut sounds,
+            mut debug_lines,
+            entities,
+        ): Self::SystemData,
+    ) {
+        let delta = time.delta_seconds();
+        for (entity, handle, turret) in (&entities, &handles, &mut turrets).join() {
+            turret.cooldown = f32::max(turret.cooldown - delta, 0.0);
+            if let Some((from, to, left)) = turret.shot {
+                debug_lines.draw_line(
+                    [from.x, from.y, 0.5].into(),
+                    [to.x, to.y, 0.5].into(),
+                    Srgba::new(1.0, 0.9, 0.4, 1.0),
+                );
+                turret.shot = if left > delta {
+                    Some((from, to, left - delta))
+                } else {
+                    None
+                };
+            }
+            if turret.cooldown > 0.0 {
+                continue;
+            }
+            let from = match physics.get_location(handle) {
+                Some(location) => location.coords,
+                None => continue,
+            };
+            let mut in_range: Vec<(Entity, Vector2<f32>)> = index
+                .within(from, config.turret_range)
+                .into_iter()
+                .filter(|(goblin, _)| goblins.contains(*goblin))
+                .map(|(goblin, position)| (goblin, position - from))
+                .collect();
+            in_range.sort_by(|a, b| a.1.norm().partial_cmp(&b.1.norm()).unwrap());
+            for (goblin, offset) in in_range.into_iter() {
+                let mut hits: Vec<(Entity, f32)> = physics
+                    .ray_cast(handle, offset.normalize())
+                    .into_iter()
+                    .filter(|(seen, _)| *seen != entity)
+                    .collect();
+                hits.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                if hits.first().map(|(seen, _)| *seen) != Some(goblin) {
+                    continue;
+                }
+                if let Some(health) = healths.get_mut(goblin) {
+                    health.current_health =
+                        health.current_health.saturating_sub(config.turret_damage);
+                }
+                turret.cooldown = config.turret_cooldown;
+                turret.shot = Some((from, from + offset, SHOT_TIME));
+                sounds.single_write(SoundEvent::at("turret_fire", from));
+                break;
+            }
+        }
+    }
+}
+
+pub struct BuildingBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for BuildingBundle {
+    fn build(
+        self,
+        world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        let config = BuildConfig::load(get_resource("structures.ron")).unwrap_or_else(|err| {
+            warn!(target: "world", "Could not read structures.ron: {}", err);
+            BuildConfig::default()
+        });
+        world.insert(config);
+        dispatcher.add(BuildSystem, "build", &[]);
+        dispatcher.add(TurretSystem, "turret", &["physics", "spatial_index"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use crate::prelude::*;
+use crate::world::WorldTiles;
+use amethyst::{
+    core::{bundle::SystemBundle, transform::*},
+    renderer::camera::*,
+};
+
+pub const ARENA_WIDTH: f32 = 240.0;
+pub const ARENA_HEIGHT: f32 = 160.0;
+const CAMERA_Z: f32 = 2.0;
+
+fn standard_camera() -> Camera {
+    Camera::standard_2d(ARENA_WIDTH, ARENA_HEIGHT)
+}
+
+#[derive(Component, Debug)]
+#[storage(VecStorage)]
+pub struct CameraFollow {
+    pub target: Entity,
+    /// How quickly the camera catches up, per second. Higher is snappier.
+    pub smoothing: f32,
+    /// Half-size of the box around the focus the target can move in without dragging the camera.
+    pub dead_zone: Vector2<f32>,
+    focus: Option<Vector2<f32>>,
+}
+
+impl CameraFollow {
+    pub fn new(target: Entity) -> Self {
+        CameraFollow {
+            target,
+            smoothing: 6.0,
+            dead_zone: Vector2::new(16.0, 12.0),
+            focus: None,
+        }
+    }
+}
+
+pub fn initialize_camera(builder: impl Builder, player: &Entity, x: f32, y: f32) -> Entity {
+    let mut transform = Transform::default();
+    transform.set_translation_xyz(x, y, CAMERA_Z);
+
+    builder
+        .with(standard_camera())
+        .with(CameraFollow::new(*player))
+        .with(transform)
+        .build()
+}
+
+/// Trauma-based screen shake. Trauma decays over time and the shake scales with its square.
+pub struct ScreenShake {
+    pub trauma: f32,
+    pub decay: f32,
+    pub max_offset: f32,
+    pub max_roll: f32,
+}
+
+impl Default for ScreenShake {
+    fn default() -> Self {
+        ScreenShake {
+            trauma: 0.0,
+            decay: 1.5,
+            max_offset: 6.0,
+            max_roll: 0.05,
+        }
+    }
+}
+
+impl ScreenShake {
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = f32::min(self.trauma + amount, 1.0);
+    }
+}
+
+fn clamp_axis(focus: f32, min: f32, max: f32, view: f32) -> f32 {
+    if max - min <= view {
+        (min + max) / 2.0
+    } else {
+        f32::max(min + view / 2.0, f32::min(max - view / 2.0, focus))
+    }
+}
+
+fn follow_axis(focus: f32, target: f32, dead_zone: f32) -> f32 {
+    let offset = target - focus;
+    if offset > dead_zone {
+        target - dead_zone
+    } else if offset < -dead_zone {
+        target + dead_zone
+    } else {
+        focus
+    }
+}
+
+struct CameraFollowSystem;
+
+impl<'s> System<'s> for CameraFollowSystem {
+    type SystemData = (
+        Read<'s, Time>,
+        Option<Read<'s, WorldTiles>>,
+        Write<'s, ScreenShake>,
+        WriteStorage<'s, CameraFollow>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (time, world_tiles, mut shake, mut follows, mut transforms): Self::SystemData,
+    ) {
+        let delta = time.delta_seconds();
+        shake.trauma = f32::max(shake.trauma - shake.decay * delta, 0.0);
+        let intensity = shake.trauma * shake.trauma;
+        for follow in (&mut follows).join() {
+            let target = match transforms.get(follow.target) {
+                Some(transform) => {
+                    let translation = transform.translation();
+                    Vector2::new(translation.x, translation.y)
+                }
+                None => continue,
+            };
+            let focus = follow.focus.unwrap_or(target);
+            let desired = Vector2::new(
+                follow_axis(focus.x, target.x, follow.dead_zone.x),
+                follow_axis(focus.y, target.y, follow.dead_zone.y),
+            );
+            let blend = 1.0 - f32::exp(-follow.smoothing * delta);
+            let mut focus = focus + (desired - focus) * blend;
+            if let Some(world_tiles) = world_tiles.as_ref() {
+                let (min, max) = world_tiles.bounds();
+                focus.x = clamp_axis(focus.x, min.x, max.x, ARENA_WIDTH);
+                focus.y = clamp_axis(focus.y, min.y, max.y, ARENA_HEIGHT);
+            }
+            follow.focus = Some(focus);
+        }
+        for (follow, transform) in (&follows, &mut transforms).join() {
+            if let Some(focus) = follow.focus {
+                let jitter = || rand::random::<f32>() * 2.0 - 1.0;
+                transform.set_translation_xyz(
+                    focus.x + shake.max_offset * intensity * jitter(),
+                    focus.y + shake.max_offset * intensity * jitter(),
+                    CAMERA_Z,
+                );
+                transform.set_rotation_2d(shake.max_roll * intensity * jitter());
+            }
+        }
+    }
+}
+
+pub struct CameraBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for CameraBundle {
+    fn build(
+        self,
+        _world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        dispatcher.add(CameraFollowSystem, "camera_follow", &["physics"]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the camera settles on its first frame following `target` on `rows`, and the map's
+    /// bounds.
+    fn first_focus(
+        rows: &[&str],
+        target: Vector2<f32>,
+    ) -> (Vector2<f32>, (Vector2<f32>, Vector2<f32>)) {
+        let mut world = World::new();
+        world.register::<CameraFollow>();
+        world.register::<Transform>();
+        world.insert(Time::default());
+        world.insert(ScreenShake::default());
+        world.insert(WorldTiles::from_rows(rows));
+        let mut transform = Transform::default();
+        transform.set_translation_xyz(target.x, target.y, 0.0);
+        let player = world.create_entity().with(transform).build();
+        let camera = world
+            .create_entity()
+            .with(CameraFollow::new(player))
+            .with(Transform::default())
+            .build();
+        CameraFollowSystem.run_now(&world);
+        let translation = *world
+            .read_storage::<Transform>()
+            .get(camera)
+            .unwrap()
+            .translation();
+        let bounds = world.read_resource::<WorldTiles>().bounds();
+        (Vector2::new(translation.x, translation.y), bounds)
+    }
+
+    /// Ten by eight tiles of 32 pixels, wider and taller than the view.
+    const LARGE: &[&str] = &[".........."; 8];
+
+    #[test]
+    fn follows_freely_inside_a_large_map() {
+        let target = Vector2::new(-16.0, 16.0);
+        let (focus, _) = first_focus(LARGE, target);
+        assert_eq!(focus, target);
+    }
+
+    #[test]
+    fn stops_at_the_edges_of_a_large_map() {
+        let (focus, (min, max)) = first_focus(LARGE, Vector2::new(1000.0, -1000.0));
+        assert_eq!(focus.x, max.x - ARENA_WIDTH / 2.0);
+        assert_eq!(focus.y, min.y + ARENA_HEIGHT / 2.0);
+
+        let (focus, _) = first_focus(LARGE, Vector2::new(-1000.0, 1000.0));
+        assert_eq!(focus.x, min.x + ARENA_WIDTH / 2.0);
+        assert_eq!(focus.y, max.y - ARENA_HEIGHT / 2.0);
+    }
+
+    #[test]
+    fn centers_a_map_smaller_than_the_view() {
+        let small: &[&str] = &["....", "....", "...."];
+        for target in [Vector2::new(1000.0, 1000.0), Vector2::new(-40.0, -20.0)].iter() {
+            let (focus, (min, max)) = first_focus(small, *target);
+            assert_eq!(focus, (min + max) / 2.0);
+        }
+    }
+}
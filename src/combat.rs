@@ -1,8 +1,15 @@
+use crate::building::{BuildConfig, Structure, Wallet};
+use crate::camera::ScreenShake;
 use crate::enemies::*;
+use crate::mixer::SoundEvent;
 use crate::physics::*;
 use crate::player::*;
 use crate::prelude::*;
-use amethyst::ui::{UiFinder, UiTransform};
+use crate::world::{fence_near, Fence, FenceBody, FenceSystem};
+use amethyst::{
+    core::{shrev::EventChannel, transform::Transform},
+    ui::{UiFinder, UiTransform},
+};
 
 #[derive(Debug, PartialEq)]
 pub enum HitType {
@@ -44,7 +51,7 @@ struct AttackHitboxSystem;
 impl AttackHitboxSystem {
     fn hit_pylon(
         &self,
-        physics: &mut Physics<f32>,
+        physics: &mut Physics,
         health: &mut Health,
         pylon: &mut Pylon,
         attack: &AttackHitbox,
@@ -59,7 +66,7 @@ impl AttackHitboxSystem {
     }
     fn hit_player(
         &self,
-        physics: &mut Physics<f32>,
+        physics: &mut Physics,
         health: &mut Health,
         player: &mut Player,
         attack: &AttackHitbox,
@@ -81,7 +88,7 @@ impl AttackHitboxSystem {
     }
     fn hit_goblin(
         &self,
-        physics: &mut Physics<f32>,
+        physics: &mut Physics,
         health: &mut Health,
         goblin: &mut Goblin,
         attack: &AttackHitbox,
@@ -93,7 +100,7 @@ impl AttackHitboxSystem {
                 goblin_physics,
                 Direction::long_seek(direction).tilts() * -60.0,
             );
-            goblin.state = GoblinState::Hit(goblin.state.get_waypoint(), 0.5);
+            goblin.blackboard.stagger(0.5);
         }
         if health.current_health < attack.damage {
             health.current_health = 0;
@@ -105,7 +112,7 @@ impl AttackHitboxSystem {
 
 impl<'s> System<'s> for AttackHitboxSystem {
     type SystemData = (
-        Write<'s, Physics<f32>>,
+        Write<'s, Physics>,
         ReadStorage<'s, AttachedSensor>,
         ReadStorage<'s, PhysicsHandle>,
         ReadStorage<'s, Named>,
@@ -113,8 +120,12 @@ impl<'s> System<'s> for AttackHitboxSystem {
         WriteStorage<'s, Player>,
         WriteStorage<'s, Goblin>,
         WriteStorage<'s, Pylon>,
+        ReadStorage<'s, Structure>,
         ReadStorage<'s, AttackHitbox>,
-        SoundPlayer<'s>,
+        ReadStorage<'s, Fence>,
+        ReadStorage<'s, FenceBody>,
+        Write<'s, EventChannel<SoundEvent>>,
+        Write<'s, ScreenShake>,
         Entities<'s>,
     );
 
@@ -129,14 +140,36 @@ impl<'s> System<'s> for AttackHitboxSystem {
             mut players,
             mut goblins,
             mut pylons,
+            structures,
             hitboxes,
-            sounds,
+            fences,
+            fence_bodies,
+            mut sounds,
+            mut shake,
             entities,
         ): Self::SystemData,
     ) {
         for (_entity, sensor, hitbox) in (&entities, &sensors, &hitboxes).join() {
             let handle = sensor.get_handle();
             for hit_entity in physics.get_intersections(&handle) {
+                if fence_bodies.contains(hit_entity) && hitbox.hit_type == HitType::EnemyAttack {
+                    // Every fence shares one body, so work out which tile the swing landed on.
+                    let fence = physics
+                        .get_collider_location(&handle)
+                        .and_then(|location| fence_near(location.coords, &entities, &fences));
+                    if let (Some(fence), Some(health)) = (
+                        fence.and_then(|fence| fences.get(fence)),
+                        fence.and_then(|fence| healths.get_mut(fence)),
+                    ) {
+                        if !health.hit_by.contains(&hitbox.id) {
+                            health.hit_by.push(hitbox.id);
+                            health.current_health =
+                                health.current_health.saturating_sub(hitbox.damage);
+                            sounds.single_write(SoundEvent::at("structure_hit", fence.position));
+                        }
+                    }
+                    continue;
+                }
                 if let (Some(hit_handle), Some(mut health)) =
                     (handles.get(hit_entity), healths.get_mut(hit_entity))
                 {
@@ -145,6 +178,13 @@ impl<'s> System<'s> for AttackHitboxSystem {
                             || (!health.friendly && hitbox.hit_type == HitType::FriendlyAttack))
                     {
                         health.hit_by.push(hitbox.id);
+                        let position = physics
+                            .get_position(&hit_handle)
+                            .map(|position| position.translation.vector);
+                        let sound = |name| SoundEvent {
+                            name,
+                            position: position.clone(),
+                        };
                         if let Some(mut player) = players.get_mut(hit_entity) {
                             self.hit_player(
                                 &mut physics,
@@ -154,7 +194,8 @@ impl<'s> System<'s> for AttackHitboxSystem {
                                 &handle,
                                 &hit_handle,
                             );
-                            sounds.player_hit();
+                            sounds.single_write(sound("player_hit"));
+                            shake.add_trauma(0.3);
                         }
                         if let Some(mut pylon) = pylons.get_mut(hit_entity) {
                             self.hit_pylon(
@@ -165,7 +206,8 @@ impl<'s> System<'s> for AttackHitboxSystem {
                                 &handle,
                                 &hit_handle,
                             );
-                            sounds.pylon_hit();
+                            sounds.single_write(sound("pylon_hit"));
+                            shake.add_trauma(0.5);
                         }
                         if let Some(mut goblin) = goblins.get_mut(hit_entity) {
                             self.hit_goblin(
@@ -176,7 +218,12 @@ impl<'s> System<'s> for AttackHitboxSystem {
                                 &handle,
                                 &hit_handle,
                             );
-                            sounds.goblin_hit();
+                            sounds.single_write(sound("goblin_hit"));
+                        }
+                        if structures.contains(hit_entity) {
+                            health.current_health =
+                                health.current_health.saturating_sub(hitbox.damage);
+                            sounds.single_write(sound("structure_hit"));
                         }
                     }
                 }
@@ -187,11 +234,36 @@ impl<'s> System<'s> for AttackHitboxSystem {
 
 struct NpcDeathSystem;
 impl<'s> System<'s> for NpcDeathSystem {
-    type SystemData = (Entities<'s>, ReadStorage<'s, Health>, Read<'s, LazyUpdate>);
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Health>,
+        ReadStorage<'s, Transform>,
+        ReadStorage<'s, Fence>,
+        Write<'s, EventChannel<SoundEvent>>,
+        Write<'s, Wallet>,
+        Read<'s, BuildConfig>,
+        Read<'s, LazyUpdate>,
+    );
 
-    fn run(&mut self, (entities, healths, lazy): Self::SystemData) {
-        for (entity, health) in (&entities, &healths).join() {
+    fn run(
+        &mut self,
+        (entities, healths, transforms, fences, mut sounds, mut wallet, config, lazy): Self::SystemData,
+    ) {
+        // Fences stay around knocked down so they can be repaired.
+        for (entity, health, transform, _) in
+            (&entities, &healths, (&transforms).maybe(), !&fences).join()
+        {
             if health.current_health == 0 {
+                if !health.friendly {
+                    wallet.coins += config.bounty;
+                }
+                if let (false, Some(transform)) = (health.friendly, transform) {
+                    let translation = transform.translation();
+                    sounds.single_write(SoundEvent::at(
+                        "goblin_death",
+                        Vector2::new(translation.x, translation.y),
+                    ));
+                }
                 lazy.exec(move |world| {
                     world.delete_entity(entity);
                 });
@@ -210,14 +282,20 @@ impl<'s> System<'s> for HealthDisplaySystem {
         ReadStorage<'s, Pylon>,
         ReadStorage<'s, Player>,
         ReadStorage<'s, Health>,
+        Read<'s, PlayerUpgrades>,
         WriteStorage<'s, UiTransform>,
         Entities<'s>,
     );
-    fn run(&mut self, (pylons, players, healths, mut transforms, entities): Self::SystemData) {
+    fn run(
+        &mut self,
+        (pylons, players, healths, upgrades, mut transforms, entities): Self::SystemData,
+    ) {
         if let Some((player, health)) = (&players, &healths).join().next() {
             for (transform) in (&mut transforms).join() {
                 if transform.id.eq("full_health") {
-                    transform.width = WIDTH_PER_HEALTH * health.current_health as f32;
+                    transform.width =
+                        WIDTH_PER_HEALTH * MAX_PLAYER_HEALTH as f32 * health.current_health as f32
+                            / upgrades.max_health as f32;
                 }
             }
         }
@@ -242,6 +320,7 @@ impl<'a, 'b> SystemBundle<'a, 'b> for CombatBundle {
         dispatcher.add(HealthDisplaySystem, "health_bar", &[]);
         dispatcher.add(AttackHitboxSystem, "attack_hitbox", &["physics"]);
         dispatcher.add(NpcDeathSystem, "npc_death", &[]);
+        dispatcher.add(FenceSystem, "fences", &["attack_hitbox"]);
         Ok(())
     }
 }
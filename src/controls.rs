@@ -0,0 +1,488 @@
+use crate::assets::get_resource;
+use amethyst::{
+    config::Config,
+    ecs::*,
+    error::Error,
+    input::{
+        is_close_requested, is_key_down, Axis, Bindings, Button, InputEvent, InputHandler,
+        StringBindings,
+    },
+    prelude::*,
+    ui::{UiCreator, UiEventType, UiFinder, UiText, UiTransform},
+    winit::VirtualKeyCode,
+};
+use log::warn;
+
+pub const USER_INPUT_FILE: &'static str = "input_user.ron";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindingSlot {
+    AxisPos(&'static str),
+    AxisNeg(&'static str),
+    Action(&'static str),
+}
+
+pub const REBINDABLE: &'static [(&'static str, BindingSlot)] = &[
+    ("updown_pos", BindingSlot::AxisPos("updown")),
+    ("updown_neg", BindingSlot::AxisNeg("updown")),
+    ("leftright_pos", BindingSlot::AxisPos("leftright")),
+    ("leftright_neg", BindingSlot::AxisNeg("leftright")),
+    ("attack", BindingSlot::Action("attack")),
+    ("interact", BindingSlot::Action("interact")),
+];
+
+pub fn load_bindings(
+    default_path: String,
+    user_path: String,
+) -> Result<Bindings<StringBindings>, Error> {
+    let mut bindings = Bindings::<StringBindings>::load(default_path)?;
+    if let Ok(overrides) = Bindings::<StringBindings>::load(user_path) {
+        merge_bindings(&mut bindings, &overrides);
+    }
+    Ok(bindings)
+}
+
+pub fn load_user_bindings() -> Bindings<StringBindings> {
+    Bindings::<StringBindings>::load(get_resource(USER_INPUT_FILE)).unwrap_or_default()
+}
+
+pub fn merge_bindings(
+    bindings: &mut Bindings<StringBindings>,
+    overrides: &Bindings<StringBindings>,
+) {
+    for action in overrides.actions() {
+        let existing: Vec<Vec<Button>> = bindings
+            .action_bindings(action)
+            .map(|binding| binding.to_vec())
+            .collect();
+        for binding in existing.iter() {
+            bindings.remove_action_binding(action, binding);
+        }
+        for binding in overrides.action_bindings(action) {
+            if let Err(err) = bindings.insert_action_binding(action.clone(), binding.to_vec()) {
+                warn!(target: "input", "Skipping override for {}: {}", action, err);
+            }
+        }
+    }
+    for axis_id in overrides.axes() {
+        if let Some(axis) = overrides.axis(axis_id) {
+            let previous = bindings.remove_axis(axis_id);
+            if let Err(err) = bindings.insert_axis(axis_id.clone(), axis.clone()) {
+                warn!(target: "input", "Skipping override for {}: {}", axis_id, err);
+                if let Some(previous) = previous {
+                    bindings.insert_axis(axis_id.clone(), previous).ok();
+                }
+            }
+        }
+    }
+}
+
+fn button_name(button: &Button) -> String {
+    match button {
+        Button::Key(key) => format!("{:?}", key),
+        Button::Mouse(mouse) => format!("Mouse {:?}", mouse),
+        Button::Controller(_, button) => format!("Pad {:?}", button),
+        other => format!("{:?}", other),
+    }
+}
+
+fn describe_slot(bindings: &Bindings<StringBindings>, slot: BindingSlot) -> String {
+    match slot {
+        BindingSlot::AxisPos(id) | BindingSlot::AxisNeg(id) => match bindings.axis(id) {
+            Some(Axis::Emulated { pos, neg }) => {
+                if let BindingSlot::AxisPos(_) = slot {
+                    button_name(pos)
+                } else {
+                    button_name(neg)
+                }
+            }
+            Some(_) => "(analog)".to_string(),
+            None => "(unbound)".to_string(),
+        },
+        BindingSlot::Action(id) => {
+            let names: Vec<String> = bindings
+                .action_bindings(id)
+                .map(|binding| {
+                    binding
+                        .iter()
+                        .map(button_name)
+                        .collect::<Vec<String>>()
+                        .join(" + ")
+                })
+                .collect();
+            if names.is_empty() {
+                "(unbound)".to_string()
+            } else {
+                names.join(" / ")
+            }
+        }
+    }
+}
+
+/// Replaces one side of an emulated axis, or the primary binding of an action.
+pub fn rebind(
+    bindings: &mut Bindings<StringBindings>,
+    slot: BindingSlot,
+    button: Button,
+) -> Result<(), Error> {
+    match slot {
+        BindingSlot::AxisPos(id) | BindingSlot::AxisNeg(id) => {
+            let (pos, neg) = match bindings.axis(id) {
+                Some(Axis::Emulated { pos, neg }) => (*pos, *neg),
+                Some(_) => {
+                    return Err(Error::from_string(format!("{} is an analog axis", id)));
+                }
+                None => return Err(Error::from_string(format!("{} has no keys to replace", id))),
+            };
+            let axis = if let BindingSlot::AxisPos(_) = slot {
+                Axis::Emulated { pos: button, neg }
+            } else {
+                Axis::Emulated { pos, neg: button }
+            };
+            let previous = bindings.remove_axis(id);
+            if let Err(err) = bindings.insert_axis(id.to_string(), axis) {
+                if let Some(previous) = previous {
+                    bindings.insert_axis(id.to_string(), previous).ok();
+                }
+                return Err(Error::from_string(format!("{}", err)));
+            }
+        }
+        BindingSlot::Action(id) => {
+            let mut existing: Vec<Vec<Button>> = bindings
+                .action_bindings(id)
+                .map(|binding| binding.to_vec())
+                .collect();
+            for binding in existing.iter() {
+                bindings.remove_action_binding(id, binding);
+            }
+            if existing.is_empty() {
+                existing.push(vec![button]);
+            } else {
+                existing[0] = vec![button];
+            }
+            let mut result = Ok(());
+            for binding in existing.into_iter() {
+                if let Err(err) = bindings.insert_action_binding(id.to_string(), binding) {
+                    result = Err(Error::from_string(format!("{}", err)));
+                }
+            }
+            return result;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the live binding for a slot into the user overrides.
+fn store_override(
+    overrides: &mut Bindings<StringBindings>,
+    bindings: &Bindings<StringBindings>,
+    slot: BindingSlot,
+) {
+    match slot {
+        BindingSlot::AxisPos(id) | BindingSlot::AxisNeg(id) => {
+            if let Some(axis) = bindings.axis(id) {
+                overrides.remove_axis(id);
+                overrides.insert_axis(id.to_string(), axis.clone()).ok();
+            }
+        }
+        BindingSlot::Action(id) => {
+            let existing: Vec<Vec<Button>> = overrides
+                .action_bindings(id)
+                .map(|binding| binding.to_vec())
+                .collect();
+            for binding in existing.iter() {
+                overrides.remove_action_binding(id, binding);
+            }
+            for binding in bindings.action_bindings(id) {
+                overrides
+                    .insert_action_binding(id.to_string(), binding.to_vec())
+                    .ok();
+            }
+        }
+    }
+}
+
+pub struct ControlsState {
+    capturing: Option<(&'static str, BindingSlot)>,
+    overrides: Bindings<StringBindings>,
+    status: String,
+}
+
+impl Default for ControlsState {
+    fn default() -> Self {
+        ControlsState {
+            capturing: None,
+            overrides: load_user_bindings(),
+            status: "".to_string(),
+        }
+    }
+}
+
+impl ControlsState {
+    fn capture(&mut self, world: &mut World, button: Button) {
+        if let Some((name, slot)) = self.capturing.take() {
+            let mut input = world.write_resource::<InputHandler<StringBindings>>();
+            match rebind(&mut input.bindings, slot, button) {
+                Ok(()) => {
+                    store_override(&mut self.overrides, &input.bindings, slot);
+                    self.status = match self.overrides.write(get_resource(USER_INPUT_FILE)) {
+                        Ok(()) => format!("Bound {} to {}", name, button_name(&button)),
+                        Err(err) => format!("Could not save controls: {}", err),
+                    };
+                }
+                Err(err) => {
+                    self.status = format!("{} not bound: {}", button_name(&button), err);
+                }
+            }
+        }
+    }
+
+    fn refresh_labels(&self, world: &mut World) {
+        let capturing = self.capturing;
+        let status = self.status.clone();
+        world.exec(
+            |(input, transforms, mut texts): (
+                Read<'_, InputHandler<StringBindings>>,
+                ReadStorage<'_, UiTransform>,
+                WriteStorage<'_, UiText>,
+            )| {
+                for (transform, text) in (&transforms, &mut texts).join() {
+                    if transform.id.eq("controls_status") {
+                        text.text = status.clone();
+                    }
+                    for (name, slot) in REBINDABLE.iter() {
+                        if transform.id.eq(&format!("binding_{}", name)) {
+                            text.text = if capturing.map(|(n, _)| n) == Some(*name) {
+                                "Press a key...".to_string()
+                            } else {
+                                describe_slot(&input.bindings, *slot)
+                            };
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+impl SimpleState for ControlsState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        data.world.delete_all();
+        data.world.exec(|mut creator: UiCreator<'_>| {
+            creator.create(get_resource("controls.ron"), ());
+        });
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        data.world.delete_all();
+    }
+
+    fn handle_event(
+        &mut self,
+        data: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        match &event {
+            StateEvent::Window(event) => {
+                if is_close_requested(&event) {
+                    Trans::Quit
+                } else if is_key_down(&event, VirtualKeyCode::Escape) && self.capturing.is_some() {
+                    self.capturing = None;
+                    self.status = "Cancelled".to_string();
+                    Trans::None
+                } else {
+                    Trans::None
+                }
+            }
+            StateEvent::Input(InputEvent::ButtonPressed(button)) => {
+                if *button != Button::Key(VirtualKeyCode::Escape) {
+                    self.capture(data.world, *button);
+                }
+                Trans::None
+            }
+            StateEvent::Ui(ui_event) => {
+                if ui_event.event_type != UiEventType::Click || self.capturing.is_some() {
+                    return Trans::None;
+                }
+                data.world.exec(|finder: UiFinder<'_>| {
+                    if let Some(back) = finder.find("back") {
+                        if back == ui_event.target {
+                            return Trans::Pop;
+                        }
+                    }
+                    for (name, slot) in REBINDABLE.iter() {
+                        if let Some(button) = finder.find(&format!("rebind_{}", name)) {
+                            if button == ui_event.target {
+                                self.capturing = Some((*name, *slot));
+                                self.status = "".to_string();
+                            }
+                        }
+                    }
+                    Trans::None
+                })
+            }
+            _ => Trans::None,
+        }
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        self.refresh_labels(data.world);
+        SimpleTrans::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_axis(pos: VirtualKeyCode, neg: VirtualKeyCode) -> Axis {
+        Axis::Emulated {
+            pos: Button::Key(pos),
+            neg: Button::Key(neg),
+        }
+    }
+
+    fn keys(bindings: &Bindings<StringBindings>, action: &str) -> Vec<Vec<Button>> {
+        bindings
+            .action_bindings(action)
+            .map(|binding| binding.to_vec())
+            .collect()
+    }
+
+    /// Arrow keys to move, Space or Z to attack.
+    fn defaults() -> Bindings<StringBindings> {
+        let mut bindings = Bindings::<StringBindings>::default();
+        bindings
+            .insert_axis("updown", key_axis(VirtualKeyCode::Up, VirtualKeyCode::Down))
+            .unwrap();
+        bindings
+            .insert_action_binding(
+                "attack".to_string(),
+                vec![Button::Key(VirtualKeyCode::Space)],
+            )
+            .unwrap();
+        bindings
+            .insert_action_binding("attack".to_string(), vec![Button::Key(VirtualKeyCode::Z)])
+            .unwrap();
+        bindings
+    }
+
+    #[test]
+    fn rebind_replaces_one_side_of_an_axis() {
+        let mut bindings = defaults();
+        rebind(
+            &mut bindings,
+            BindingSlot::AxisNeg("updown"),
+            Button::Key(VirtualKeyCode::S),
+        )
+        .unwrap();
+        assert_eq!(
+            bindings.axis("updown"),
+            Some(&key_axis(VirtualKeyCode::Up, VirtualKeyCode::S))
+        );
+    }
+
+    #[test]
+    fn rebind_refuses_an_unbound_axis() {
+        let mut bindings = defaults();
+        let result = rebind(
+            &mut bindings,
+            BindingSlot::AxisPos("leftright"),
+            Button::Key(VirtualKeyCode::D),
+        );
+        assert!(result.is_err());
+        assert_eq!(bindings.axis("leftright"), None);
+    }
+
+    #[test]
+    fn rebind_refuses_an_analog_axis() {
+        let mut bindings = defaults();
+        let wheel = Axis::MouseWheel { horizontal: false };
+        bindings.insert_axis("zoom", wheel.clone()).unwrap();
+        let result = rebind(
+            &mut bindings,
+            BindingSlot::AxisPos("zoom"),
+            Button::Key(VirtualKeyCode::Q),
+        );
+        assert!(result.is_err());
+        assert_eq!(bindings.axis("zoom"), Some(&wheel));
+    }
+
+    #[test]
+    fn rebind_keeps_the_axis_when_the_key_is_taken() {
+        let mut bindings = defaults();
+        let result = rebind(
+            &mut bindings,
+            BindingSlot::AxisPos("updown"),
+            Button::Key(VirtualKeyCode::Space),
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            bindings.axis("updown"),
+            Some(&key_axis(VirtualKeyCode::Up, VirtualKeyCode::Down))
+        );
+    }
+
+    #[test]
+    fn rebind_replaces_only_the_primary_action_binding() {
+        let mut bindings = defaults();
+        rebind(
+            &mut bindings,
+            BindingSlot::Action("attack"),
+            Button::Key(VirtualKeyCode::X),
+        )
+        .unwrap();
+        let mut attack = keys(&bindings, "attack");
+        attack.sort_by_key(|binding| format!("{:?}", binding));
+        assert_eq!(
+            attack,
+            vec![
+                vec![Button::Key(VirtualKeyCode::X)],
+                vec![Button::Key(VirtualKeyCode::Z)],
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_replaces_overridden_bindings_and_keeps_the_rest() {
+        let mut bindings = defaults();
+        bindings
+            .insert_action_binding("interact".to_string(), vec![Button::Key(VirtualKeyCode::E)])
+            .unwrap();
+        let mut overrides = Bindings::<StringBindings>::default();
+        overrides
+            .insert_axis("updown", key_axis(VirtualKeyCode::W, VirtualKeyCode::S))
+            .unwrap();
+        overrides
+            .insert_action_binding("attack".to_string(), vec![Button::Key(VirtualKeyCode::J)])
+            .unwrap();
+        merge_bindings(&mut bindings, &overrides);
+        assert_eq!(
+            bindings.axis("updown"),
+            Some(&key_axis(VirtualKeyCode::W, VirtualKeyCode::S))
+        );
+        assert_eq!(
+            keys(&bindings, "attack"),
+            vec![vec![Button::Key(VirtualKeyCode::J)]]
+        );
+        assert_eq!(
+            keys(&bindings, "interact"),
+            vec![vec![Button::Key(VirtualKeyCode::E)]]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_the_default_when_an_override_conflicts() {
+        let mut bindings = defaults();
+        let mut overrides = Bindings::<StringBindings>::default();
+        // Space already attacks, so moving with it can't be merged in.
+        overrides
+            .insert_axis("updown", key_axis(VirtualKeyCode::Space, VirtualKeyCode::S))
+            .unwrap();
+        merge_bindings(&mut bindings, &overrides);
+        assert_eq!(
+            bindings.axis("updown"),
+            Some(&key_axis(VirtualKeyCode::Up, VirtualKeyCode::Down))
+        );
+    }
+}
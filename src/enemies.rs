@@ -1,48 +1,62 @@
-use crate::assets::{AnimationId, Direction, MyPrefabData, PrefabStorage};
+use crate::ai::{self, Agent, AiConfig, AiContext, Blackboard};
+use crate::assets::{get_resource, AnimationId, Assets, Direction, MyPrefabData};
+use crate::building::Structure;
 use crate::combat::*;
+use crate::mixer::SoundEvent;
+use crate::perception::{HearingSystem, Perception};
 use crate::physics::*;
 use crate::prelude::*;
+use crate::spatial::SpatialIndex;
+use crate::squad::{SquadCoordinator, SquadSystem};
+use crate::world::{MapPart, WorldTiles};
 use amethyst::{
     animation::*,
     assets::{Handle, Prefab},
-    core::{bundle::SystemBundle, timing::Time, transform::*},
-    ecs::world::LazyBuilder,
+    config::Config,
+    core::{bundle::SystemBundle, shrev::EventChannel, timing::Time, transform::*},
+    ecs::{prelude::ParallelIterator, world::LazyBuilder},
     renderer::SpriteRender,
     ui::{UiText, UiTransform},
 };
+use log::{debug, info, trace, warn};
 use na::Isometry2;
 use ncollide2d::shape::*;
 use nphysics2d::object::*;
+use serde::{Deserialize, Serialize};
 
 fn spawn_crab(
-    prefab: Handle<Prefab<MyPrefabData>>,
-    player_builder: EntityBuilder,
+    prefab: Option<Handle<Prefab<MyPrefabData>>>,
+    mut player_builder: EntityBuilder,
     x: f32,
     y: f32,
     waypoint: &Entity,
 ) -> Entity {
     let shape = ShapeHandle::new(Ball::new(8.0));
-    let body = RigidBodyDesc::new().status(BodyStatus::Dynamic).mass(1.0);
+    let body = RigidBodyDesc::new().mass(1.0);
     let collider = ColliderDesc::new(shape);
     let mut transform = Transform::default();
     transform.set_translation_xyz(x, y, 1.0);
+    if let Some(prefab) = prefab {
+        player_builder = player_builder.with(prefab);
+    }
     player_builder
-        .with(PhysicsDesc::new(body, collider))
-        .with(prefab)
+        .with(PhysicsDesc::new(body, collider).character(CharacterController::default()))
         .with(Goblin {
             walk_speed: 40.0,
-            state: GoblinState::Idling(waypoint.clone(), 1.0),
+            archetype: "goblin".to_string(),
+            blackboard: Blackboard::resting_at(waypoint.clone(), 1.0),
             facing: Direction::South,
             attack_distance: 70.0,
             lunge_speed: 120.0,
-            chase_distance: 80.0,
         })
+        .with(Perception::default())
+        .with(MovementIntent::default())
         .with(Health::new(false, 3))
         .with(transform)
         .build()
 }
 
-fn spawn_goblin_attack_sensor(
+pub fn spawn_goblin_attack_sensor(
     builder: LazyBuilder,
     goblin: Entity,
     direction: Direction,
@@ -51,7 +65,7 @@ fn spawn_goblin_attack_sensor(
     let shape = ShapeHandle::new(Cuboid::new(Vector2::new(6.0, 6.0)));
     let collider = ColliderDesc::new(shape)
         .sensor(true)
-        .position(Isometry2::new(offset, 0.0));
+        .position(Isometry2::new(offset, direction.angle()));
     builder
         .with(AttachedSensor::new(collider))
         .with(AttackHitbox {
@@ -99,43 +113,23 @@ pub fn spawn_spawner_world(world: &mut World, x: f32, y: f32, waypoint: &Entity)
 }
 
 pub fn spawn_goblin_world(world: &mut World, x: f32, y: f32, waypoint: &Entity) -> Entity {
-    let prefab = {
-        let prefabs = world.read_resource::<PrefabStorage>();
-        prefabs.crab.clone()
-    };
+    let prefab = world.read_resource::<Assets>().prefab("goblin");
+    if prefab.is_none() {
+        warn!(target: "assets", "No \"goblin\" prefab in assets.ron");
+    }
     let builder = world.create_entity();
     spawn_crab(prefab, builder, x, y, waypoint)
 }
 
-#[derive(Debug, PartialEq)]
-pub enum GoblinState {
-    Idling(Entity, f32),
-    Moving(Entity),
-    Chasing(Entity, Entity),
-    Attacking(Entity, usize, f32),
-    Hit(Entity, f32),
-}
-
-impl GoblinState {
-    pub fn get_waypoint(&self) -> Entity {
-        match self {
-            GoblinState::Idling(waypoint, _) => *waypoint,
-            GoblinState::Moving(waypoint) => *waypoint,
-            GoblinState::Chasing(waypoint, _) => *waypoint,
-            GoblinState::Attacking(waypoint, _, _) => *waypoint,
-            GoblinState::Hit(waypoint, _) => *waypoint,
-        }
-    }
-}
-
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
 pub struct Goblin {
     pub walk_speed: f32,
     pub lunge_speed: f32,
-    pub state: GoblinState,
+    /// Which behaviour tree from `ai.ron` drives this goblin.
+    pub archetype: String,
+    pub blackboard: Blackboard,
     pub facing: Direction,
-    pub chase_distance: f32,
     pub attack_distance: f32,
 }
 
@@ -153,121 +147,25 @@ pub struct Waypoint {
 }
 
 struct GoblinAiSystem;
-impl GoblinAiSystem {
-    fn should_chase<'s>(
-        &self,
-        physics: &Physics<f32>,
-        handles: &ReadStorage<'s, PhysicsHandle>,
-        entities: &Entities<'s>,
-        names: &ReadStorage<'s, Named>,
-        goblin: &Goblin,
-        goblin_handle: &PhysicsHandle,
-    ) -> Option<Entity> {
-        self.should_chase_name(
-            &physics,
-            &handles,
-            &entities,
-            &names,
-            "pylon",
-            &goblin,
-            &goblin_handle,
-        )
-        .or_else(|| {
-            self.should_chase_name(
-                &physics,
-                &handles,
-                &entities,
-                &names,
-                "player",
-                &goblin,
-                &goblin_handle,
-            )
-        })
-    }
-    fn should_chase_name<'s>(
-        &self,
-        physics: &Physics<f32>,
-        handles: &ReadStorage<'s, PhysicsHandle>,
-        entities: &Entities<'s>,
-        names: &ReadStorage<'s, Named>,
-        name: &'static str,
-        goblin: &Goblin,
-        goblin_handle: &PhysicsHandle,
-    ) -> Option<Entity> {
-        get_named_entity(entities, names, name)
-            .and_then(|player_entity| {
-                handles
-                    .get(player_entity)
-                    .map(|handle| (player_entity, handle))
-            })
-            .and_then(|(player_entity, player_handle)| {
-                physics
-                    .get_between(player_handle, goblin_handle)
-                    .map(|offset| (player_entity, offset))
-            })
-            .and_then(|(player_entity, offset)| {
-                if offset.x * offset.x + offset.y * offset.y
-                    <= goblin.chase_distance * goblin.chase_distance
-                {
-                    Some(player_entity)
-                } else {
-                    None
-                }
-            })
-    }
-    fn get_waypoint<'s>(
-        &self,
-        physics: &Physics<f32>,
-        goblin_handle: &PhysicsHandle,
-        waypoint_handle: &PhysicsHandle,
-        current: &Waypoint,
-    ) -> Option<Entity> {
-        if let Some(distance) = physics.get_between(waypoint_handle, goblin_handle) {
-            if distance.x * distance.x + distance.y * distance.y < current.margin {
-                current.next
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    }
-
-    fn walk(
-        &self,
-        direction: Direction,
-        physics: &mut Physics<f32>,
-        handle: &PhysicsHandle,
-        goblin: &mut Goblin,
-        control_set: &mut AnimationControlSet<AnimationId, SpriteRender>,
-        animation_set: &AnimationSet<AnimationId, SpriteRender>,
-    ) {
-        goblin.facing = direction;
-        physics.set_velocity(handle, goblin.facing.tilts() * goblin.walk_speed);
-        set_active_animation(
-            control_set,
-            AnimationId::Walk(goblin.facing),
-            &animation_set,
-            EndControl::Loop(None),
-            1.0,
-        );
-    }
-}
 
 impl<'s> System<'s> for GoblinAiSystem {
     type SystemData = (
-        Write<'s, Physics<f32>>,
+        Read<'s, Physics>,
         Read<'s, Time>,
-        ReadStorage<'s, Named>,
+        Read<'s, AiConfig>,
         ReadStorage<'s, Waypoint>,
-        ReadStorage<'s, AttachedSensor>,
-        ReadStorage<'s, Parent>,
+        Read<'s, SpatialIndex>,
         ReadStorage<'s, PhysicsHandle>,
         ReadStorage<'s, AnimationSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, AnimationControlSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, Goblin>,
-        WriteStorage<'s, AttackHitbox>,
-        ReadStorage<'s, GoblinSpawner>,
+        WriteStorage<'s, Perception>,
+        WriteStorage<'s, MovementIntent>,
+        ReadStorage<'s, AttackHitbox>,
+        ReadStorage<'s, MapPart>,
+        ReadStorage<'s, Structure>,
+        Read<'s, SquadCoordinator>,
+        Option<Read<'s, WorldTiles>>,
         Read<'s, LazyUpdate>,
         Entities<'s>,
     );
@@ -275,171 +173,75 @@ impl<'s> System<'s> for GoblinAiSystem {
     fn run(
         &mut self,
         (
-            mut physics,
+            physics,
             time,
-            names,
+            config,
             waypoints,
-            sensors,
-            parents,
+            index,
             handles,
             animation_sets,
             mut control_sets,
             mut goblins,
-            mut attacks,
-            _spawner,
+            mut perceptions,
+            mut intents,
+            attacks,
+            map_parts,
+            structures,
+            squad,
+            tiles,
             lazy,
             entities,
         ): Self::SystemData,
     ) {
-        for (entity, handle, mut goblin) in (&entities, &handles, &mut goblins).join() {
-            if let (Some(animation_set), Some(mut control_set)) = (
-                animation_sets.get(entity),
-                get_animation_set(&mut control_sets, entity),
-            ) {
-                match goblin.state {
-                    GoblinState::Moving(target) => {
-                        if let Some(player) = self
-                            .should_chase(&physics, &handles, &entities, &names, &goblin, &handle)
-                        {
-                            goblin.state = GoblinState::Chasing(target, player);
-                        } else {
-                            self.walk(
-                                goblin.facing,
-                                &mut physics,
-                                &handle,
-                                &mut goblin,
-                                &mut control_set,
-                                &animation_set,
-                            );
-                            if let Some(waypoint_handle) = handles.get(target) {
-                                if let Some(next) = self.get_waypoint(
-                                    &physics,
-                                    waypoint_handle,
-                                    handle,
-                                    waypoints.get(target).unwrap(),
-                                ) {
-                                    goblin.state = GoblinState::Moving(next);
-                                } else {
-                                    let offset = physics.get_between(handle, waypoint_handle);
-                                    goblin.facing = Direction::short_seek(offset.unwrap(), 4.0);
-                                }
-                            }
-                        }
-                    }
-                    GoblinState::Idling(waypoint, time_left) => {
-                        for (_, sensor) in get_sensors(&entities, &sensors, &parents, entity).iter()
-                        {
-                            let sensor = *sensor;
-                            if attacks.contains(sensor) {
-                                lazy.exec(move |world| {
-                                    world.delete_entity(sensor);
-                                });
-                            }
-                        }
-                        if let Some(player) = self
-                            .should_chase(&physics, &handles, &entities, &names, &goblin, &handle)
-                            .and_then(|player| if time_left < 2.0 { Some(player) } else { None })
-                        {
-                            goblin.state = GoblinState::Chasing(waypoint, player);
-                        } else {
-                            physics.set_velocity(handle, Vector2::zeros());
-                            set_active_animation(
-                                control_set,
-                                AnimationId::Idle(goblin.facing),
-                                &animation_set,
-                                EndControl::Loop(None),
-                                1.0,
-                            );
-                            if time_left < time.delta_seconds() {
-                                goblin.state = GoblinState::Moving(waypoint);
-                            } else {
-                                goblin.state =
-                                    GoblinState::Idling(waypoint, time_left - time.delta_seconds());
-                            }
-                        }
-                    }
-                    GoblinState::Chasing(waypoint, player) => {
-                        if let Some(player_handle) = handles.get(player) {
-                            let mut found = false;
-                            for direction in Direction::vec() {
-                                for (seen, distance) in
-                                    physics.ray_cast(handle, direction.tilts(), None).iter()
-                                {
-                                    if *seen == player && *distance < goblin.attack_distance {
-                                        set_active_animation(
-                                            control_set,
-                                            AnimationId::Attack(direction),
-                                            &animation_set,
-                                            EndControl::Stay,
-                                            1.0,
-                                        );
-                                        goblin.state =
-                                            GoblinState::Attacking(waypoint, rand::random(), 0.0);
-                                        goblin.facing = direction;
-                                        spawn_goblin_attack_sensor(
-                                            lazy.create_entity(&entities),
-                                            entity,
-                                            goblin.facing,
-                                        );
-                                        found = true;
-                                    }
-                                }
-                            }
-                            if !found {
-                                if let Some(offset) = physics.get_between(handle, player_handle) {
-                                    self.walk(
-                                        Direction::short_seek(offset, 4.0),
-                                        &mut physics,
-                                        &handle,
-                                        &mut goblin,
-                                        &mut control_set,
-                                        &animation_set,
-                                    );
-                                }
-                            }
-                        } else {
-                            goblin.state = GoblinState::Idling(waypoint, 4.0);
-                        }
-                    }
-                    GoblinState::Attacking(waypoint, attack_id, progress) => {
-                        if let Some(AnimationId::Attack(_)) = get_active_animation(control_set) {
-                            if progress > 0.375 {
-                                physics.set_velocity(
-                                    handle,
-                                    goblin.facing.tilts() * goblin.lunge_speed,
-                                );
-                            } else {
-                                physics.set_velocity(handle, Vector2::zeros());
-                            }
-                            goblin.state = GoblinState::Attacking(
-                                waypoint,
-                                attack_id,
-                                progress + time.delta_seconds(),
-                            );
-                        } else {
-                            goblin.state = GoblinState::Idling(waypoint, 4.0);
-                        }
-                    }
-                    GoblinState::Hit(waypoint, size) => {
-                        if size < time.delta_seconds() {
-                            goblin.state = GoblinState::Idling(waypoint, 3.0);
-                        } else {
-                            goblin.state = GoblinState::Hit(waypoint, size - time.delta_seconds());
-                            set_active_animation(
-                                control_set,
-                                AnimationId::Staggered(goblin.facing),
-                                &animation_set,
-                                EndControl::Stay,
-                                1.0,
-                            );
-                        }
-                    }
-                    _ => {}
-                }
-            } else {
-                println!("NO ANIMATION SET OR CONTROLLER");
-            }
+        // Control sets are created on first use, which can't happen inside the parallel join.
+        for (entity, _, _) in (&entities, &goblins, &animation_sets).join() {
+            get_animation_set(&mut control_sets, entity);
         }
+        let ctx = AiContext {
+            physics: &physics,
+            delta: time.delta_seconds(),
+            entities: &entities,
+            handles: &handles,
+            waypoints: &waypoints,
+            index: &index,
+            attacks: &attacks,
+            map_parts: &map_parts,
+            structures: &structures,
+            squad: &squad,
+            tiles: tiles.as_deref(),
+            lazy: &lazy,
+        };
+        (
+            &entities,
+            &handles,
+            &mut goblins,
+            &mut perceptions,
+            &mut intents,
+            &animation_sets,
+            &mut control_sets,
+        )
+            .par_join()
+            .for_each(
+                |(entity, handle, goblin, perception, intent, animation_set, control_set)| {
+                    let tree = match config.trees.get(&goblin.archetype) {
+                        Some(tree) => tree,
+                        None => {
+                            trace!(target: "ai", "No behaviour tree for {:?}", goblin.archetype);
+                            return;
+                        }
+                    };
+                    let mut agent = Agent {
+                        entity,
+                        handle,
+                        goblin,
+                        perception,
+                        intent,
+                        control_set,
+                        animation_set,
+                    };
+                    ai::tick(tree, &ctx, &mut agent);
+                },
+            );
     }
 }
 
@@ -449,13 +251,37 @@ pub struct WaveState {
     pub wave_num: usize,
 }
 
+/// Holds the next wave back while the upgrade shop is open.
+#[derive(Debug, Default)]
+pub struct Intermission {
+    pub shopping: bool,
+    /// The wave whose aftermath the shop last opened in.
+    pub shopped_wave: usize,
+}
+
 pub struct WaveSystem;
 
 pub const SPAWNS: &'static [usize] = &[3, 5, 7, 9, 11];
 
+/// Goblins spawned per wave, loaded from `waves.ron`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WaveData {
+    pub spawns: Vec<usize>,
+}
+
+impl Default for WaveData {
+    fn default() -> Self {
+        WaveData {
+            spawns: SPAWNS.to_vec(),
+        }
+    }
+}
+
 impl<'s> System<'s> for WaveSystem {
     type SystemData = (
         Write<'s, WaveState>,
+        Read<'s, WaveData>,
+        Read<'s, Intermission>,
         ReadStorage<'s, Goblin>,
         Read<'s, LazyUpdate>,
         ReadStorage<'s, UiTransform>,
@@ -465,7 +291,17 @@ impl<'s> System<'s> for WaveSystem {
     );
     fn run(
         &mut self,
-        (mut waves, goblins, lazy, transforms, mut ui_texts, time, entities): Self::SystemData,
+        (
+            mut waves,
+            wave_data,
+            intermission,
+            goblins,
+            lazy,
+            transforms,
+            mut ui_texts,
+            time,
+            entities,
+        ): Self::SystemData,
     ) {
         let mut goblin_count = 0;
         for _goblin in (&goblins).join() {
@@ -481,12 +317,17 @@ impl<'s> System<'s> for WaveSystem {
             if transform.id.eq("goblin_count") {
                 if goblin_count > 0 {
                     text.text = format!("Goblins Left: {}", goblin_count);
+                } else if intermission.shopping {
+                    text.text = "Shop open".to_string();
                 } else if waves.idle_time < 5.0 {
                     text.text = format!("Next wave in: {}", (5 - waves.idle_time as usize));
                 }
             }
         }
-        if waves.idle_time > 5.0 && waves.wave_num < SPAWNS.len() {
+        if waves.idle_time > 5.0
+            && !intermission.shopping
+            && waves.wave_num < wave_data.spawns.len()
+        {
             lazy.exec_mut(|world| {
                 let spawners = world.exec(
                     |(waves, entities, transforms, spawners): (
@@ -501,8 +342,8 @@ impl<'s> System<'s> for WaveSystem {
                         {
                             let translation = transform.translation();
                             spawn_list.push((translation.x, translation.y, spawner.waypoint));
-                            println!("{:?}", spawn_list);
                         }
+                        debug!(target: "waves", "Spawners: {:?}", spawn_list);
                         spawn_list
                     },
                 );
@@ -510,7 +351,14 @@ impl<'s> System<'s> for WaveSystem {
                     let wave_state = world.read_resource::<WaveState>();
                     wave_state.wave_num
                 };
-                for i in 0..SPAWNS[wave_num] {
+                let spawn_count = world.read_resource::<WaveData>().spawns[wave_num];
+                info!(
+                    target: "waves",
+                    "Spawning wave {} with {} goblins",
+                    wave_num + 1,
+                    spawn_count
+                );
+                for i in 0..spawn_count {
                     let spawner_id: usize = rand::random::<usize>() % spawners.len();
                     let (tx, ty, waypoint) = spawners.get(spawner_id).unwrap();
                     spawn_goblin_world(world, *tx, *ty, waypoint);
@@ -525,11 +373,21 @@ pub struct EnemiesBundle;
 impl<'a, 'b> SystemBundle<'a, 'b> for EnemiesBundle {
     fn build(
         self,
-        _world: &mut World,
+        world: &mut World,
         dispatcher: &mut DispatcherBuilder<'a, 'b>,
     ) -> Result<(), Error> {
+        let config = AiConfig::load(get_resource("ai.ron")).unwrap_or_else(|err| {
+            warn!(target: "ai", "Could not read ai.ron: {}", err);
+            AiConfig::default()
+        });
+        world.insert(config);
         dispatcher.add(WaveSystem, "waves", &[]);
-        dispatcher.add(GoblinAiSystem, "goblin", &[]);
+        let reader = world
+            .fetch_mut::<EventChannel<SoundEvent>>()
+            .register_reader();
+        dispatcher.add(HearingSystem::new(reader), "hearing", &[]);
+        dispatcher.add(SquadSystem, "squad", &[]);
+        dispatcher.add(GoblinAiSystem, "goblin", &["hearing", "squad"]);
         Ok(())
     }
 }
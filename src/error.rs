@@ -0,0 +1,32 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameError {
+    MapParse(String),
+    MapNotLoaded,
+    MissingTileset(usize),
+    MissingLayer(usize, &'static str),
+    MissingMarker(&'static str),
+    InvalidMap(String),
+    MissingBody,
+    MissingAsset(String),
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::MapParse(reason) => write!(f, "Could not parse map: {}", reason),
+            GameError::MapNotLoaded => write!(f, "The map asset failed to load"),
+            GameError::MissingTileset(index) => write!(f, "Map has no tileset {}", index),
+            GameError::MissingLayer(index, purpose) => {
+                write!(f, "Map has no layer {} ({})", index, purpose)
+            }
+            GameError::MissingMarker(marker) => write!(f, "Map has no {} marker", marker),
+            GameError::InvalidMap(problem) => write!(f, "Map is invalid: {}", problem),
+            GameError::MissingBody => write!(f, "Physics handle has no rigid body"),
+            GameError::MissingAsset(name) => write!(f, "No asset named {} in assets.ron", name),
+        }
+    }
+}
+
+impl std::error::Error for GameError {}
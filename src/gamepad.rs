@@ -0,0 +1,231 @@
+use amethyst::{
+    core::{bundle::SystemBundle, shrev::EventChannel},
+    ecs::*,
+    error::Error,
+    input::{
+        ControllerAxis, ControllerButton, ControllerEvent, InputEvent, InputHandler, StringBindings,
+    },
+};
+use gilrs::{Axis, Button, EventType, Gilrs};
+use log::warn;
+use std::collections::VecDeque;
+
+/// Anything that can produce controller events for the `InputHandler`.
+pub trait GamepadSource {
+    fn poll(&mut self) -> Option<ControllerEvent>;
+}
+
+pub struct GilrsSource {
+    gilrs: Option<Gilrs>,
+    pending: VecDeque<ControllerEvent>,
+}
+
+impl GilrsSource {
+    pub fn new() -> Self {
+        let mut pending = VecDeque::new();
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => {
+                // gilrs doesn't report pads that were plugged in before startup.
+                for (id, _gamepad) in gilrs.gamepads() {
+                    pending.push_back(ControllerEvent::ControllerConnected {
+                        which: usize::from(id) as u32,
+                    });
+                }
+                Some(gilrs)
+            }
+            Err(err) => {
+                warn!(target: "input", "Gamepads unavailable: {}", err);
+                None
+            }
+        };
+        GilrsSource { gilrs, pending }
+    }
+}
+
+fn map_button(button: Button) -> Option<ControllerButton> {
+    match button {
+        Button::South => Some(ControllerButton::A),
+        Button::East => Some(ControllerButton::B),
+        Button::West => Some(ControllerButton::X),
+        Button::North => Some(ControllerButton::Y),
+        Button::LeftTrigger => Some(ControllerButton::LeftShoulder),
+        Button::RightTrigger => Some(ControllerButton::RightShoulder),
+        Button::LeftThumb => Some(ControllerButton::LeftStick),
+        Button::RightThumb => Some(ControllerButton::RightStick),
+        Button::Select => Some(ControllerButton::Back),
+        Button::Start => Some(ControllerButton::Start),
+        Button::Mode => Some(ControllerButton::Guide),
+        Button::DPadUp => Some(ControllerButton::DPadUp),
+        Button::DPadDown => Some(ControllerButton::DPadDown),
+        Button::DPadLeft => Some(ControllerButton::DPadLeft),
+        Button::DPadRight => Some(ControllerButton::DPadRight),
+        _ => None,
+    }
+}
+
+fn map_axis(axis: Axis) -> Option<ControllerAxis> {
+    match axis {
+        Axis::LeftStickX => Some(ControllerAxis::LeftX),
+        Axis::LeftStickY => Some(ControllerAxis::LeftY),
+        Axis::RightStickX => Some(ControllerAxis::RightX),
+        Axis::RightStickY => Some(ControllerAxis::RightY),
+        Axis::LeftZ => Some(ControllerAxis::LeftTrigger),
+        Axis::RightZ => Some(ControllerAxis::RightTrigger),
+        _ => None,
+    }
+}
+
+impl GamepadSource for GilrsSource {
+    fn poll(&mut self) -> Option<ControllerEvent> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let gilrs = self.gilrs.as_mut()?;
+        while let Some(event) = gilrs.next_event() {
+            let which = usize::from(event.id) as u32;
+            let mapped = match event.event {
+                EventType::ButtonPressed(button, _) => map_button(button)
+                    .map(|button| ControllerEvent::ControllerButtonPressed { which, button }),
+                EventType::ButtonReleased(button, _) => map_button(button)
+                    .map(|button| ControllerEvent::ControllerButtonReleased { which, button }),
+                EventType::AxisChanged(axis, value, _) => map_axis(axis)
+                    .map(|axis| ControllerEvent::ControllerAxisMoved { which, axis, value }),
+                EventType::Connected => Some(ControllerEvent::ControllerConnected { which }),
+                EventType::Disconnected => Some(ControllerEvent::ControllerDisconnected { which }),
+                _ => None,
+            };
+            if mapped.is_some() {
+                return mapped;
+            }
+        }
+        None
+    }
+}
+
+/// A gamepad that replays queued events, for driving the input handler without hardware.
+#[cfg(test)]
+#[derive(Default)]
+pub struct ScriptedGamepad {
+    pub events: VecDeque<ControllerEvent>,
+}
+
+#[cfg(test)]
+impl ScriptedGamepad {
+    pub fn push(&mut self, event: ControllerEvent) {
+        self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+impl GamepadSource for ScriptedGamepad {
+    fn poll(&mut self) -> Option<ControllerEvent> {
+        self.events.pop_front()
+    }
+}
+
+pub struct GamepadSystem<S: GamepadSource> {
+    source: S,
+}
+
+impl<S: GamepadSource> GamepadSystem<S> {
+    pub fn new(source: S) -> Self {
+        GamepadSystem { source }
+    }
+}
+
+impl<'s, S: GamepadSource> System<'s> for GamepadSystem<S> {
+    type SystemData = (
+        Write<'s, InputHandler<StringBindings>>,
+        Write<'s, EventChannel<InputEvent<StringBindings>>>,
+    );
+
+    fn run(&mut self, (mut input, mut events): Self::SystemData) {
+        while let Some(event) = self.source.poll() {
+            input.send_controller_event(&event, &mut events);
+        }
+    }
+}
+
+pub struct GamepadBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for GamepadBundle {
+    fn build(
+        self,
+        _world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        // Gilrs isn't Send on every platform, so it stays on the main thread.
+        dispatcher.add_thread_local(GamepadSystem::new(GilrsSource::new()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amethyst::{config::Config, input::Bindings};
+
+    const PAD: u32 = 3;
+
+    /// Feeds `events` through the gamepad system into a handler with the shipped bindings.
+    fn replay(events: Vec<ControllerEvent>) -> InputHandler<StringBindings> {
+        let mut world = World::new();
+        let mut input = InputHandler::<StringBindings>::new();
+        input.bindings =
+            Bindings::load(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/input.ron"))
+                .expect("input.ron should parse");
+        world.insert(input);
+        world.insert(EventChannel::<InputEvent<StringBindings>>::new());
+        let mut gamepad = ScriptedGamepad::default();
+        gamepad.push(ControllerEvent::ControllerConnected { which: PAD });
+        for event in events {
+            gamepad.push(event);
+        }
+        GamepadSystem::new(gamepad).run_now(&world);
+        world
+            .remove::<InputHandler<StringBindings>>()
+            .expect("input handler")
+    }
+
+    fn stick(axis: ControllerAxis, value: f32) -> ControllerEvent {
+        ControllerEvent::ControllerAxisMoved {
+            which: PAD,
+            axis,
+            value,
+        }
+    }
+
+    #[test]
+    fn sticks_drive_movement_and_aim_axes() {
+        let input = replay(vec![
+            stick(ControllerAxis::LeftX, 1.0),
+            stick(ControllerAxis::LeftY, -1.0),
+            stick(ControllerAxis::RightX, 0.1),
+        ]);
+        assert_eq!(input.axis_value("pad_leftright"), Some(1.0));
+        assert_eq!(input.axis_value("pad_updown"), Some(-1.0));
+        // Inside the aim dead zone.
+        assert_eq!(input.axis_value("aim_leftright"), Some(0.0));
+        assert_eq!(input.axis_value("aim_updown"), Some(0.0));
+    }
+
+    #[test]
+    fn buttons_drive_actions() {
+        let input = replay(vec![
+            ControllerEvent::ControllerButtonPressed {
+                which: PAD,
+                button: ControllerButton::A,
+            },
+            ControllerEvent::ControllerButtonPressed {
+                which: PAD,
+                button: ControllerButton::X,
+            },
+            ControllerEvent::ControllerButtonReleased {
+                which: PAD,
+                button: ControllerButton::X,
+            },
+        ]);
+        assert_eq!(input.action_is_down("attack"), Some(true));
+        assert_eq!(input.action_is_down("interact"), Some(false));
+    }
+}
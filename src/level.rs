@@ -0,0 +1,873 @@
+use crate::error::GameError;
+use ncollide2d::shape::{Ball, ConvexPolygon, Cuboid, ShapeHandle};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub type TileLayer = Vec<Vec<tiled::LayerTile>>;
+
+pub fn layer_tile_id(layer: &TileLayer, x: usize, y: usize) -> Option<usize> {
+    layer.get(y).and_then(|row| row.get(x)).and_then(|tile| {
+        if tile.gid > 0 {
+            Some(tile.gid as usize - 1)
+        } else {
+            None
+        }
+    })
+}
+
+pub fn tile_type(tileset: &tiled::Tileset, tile_id: usize) -> String {
+    for tile in tileset.tiles.iter() {
+        if tile.id == tile_id as u32 {
+            if let Some(tile_type) = &tile.tile_type {
+                return tile_type.to_string();
+            } else {
+                return "".to_string();
+            }
+        }
+    }
+    "".to_string()
+}
+
+pub fn is_fence(tileset: &tiled::Tileset, tile_id: usize) -> bool {
+    tile_type(tileset, tile_id).eq("f")
+}
+
+/// What a fence tile turns into when it's knocked down, unless it names a `broken_tile`: the
+/// first tile in the sheet, plain ground.
+const GROUND_TILE: usize = 0;
+
+/// The broken variant of every fence tile in the tileset.
+pub fn broken_variants(tileset: &tiled::Tileset) -> HashMap<usize, usize> {
+    tileset
+        .tiles
+        .iter()
+        .filter(|tile| is_fence(tileset, tile.id as usize))
+        .map(|tile| {
+            let broken = match tile.properties.get("broken_tile") {
+                Some(tiled::PropertyValue::IntValue(id)) if *id >= 0 => *id as usize,
+                _ => GROUND_TILE,
+            };
+            (tile.id as usize, broken)
+        })
+        .collect()
+}
+
+/// How a tile affects whoever walks on it, from the tile's custom properties in Tiled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Terrain {
+    pub speed_multiplier: f32,
+    pub impassable_for_enemies: bool,
+    pub damage_per_second: f32,
+}
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Terrain {
+            speed_multiplier: 1.0,
+            impassable_for_enemies: false,
+            damage_per_second: 0.0,
+        }
+    }
+}
+
+fn float_property(properties: &tiled::Properties, name: &str) -> Option<f32> {
+    match properties.get(name)? {
+        tiled::PropertyValue::FloatValue(value) => Some(*value),
+        tiled::PropertyValue::IntValue(value) => Some(*value as f32),
+        _ => None,
+    }
+}
+
+/// The terrain of every tile in the tileset that differs from plain ground.
+pub fn tileset_terrain(tileset: &tiled::Tileset) -> HashMap<usize, Terrain> {
+    let mut terrain = HashMap::new();
+    for tile in tileset.tiles.iter() {
+        let properties = &tile.properties;
+        let defaults = Terrain::default();
+        let tile_terrain = Terrain {
+            speed_multiplier: float_property(properties, "speed_multiplier")
+                .unwrap_or(defaults.speed_multiplier),
+            impassable_for_enemies: match properties.get("impassable_for_enemies") {
+                Some(tiled::PropertyValue::BoolValue(value)) => *value,
+                _ => defaults.impassable_for_enemies,
+            },
+            damage_per_second: float_property(properties, "damage_per_second")
+                .unwrap_or(defaults.damage_per_second),
+        };
+        if tile_terrain != defaults {
+            terrain.insert(tile.id as usize, tile_terrain);
+        }
+    }
+    terrain
+}
+
+/// A collision shape drawn on a tile in Tiled's collision editor, placed relative to the
+/// tile's centre with y pointing up.
+#[derive(Clone)]
+pub struct TileCollider {
+    pub position: na::Isometry2<f32>,
+    pub shape: ShapeHandle<f32>,
+}
+
+/// Converts a point in Tiled's tile space, from the top-left corner with y pointing down.
+fn from_tile_space(x: f32, y: f32, tile_size: (u32, u32)) -> na::Vector2<f32> {
+    na::Vector2::new(x - tile_size.0 as f32 / 2.0, tile_size.1 as f32 / 2.0 - y)
+}
+
+/// How a placed tile is mirrored, from the flip flags Tiled stores with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TileFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+    pub diagonal: bool,
+}
+
+impl TileFlip {
+    pub fn of(tile: &tiled::LayerTile) -> Self {
+        TileFlip {
+            horizontal: tile.flip_h,
+            vertical: tile.flip_v,
+            diagonal: tile.flip_d,
+        }
+    }
+
+    /// The flip applied to a point relative to the tile's centre, with y pointing up. Tiled
+    /// flips diagonally first, swapping the tile's top-right and bottom-left corners.
+    fn matrix(&self) -> na::Matrix2<f32> {
+        let mut matrix = na::Matrix2::identity();
+        if self.diagonal {
+            matrix = na::Matrix2::new(0.0, -1.0, -1.0, 0.0) * matrix;
+        }
+        if self.horizontal {
+            matrix = na::Matrix2::new(-1.0, 0.0, 0.0, 1.0) * matrix;
+        }
+        if self.vertical {
+            matrix = na::Matrix2::new(1.0, 0.0, 0.0, -1.0) * matrix;
+        }
+        matrix
+    }
+}
+
+/// Corners of the polygon standing in for an ellipse.
+const ELLIPSE_SEGMENTS: usize = 16;
+
+fn tile_object_collider(
+    object: &tiled::Object,
+    tile_size: (u32, u32),
+    flip: &na::Matrix2<f32>,
+    offset: na::Vector2<f32>,
+) -> Option<TileCollider> {
+    // Tiled rotates clockwise about the object's origin, which is counter-clockwise here.
+    let angle = -object.rotation.to_radians();
+    let origin = from_tile_space(object.x, object.y, tile_size);
+    let rotation = na::Rotation2::new(angle);
+    let at = |x: f32, y: f32| offset + flip * (origin + rotation * na::Vector2::new(x, -y));
+    // A mirrored box is the same box turned to face wherever its side now points.
+    let side = flip * na::Vector2::new(angle.cos(), angle.sin());
+    match &object.shape {
+        tiled::ObjectShape::Rect { width, height } => Some(TileCollider {
+            position: na::Isometry2::new(at(width / 2.0, height / 2.0), side.y.atan2(side.x)),
+            shape: ShapeHandle::new(Cuboid::new(na::Vector2::new(width / 2.0, height / 2.0))),
+        }),
+        tiled::ObjectShape::Ellipse { width, height } if width == height => Some(TileCollider {
+            position: na::Isometry2::new(at(width / 2.0, height / 2.0), 0.0),
+            shape: ShapeHandle::new(Ball::new(width / 2.0)),
+        }),
+        tiled::ObjectShape::Ellipse { width, height } => {
+            let (rx, ry) = (width / 2.0, height / 2.0);
+            let points: Vec<na::Point2<f32>> = (0..ELLIPSE_SEGMENTS)
+                .map(|step| {
+                    let turn = step as f32 / ELLIPSE_SEGMENTS as f32 * 2.0 * std::f32::consts::PI;
+                    na::Point2::from(at(rx + rx * turn.cos(), ry + ry * turn.sin()))
+                })
+                .collect();
+            ConvexPolygon::try_from_points(&points).map(|polygon| TileCollider {
+                position: na::Isometry2::identity(),
+                shape: ShapeHandle::new(polygon),
+            })
+        }
+        tiled::ObjectShape::Polygon { points } => {
+            let points: Vec<na::Point2<f32>> = points
+                .iter()
+                .map(|(x, y)| na::Point2::from(at(*x, *y)))
+                .collect();
+            ConvexPolygon::try_from_points(&points).map(|polygon| TileCollider {
+                position: na::Isometry2::identity(),
+                shape: ShapeHandle::new(polygon),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The collision shapes authored on a tile, if it has any, mirrored as the tile is and placed
+/// relative to the centre of its `cell_size` map cell. Concave polygons are replaced by their
+/// hull, and ellipses that aren't circles by a polygon traced around them.
+pub fn tile_colliders(
+    tileset: &tiled::Tileset,
+    tile_id: usize,
+    flip: TileFlip,
+    cell_size: (u32, u32),
+) -> Vec<TileCollider> {
+    let tile_size = (tileset.tile_width, tileset.tile_height);
+    // Like Tiled draws them, tiles bigger than a cell stick out of its top and right.
+    let offset = na::Vector2::new(
+        (tile_size.0 as f32 - cell_size.0 as f32) / 2.0,
+        (tile_size.1 as f32 - cell_size.1 as f32) / 2.0,
+    );
+    let flip = flip.matrix();
+    tileset
+        .tiles
+        .iter()
+        .find(|tile| tile.id == tile_id as u32)
+        .and_then(|tile| tile.objectgroup.as_ref())
+        .map(|group| {
+            group
+                .objects
+                .iter()
+                .filter_map(|object| tile_object_collider(object, tile_size, &flip, offset))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Splits a map into its tile layer, tileset and marker layer.
+pub fn map_layers(
+    map: &tiled::Map,
+) -> Result<(&TileLayer, &tiled::Tileset, &TileLayer), GameError> {
+    let tile_layer = map
+        .layers
+        .get(0)
+        .ok_or(GameError::MissingLayer(0, "tiles"))?;
+    let object_layer = map
+        .layers
+        .get(1)
+        .ok_or(GameError::MissingLayer(1, "markers"))?;
+    let tileset = map.tilesets.get(0).ok_or(GameError::MissingTileset(0))?;
+    Ok((&tile_layer.tiles, tileset, &object_layer.tiles))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarkerKind {
+    Goblin,
+    Waypoint(usize),
+    Pylon,
+    Player,
+}
+
+impl MarkerKind {
+    fn from_tile_type(tile_type: &str) -> Option<Self> {
+        match tile_type {
+            "Goblin" => Some(MarkerKind::Goblin),
+            "Pylon" => Some(MarkerKind::Pylon),
+            "Player" => Some(MarkerKind::Player),
+            _ => {
+                if tile_type.starts_with("Waypoint") {
+                    tile_type["Waypoint".len()..]
+                        .parse()
+                        .ok()
+                        .filter(|order| *order > 0)
+                        .map(MarkerKind::Waypoint)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub tile: (usize, usize),
+    pub position: (f32, f32),
+}
+
+pub struct LevelLayout {
+    pub map_size: (u32, u32),
+    pub tile_size: (u32, u32),
+    pub fences: HashSet<(usize, usize)>,
+    /// Tiles with collision shapes drawn in Tiled, which replace the full box a fence gets.
+    pub shaped: HashMap<(usize, usize), Vec<TileCollider>>,
+    pub markers: Vec<Marker>,
+}
+
+/// A block of tiles, `width` by `height` from its top-left tile at `x`, `y`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl TileRect {
+    /// World-space centre and half size of the block.
+    pub fn bounds(&self, map_size: (u32, u32), tile_size: (u32, u32)) -> ((f32, f32), (f32, f32)) {
+        let (left, top) = tile_position(self.x as u32, self.y as u32, map_size, tile_size);
+        let (right, bottom) = tile_position(
+            (self.x + self.width - 1) as u32,
+            (self.y + self.height - 1) as u32,
+            map_size,
+            tile_size,
+        );
+        (
+            ((left + right) / 2.0, (top + bottom) / 2.0),
+            (
+                self.width as f32 * tile_size.0 as f32 / 2.0,
+                self.height as f32 * tile_size.1 as f32 / 2.0,
+            ),
+        )
+    }
+}
+
+/// Covers the fence tiles without drawn shapes with as few rectangles as a greedy pass finds:
+/// each one grows right along its row, then down while the whole row below is fence too.
+pub fn fence_rects(
+    fences: &HashSet<(usize, usize)>,
+    shaped: &HashMap<(usize, usize), Vec<TileCollider>>,
+) -> Vec<TileRect> {
+    let mut tiles: Vec<(usize, usize)> = fences
+        .iter()
+        .filter(|tile| !shaped.contains_key(tile))
+        .cloned()
+        .collect();
+    tiles.sort_by_key(|(x, y)| (*y, *x));
+    let mut covered = HashSet::new();
+    let mut rects = Vec::new();
+    for (x, y) in tiles {
+        if covered.contains(&(x, y)) {
+            continue;
+        }
+        let free = |tile: (usize, usize)| {
+            fences.contains(&tile) && !shaped.contains_key(&tile) && !covered.contains(&tile)
+        };
+        let mut width = 1;
+        while free((x + width, y)) {
+            width += 1;
+        }
+        let mut height = 1;
+        while (x..x + width).all(|column| free((column, y + height))) {
+            height += 1;
+        }
+        for row in y..y + height {
+            for column in x..x + width {
+                covered.insert((column, row));
+            }
+        }
+        rects.push(TileRect {
+            x,
+            y,
+            width,
+            height,
+        });
+    }
+    rects
+}
+
+pub fn tile_position(x: u32, y: u32, map_size: (u32, u32), tile_size: (u32, u32)) -> (f32, f32) {
+    let tx = (x * tile_size.0) as f32 - (tile_size.0 as f32 * map_size.0 as f32 / 2.0);
+    let ty = (tile_size.1 as f32 * map_size.1 as f32 / 2.0) - (y * tile_size.1) as f32;
+    (tx, ty)
+}
+
+/// The tile whose centre is nearest a world position, if it lies on the map.
+pub fn tile_at(
+    x: f32,
+    y: f32,
+    map_size: (u32, u32),
+    tile_size: (u32, u32),
+) -> Option<(usize, usize)> {
+    let tx = ((x + tile_size.0 as f32 * map_size.0 as f32 / 2.0) / tile_size.0 as f32).round();
+    let ty = ((tile_size.1 as f32 * map_size.1 as f32 / 2.0 - y) / tile_size.1 as f32).round();
+    if tx < 0.0 || ty < 0.0 || tx >= map_size.0 as f32 || ty >= map_size.1 as f32 {
+        None
+    } else {
+        Some((tx as usize, ty as usize))
+    }
+}
+
+/// Finds every fence tile and marker in the map, without touching the ECS world.
+pub fn scan_level(map: &tiled::Map) -> Result<LevelLayout, GameError> {
+    let (tiles, tileset, objects) = map_layers(map)?;
+    let map_size = (map.width, map.height);
+    let tile_size = (map.tile_width, map.tile_height);
+    let mut fences = HashSet::new();
+    let mut shaped = HashMap::new();
+    let mut shapes_by_id: HashMap<(usize, TileFlip), Vec<TileCollider>> = HashMap::new();
+    let mut markers = Vec::new();
+    for y in 0..map_size.1 {
+        for x in 0..map_size.0 {
+            if let Some(tile_id) = layer_tile_id(tiles, x as usize, y as usize) {
+                if is_fence(tileset, tile_id) {
+                    fences.insert((x as usize, y as usize));
+                }
+                let flip = TileFlip::of(&tiles[y as usize][x as usize]);
+                let colliders = shapes_by_id
+                    .entry((tile_id, flip))
+                    .or_insert_with(|| tile_colliders(tileset, tile_id, flip, tile_size));
+                if !colliders.is_empty() {
+                    shaped.insert((x as usize, y as usize), colliders.clone());
+                }
+            }
+            if let Some(obj_id) = layer_tile_id(objects, x as usize, y as usize) {
+                if let Some(kind) = MarkerKind::from_tile_type(&tile_type(tileset, obj_id)) {
+                    markers.push(Marker {
+                        kind,
+                        tile: (x as usize, y as usize),
+                        position: tile_position(x, y, map_size, tile_size),
+                    });
+                }
+            }
+        }
+    }
+    Ok(LevelLayout {
+        map_size,
+        tile_size,
+        fences,
+        shaped,
+        markers,
+    })
+}
+
+impl LevelLayout {
+    pub fn of_kind(&self, kind: MarkerKind) -> Vec<Marker> {
+        self.markers
+            .iter()
+            .filter(|marker| marker.kind == kind)
+            .cloned()
+            .collect()
+    }
+
+    pub fn waypoint_markers(&self) -> Vec<Marker> {
+        self.markers
+            .iter()
+            .filter(|marker| match marker.kind {
+                MarkerKind::Waypoint(_) => true,
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Waypoints as `(x, y, order)`, in the same order as `waypoint_markers`.
+    pub fn waypoints(&self) -> Vec<(f32, f32, usize)> {
+        self.waypoint_markers()
+            .iter()
+            .filter_map(|marker| match marker.kind {
+                MarkerKind::Waypoint(order) => Some((marker.position.0, marker.position.1, order)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether there's a way between two tiles that doesn't go through a fence, stepping
+    /// between tiles that share a side.
+    pub fn open_path(&self, from: (usize, usize), to: (usize, usize)) -> bool {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        seen.insert(from);
+        queue.push_back(from);
+        while let Some((x, y)) = queue.pop_front() {
+            if (x, y) == to {
+                return true;
+            }
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for tile in neighbours.iter() {
+                let on_map = tile.0 < self.map_size.0 as usize && tile.1 < self.map_size.1 as usize;
+                let open = *tile == to || !self.fences.contains(tile);
+                if on_map && open && seen.insert(*tile) {
+                    queue.push_back(*tile);
+                }
+            }
+        }
+        false
+    }
+}
+
+pub fn closest_waypoint(
+    tx: f32,
+    ty: f32,
+    waypoints: &Vec<(f32, f32, usize)>,
+    of_type: Option<usize>,
+) -> (usize, f32) {
+    let mut best = 0;
+    let mut best_distance = 999999999999.0;
+    for i in 0..waypoints.len() {
+        let (wx, wy, wt) = waypoints.get(i).unwrap();
+        let dx = tx - wx;
+        let dy = ty - wy;
+        let distance = dx * dx + dy * dy;
+        if let Some(waypoint_type) = of_type {
+            if *wt != waypoint_type - 1 {
+                continue;
+            }
+        }
+        if distance < best_distance {
+            best = i;
+            best_distance = distance;
+        }
+    }
+    (best, best_distance)
+}
+
+/// The waypoint a goblin walks to after `index`, if the chain continues.
+pub fn next_waypoint(waypoints: &Vec<(f32, f32, usize)>, index: usize) -> Option<usize> {
+    let (wx, wy, wt) = waypoints.get(index)?;
+    if *wt > 1 && waypoints.iter().any(|(_, _, order)| *order == wt - 1) {
+        Some(closest_waypoint(*wx, *wy, waypoints, Some(*wt)).0)
+    } else {
+        None
+    }
+}
+
+/// Indices of every waypoint visited starting from `index`, ending at a Waypoint1 or a gap.
+pub fn waypoint_chain(waypoints: &Vec<(f32, f32, usize)>, index: usize) -> Vec<usize> {
+    let mut chain = vec![index];
+    let mut current = index;
+    while let Some(next) = next_waypoint(waypoints, current) {
+        if chain.contains(&next) {
+            break;
+        }
+        chain.push(next);
+        current = next;
+    }
+    chain
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    fn error(message: String) -> Self {
+        Problem {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Problem {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+/// Checks the markers a level needs and that goblins can walk their waypoint chains.
+pub fn validate_layout(layout: &LevelLayout) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for (kind, name) in [(MarkerKind::Pylon, "Pylon"), (MarkerKind::Player, "Player")].iter() {
+        match layout.of_kind(*kind).len() {
+            0 => problems.push(Problem::error(format!("No {} marker", name))),
+            1 => {}
+            count => problems.push(Problem::warning(format!(
+                "{} {} markers, only the first is used",
+                count, name
+            ))),
+        }
+    }
+    let markers = layout.waypoint_markers();
+    let waypoints = layout.waypoints();
+    for (marker, (_, _, order)) in markers.iter().zip(waypoints.iter()) {
+        if *order > 1 && !waypoints.iter().any(|(_, _, other)| *other == order - 1) {
+            problems.push(Problem::error(format!(
+                "Waypoint{} at {:?} has no Waypoint{} to lead to",
+                order,
+                marker.tile,
+                order - 1
+            )));
+        }
+    }
+    for (index, marker) in markers.iter().enumerate() {
+        if let Some(next) = next_waypoint(&waypoints, index) {
+            if !layout.open_path(marker.tile, markers[next].tile) {
+                problems.push(Problem::warning(format!(
+                    "Waypoint at {:?} leads across a fence to {:?}",
+                    marker.tile, markers[next].tile
+                )));
+            }
+        }
+    }
+    let goblins = layout.of_kind(MarkerKind::Goblin);
+    if !goblins.is_empty() && waypoints.is_empty() {
+        problems.push(Problem::error(
+            "Goblin spawners but no waypoints".to_string(),
+        ));
+    }
+    for goblin in goblins.iter() {
+        if waypoints.is_empty() {
+            break;
+        }
+        let (closest, _) = closest_waypoint(goblin.position.0, goblin.position.1, &waypoints, None);
+        if !layout.open_path(goblin.tile, markers[closest].tile) {
+            problems.push(Problem::error(format!(
+                "Goblin spawner at {:?} reaches its first waypoint {:?} across a fence",
+                goblin.tile, markers[closest].tile
+            )));
+        }
+    }
+    if let Some(pylon) = layout.of_kind(MarkerKind::Pylon).first() {
+        for goblin in goblins.iter() {
+            if !layout.open_path(goblin.tile, pylon.tile) {
+                problems.push(Problem::warning(format!(
+                    "Goblins from the spawner at {:?} have to break a fence to reach the pylon",
+                    goblin.tile
+                )));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tiles 1 to 3 are mud, water and lava; tile 4 has properties but plays like ground.
+    fn terrain_tileset() -> tiled::Tileset {
+        let tsx = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/terrain.tsx"
+        ));
+        tiled::parse_tileset(tsx.as_bytes(), 1).expect("terrain.tsx should parse")
+    }
+
+    #[test]
+    fn tileset_terrain_reads_tile_properties() {
+        let terrain = tileset_terrain(&terrain_tileset());
+        assert_eq!(terrain.len(), 3);
+        assert_eq!(
+            terrain[&1],
+            Terrain {
+                speed_multiplier: 0.5,
+                ..Terrain::default()
+            }
+        );
+        assert_eq!(
+            terrain[&2],
+            Terrain {
+                impassable_for_enemies: true,
+                ..Terrain::default()
+            }
+        );
+        assert_eq!(
+            terrain[&3],
+            Terrain {
+                speed_multiplier: 0.75,
+                impassable_for_enemies: false,
+                damage_per_second: 4.0,
+            }
+        );
+        assert!(!terrain.contains_key(&4));
+    }
+
+    /// A layout from rows of `.` for open ground, `f` for fence and `G`/`P` for a goblin spawner
+    /// or the pylon.
+    fn layout(rows: &[&str]) -> LevelLayout {
+        let tile_size = (32, 32);
+        let map_size = (rows[0].len() as u32, rows.len() as u32);
+        let mut fences = HashSet::new();
+        let mut markers = Vec::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                let kind = match tile {
+                    'f' => {
+                        fences.insert((x, y));
+                        continue;
+                    }
+                    'G' => MarkerKind::Goblin,
+                    'P' => MarkerKind::Pylon,
+                    _ => continue,
+                };
+                markers.push(Marker {
+                    kind,
+                    tile: (x, y),
+                    position: tile_position(x as u32, y as u32, map_size, tile_size),
+                });
+            }
+        }
+        LevelLayout {
+            map_size,
+            tile_size,
+            fences,
+            shaped: HashMap::new(),
+            markers,
+        }
+    }
+
+    /// Checks that `rects` cover every tile in `fences` exactly once and nothing else.
+    fn assert_exact_cover(rects: &[TileRect], fences: &HashSet<(usize, usize)>) {
+        let mut covered = HashSet::new();
+        for rect in rects.iter() {
+            for y in rect.y..rect.y + rect.height {
+                for x in rect.x..rect.x + rect.width {
+                    assert!(
+                        fences.contains(&(x, y)),
+                        "{:?} covers open {:?}",
+                        rect,
+                        (x, y)
+                    );
+                    assert!(
+                        covered.insert((x, y)),
+                        "{:?} overlaps at {:?}",
+                        rect,
+                        (x, y)
+                    );
+                }
+            }
+        }
+        assert_eq!(&covered, fences);
+    }
+
+    #[test]
+    fn l_shaped_fence_takes_two_rects() {
+        let fences = layout(&["f..", "f..", "fff"]).fences;
+        let rects = fence_rects(&fences, &HashMap::new());
+        assert_exact_cover(&rects, &fences);
+        assert_eq!(rects.len(), 2);
+    }
+
+    #[test]
+    fn filled_block_is_one_rect() {
+        let fences = layout(&[".....", ".fff.", ".fff.", "....."]).fences;
+        let rects = fence_rects(&fences, &HashMap::new());
+        assert_exact_cover(&rects, &fences);
+        assert_eq!(
+            rects,
+            vec![TileRect {
+                x: 1,
+                y: 1,
+                width: 3,
+                height: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn isolated_fences_get_a_rect_each() {
+        let fences = layout(&["f.f", "...", ".f."]).fences;
+        let rects = fence_rects(&fences, &HashMap::new());
+        assert_exact_cover(&rects, &fences);
+        assert_eq!(rects.len(), 3);
+    }
+
+    #[test]
+    fn shaped_fences_are_left_out() {
+        let fences = layout(&["fff", "fff"]).fences;
+        let mut shaped = HashMap::new();
+        shaped.insert(
+            (1, 0),
+            vec![TileCollider {
+                position: na::Isometry2::identity(),
+                shape: ShapeHandle::new(Ball::new(4.0)),
+            }],
+        );
+        let rects = fence_rects(&fences, &shaped);
+        let boxed = fences
+            .iter()
+            .filter(|tile| !shaped.contains_key(tile))
+            .cloned()
+            .collect();
+        assert_exact_cover(&rects, &boxed);
+    }
+
+    #[test]
+    fn ellipses_keep_their_proportions() {
+        let tsx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset name="Shapes" tilewidth="32" tileheight="32" tilecount="2" columns="2">
+ <tile id="0">
+  <objectgroup draworder="index">
+   <object id="1" x="4" y="12" width="24" height="8"><ellipse/></object>
+  </objectgroup>
+ </tile>
+ <tile id="1">
+  <objectgroup draworder="index">
+   <object id="1" x="8" y="8" width="16" height="16"><ellipse/></object>
+  </objectgroup>
+ </tile>
+</tileset>"#;
+        let tileset = tiled::parse_tileset(tsx.as_bytes(), 1).expect("tileset should parse");
+        let extents = |tile_id: usize| {
+            let colliders = tile_colliders(&tileset, tile_id, TileFlip::default(), (32, 32));
+            assert_eq!(colliders.len(), 1);
+            let aabb = colliders[0].shape.aabb(&colliders[0].position);
+            (aabb.center().coords, aabb.half_extents())
+        };
+
+        let (center, half) = extents(0);
+        assert!(center.norm() < 1e-3);
+        assert!((half - na::Vector2::new(12.0, 4.0)).norm() < 1e-3);
+
+        let (center, half) = extents(1);
+        assert!(center.norm() < 1e-3);
+        assert!((half - na::Vector2::new(8.0, 8.0)).norm() < 1e-3);
+    }
+
+    #[test]
+    fn flips_mirror_about_the_tile_centre() {
+        let right = na::Vector2::new(1.0, 0.0);
+        let top_right = na::Vector2::new(1.0, 1.0);
+        let horizontal = TileFlip {
+            horizontal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(horizontal.matrix() * top_right, na::Vector2::new(-1.0, 1.0));
+        let diagonal = TileFlip {
+            diagonal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(diagonal.matrix() * top_right, na::Vector2::new(-1.0, -1.0));
+        // Tiled turns a tile a quarter clockwise by flipping it diagonally and horizontally.
+        let quarter_turn = TileFlip {
+            horizontal: true,
+            diagonal: true,
+            ..TileFlip::default()
+        };
+        assert_eq!(quarter_turn.matrix() * right, na::Vector2::new(0.0, -1.0));
+    }
+
+    #[test]
+    fn path_goes_round_a_fence() {
+        let layout = layout(&["G.f..", "..f..", "..f.P", "....."]);
+        assert!(layout.open_path((0, 0), (4, 2)));
+    }
+
+    #[test]
+    fn closed_fence_blocks_the_path() {
+        let layout = layout(&["G.f..", "..f..", "..f.P", "..f.."]);
+        assert!(!layout.open_path((0, 0), (4, 2)));
+    }
+
+    #[test]
+    fn diagonal_fences_leave_no_gap() {
+        let layout = layout(&["G.f", ".f.", "f.P"]);
+        assert!(!layout.open_path((0, 0), (2, 2)));
+    }
+
+    #[test]
+    fn walled_in_pylon_is_reported() {
+        let open = layout(&["G.f..", "..f..", "..f.P", "....."]);
+        let closed = layout(&["G.f..", "..f..", "..f.P", "..f.."]);
+        let fenced_in = |layout: &LevelLayout| {
+            validate_layout(layout)
+                .iter()
+                .any(|problem| problem.message.contains("reach the pylon"))
+        };
+        assert!(!fenced_in(&open));
+        assert!(fenced_in(&closed));
+    }
+}
@@ -0,0 +1,6 @@
+//! Asset and level loading shared by the game and the `validate-map` tool.
+extern crate nalgebra as na;
+
+pub mod assets;
+pub mod error;
+pub mod level;
@@ -2,17 +2,29 @@ extern crate nalgebra as na;
 extern crate nalgebra19 as na19;
 extern crate rand;
 extern crate tiled;
-mod assets;
+mod ai;
+mod building;
+mod camera;
 mod combat;
+mod controls;
 mod enemies;
+mod gamepad;
+mod mixer;
+mod music;
+mod perception;
 mod physics;
 mod player;
 mod prelude;
+#[cfg(feature = "dev")]
+mod reload;
+mod shop;
+mod spatial;
+mod squad;
 mod world;
 use amethyst::{
     animation::AnimationBundle,
     assets::*,
-    audio::{output::init_output, AudioBundle, SourceHandle, WavFormat},
+    audio::{SourceHandle, WavFormat},
     core::transform::*,
     ecs::*,
     input::{is_close_requested, is_key_down},
@@ -28,18 +40,23 @@ use amethyst::{
         ImageFormat, SpriteRender, SpriteSheet, SpriteSheetFormat,
     },
     tiles::{MortonEncoder, RenderTiles2D},
-    ui::{RenderUi, UiBundle, UiCreator, UiEventType, UiFinder},
-    utils::{
-        application_root_dir,
-        fps_counter::{FpsCounter, FpsCounterBundle},
-    },
+    ui::{RenderUi, UiBundle, UiCreator, UiEventType, UiFinder, UiText, UiTransform},
+    utils::{application_root_dir, fps_counter::FpsCounterBundle},
     winit::VirtualKeyCode,
+    LoggerConfig,
 };
 use amethyst_imgui::RenderImgui;
 use assets::*;
+use building::{BuildMode, BuildingBundle, Wallet};
+use camera::CameraBundle;
 use combat::CombatBundle;
+use controls::*;
 use enemies::*;
-use imgui::*;
+use error::GameError;
+use gamepad::GamepadBundle;
+use log::{debug, error, info, trace, LevelFilter};
+use mixer::MixerBundle;
+use music::MusicBundle;
 use na::{Isometry2, Point2, Point3, RealField, UnitQuaternion, Vector2, Vector3};
 use ncollide2d::shape::*;
 use nphysics2d::material::*;
@@ -47,17 +64,24 @@ use nphysics2d::object::*;
 use physics::*;
 use player::*;
 use prelude::*;
+use shop::{load_shop_config, ShopState};
 use std::f32::consts::PI;
+use topdown_physics::{assets, error, level};
 use world::*;
 
 #[derive(Default)]
 struct LoadingState {
     progress: Option<ProgressCounter>,
-    assets: Option<(SpriteStorage, PrefabStorage, SoundStorage, MapStorage)>,
+    last_finished: usize,
+    assets: Option<Assets>,
+    manifest: AssetManifest,
+    /// Assets that could not be loaded, by name, with the reason.
+    failures: Vec<String>,
 }
 
 struct GameplayState {
-    assets: (SpriteStorage, PrefabStorage, SoundStorage, MapStorage),
+    assets: Assets,
+    error: Option<GameError>,
 }
 impl SimpleState for GameplayState {
     fn on_start(&mut self, mut data: StateData<'_, GameData<'_, '_>>) {
@@ -66,43 +90,82 @@ impl SimpleState for GameplayState {
             idle_time: 0.0,
             wave_num: 0,
         });
-        data.world.insert(self.assets.0.clone());
-        data.world.insert(self.assets.1.clone());
-        data.world.insert(self.assets.2.clone());
-        data.world.insert(self.assets.3.clone());
-        initialize_tile_world(data.world);
+        data.world.insert(self.assets.clone());
+        data.world.insert(Wallet::default());
+        data.world.insert(BuildMode::default());
+        data.world.insert(PlayerUpgrades::default());
+        data.world.insert(Intermission::default());
+        data.world.insert(load_shop_config());
+        data.world
+            .insert(WaveData::load(get_resource("waves.ron")).unwrap_or_default());
+        #[cfg(feature = "dev")]
+        data.world.insert(reload::ReloadWatcher::default());
+        if let Err(err) = initialize_tile_world(data.world) {
+            error!(target: "world", "Could not start level: {}", err);
+            self.error = Some(err);
+            return;
+        }
         data.world.exec(|mut creator: UiCreator<'_>| {
             creator.create(get_resource("hud.ron"), ());
         });
     }
 
     fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        if let Some(err) = self.error.take() {
+            return SimpleTrans::Switch(Box::new(MenuState {
+                assets: self.assets.clone(),
+                menu: "error_menu.ron",
+                message: Some(format!("{}", err)),
+            }));
+        }
+        #[cfg(feature = "dev")]
+        reload::apply_hot_reloads(data.world);
         let (entities, names): (Entities<'_>, ReadStorage<'_, Named>) = data.world.system_data();
         if get_named_entity(&entities, &names, "player").is_none() {
             return SimpleTrans::Switch(Box::new(MenuState {
                 assets: self.assets.clone(),
                 menu: "game_over.ron",
+                message: None,
             }));
         }
         if get_named_entity(&entities, &names, "pylon").is_none() {
             return SimpleTrans::Switch(Box::new(MenuState {
                 assets: self.assets.clone(),
                 menu: "game_over.ron",
+                message: None,
             }));
         }
-        if data.world.read_resource::<WaveState>().wave_num == SPAWNS.len() {
+        if data.world.read_resource::<WaveState>().wave_num
+            == data.world.read_resource::<WaveData>().spawns.len()
+        {
             return SimpleTrans::Switch(Box::new(MenuState {
                 assets: self.assets.clone(),
                 menu: "game_over.ron",
+                message: None,
             }));
         }
+        let (waves, wave_data, intermission, goblins): (
+            Read<'_, WaveState>,
+            Read<'_, WaveData>,
+            Read<'_, Intermission>,
+            ReadStorage<'_, Goblin>,
+        ) = data.world.system_data();
+        if waves.wave_num > 0
+            && waves.idle_time > 0.0
+            && intermission.shopped_wave != waves.wave_num
+            && waves.wave_num < wave_data.spawns.len()
+            && (&goblins).join().next().is_none()
+        {
+            return SimpleTrans::Push(Box::new(ShopState::default()));
+        }
         SimpleTrans::None
     }
 }
 
 struct MenuState {
-    assets: (SpriteStorage, PrefabStorage, SoundStorage, MapStorage),
+    assets: Assets,
     menu: &'static str,
+    message: Option<String>,
 }
 impl SimpleState for MenuState {
     fn on_start(&mut self, mut data: StateData<'_, GameData<'_, '_>>) {
@@ -112,6 +175,13 @@ impl SimpleState for MenuState {
         });
     }
 
+    fn on_resume(&mut self, mut data: StateData<'_, GameData<'_, '_>>) {
+        data.world.delete_all();
+        data.world.exec(|mut creator: UiCreator<'_>| {
+            creator.create(get_resource(self.menu), ());
+        });
+    }
+
     fn handle_event(
         &mut self,
         data: StateData<'_, GameData<'_, '_>>,
@@ -131,9 +201,15 @@ impl SimpleState for MenuState {
                         if start == ui_event.target {
                             return Trans::Push(Box::new(GameplayState {
                                 assets: self.assets.clone(),
+                                error: None,
                             }));
                         }
                     }
+                    if let Some(controls) = finder.find("controls") {
+                        if controls == ui_event.target {
+                            return Trans::Push(Box::new(ControlsState::default()));
+                        }
+                    }
                     if let Some(exit) = finder.find("exit") {
                         if exit == ui_event.target {
                             return Trans::Quit;
@@ -145,86 +221,125 @@ impl SimpleState for MenuState {
             _ => Trans::None,
         }
     }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        if let Some(message) = &self.message {
+            let (transforms, mut texts): (ReadStorage<'_, UiTransform>, WriteStorage<'_, UiText>) =
+                data.world.system_data();
+            for (transform, text) in (&transforms, &mut texts).join() {
+                if transform.id.eq("error_message") {
+                    text.text = message.clone();
+                }
+            }
+        }
+        SimpleTrans::None
+    }
 }
 
+/// Width of `loading_bar_frame` in `loading.ron`, which the bar fills as assets finish.
+const LOADING_BAR_WIDTH: f32 = 600.0;
+
 impl SimpleState for LoadingState {
     fn on_start(&mut self, mut data: StateData<'_, GameData<'_, '_>>) {
         data.world.register::<PhysicsHandle>();
         data.world.insert(AssetStorage::<TiledMap>::default());
 
-        init_output(data.world);
-
         let mut progress_counter = ProgressCounter::new();
 
-        let tile_spritesheet =
-            load_spritesheet(data.world, get_resource("Tiles"), &mut progress_counter);
-        let player_prefab = load_prefab(
-            data.world,
-            get_resource("Player.ron"),
-            &mut progress_counter,
-        );
-        let crab_prefab = load_prefab(
-            data.world,
-            get_resource("Enemies1.ron"),
-            &mut progress_counter,
-        );
-        let goblin_hit = load_sound_file(
-            data.world,
-            get_resource("Goblin_Hit.wav"),
-            &mut progress_counter,
-        );
-        let player_hit = load_sound_file(
-            data.world,
-            get_resource("Player_Hit.wav"),
-            &mut progress_counter,
-        );
-        let pylon_hit = load_sound_file(
-            data.world,
-            get_resource("Pylon_Hit.wav"),
-            &mut progress_counter,
-        );
-        let sword_slash = load_sound_file(
-            data.world,
-            get_resource("Sword_Slash.wav"),
-            &mut progress_counter,
-        );
-        let main_theme = load_sound_file(
-            data.world,
-            get_resource("MainTheme.wav"),
-            &mut progress_counter,
-        );
-
-        let village_map = load_map(
+        self.manifest = match AssetManifest::load(get_resource("assets.ron")) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                error!(target: "assets", "Could not read assets.ron: {}", err);
+                self.failures.push(format!("assets.ron: {}", err));
+                AssetManifest::default()
+            }
+        };
+        self.assets = Some(Assets::load(
             data.world,
-            get_resource("Village.tmx"),
+            &self.manifest,
             &mut progress_counter,
-        );
-
-        self.progress = Some(progress_counter);
-        self.assets = Some((
-            SpriteStorage { tile_spritesheet },
-            PrefabStorage {
-                player: player_prefab,
-                crab: crab_prefab,
-            },
-            SoundStorage {
-                goblin_hit,
-                player_hit,
-                pylon_hit,
-                sword_slash,
-                main_theme,
-            },
-            MapStorage { village_map },
         ));
+        self.progress = Some(progress_counter);
+
+        data.world.exec(|mut creator: UiCreator<'_>| {
+            creator.create(get_resource("loading.ron"), ());
+        });
     }
 
     fn update(&mut self, data: &mut StateData<GameData>) -> SimpleTrans {
         if let Some(progress) = &self.progress {
-            println!("{:?}", progress);
+            if progress.num_finished() != self.last_finished {
+                self.last_finished = progress.num_finished();
+                debug!(
+                    target: "assets",
+                    "Loaded {} of {} assets",
+                    progress.num_finished(),
+                    progress.num_assets()
+                );
+            }
+            let fraction = if progress.num_assets() > 0 {
+                progress.num_finished() as f32 / progress.num_assets() as f32
+            } else {
+                1.0
+            };
+            let (mut transforms, mut texts): (
+                WriteStorage<'_, UiTransform>,
+                WriteStorage<'_, UiText>,
+            ) = data.world.system_data();
+            for (transform, text) in (&mut transforms, (&mut texts).maybe()).join() {
+                if transform.id.eq("loading_bar") {
+                    transform.width = LOADING_BAR_WIDTH * fraction;
+                }
+                if let Some(text) = text {
+                    if transform.id.eq("loading_status") {
+                        text.text = if progress.num_failed() > 0 {
+                            format!(
+                                "Loading {} of {} ({} failed)",
+                                progress.num_finished(),
+                                progress.num_assets(),
+                                progress.num_failed()
+                            )
+                        } else {
+                            format!(
+                                "Loading {} of {}",
+                                progress.num_finished(),
+                                progress.num_assets()
+                            )
+                        };
+                    }
+                }
+            }
+            // Failed assets never finish, so wait until everything else has before reporting.
+            let settled = progress.num_finished() + progress.num_failed();
+            if progress.num_failed() > 0 && settled >= progress.num_assets() {
+                for meta in progress.errors() {
+                    let name = self
+                        .manifest
+                        .name_of(&meta.asset_name)
+                        .unwrap_or(&meta.asset_name);
+                    error!(
+                        target: "assets",
+                        "Failed to load {} ({}): {}",
+                        name,
+                        meta.asset_name,
+                        meta.error
+                    );
+                    self.failures.push(format!("{}: {}", name, meta.error));
+                }
+            }
+            if !self.failures.is_empty() {
+                return SimpleTrans::Switch(Box::new(MenuState {
+                    assets: self.assets.clone().unwrap_or_default(),
+                    menu: "load_failed.ron",
+                    message: Some(self.failures.join("\n")),
+                }));
+            }
             if progress.is_complete() {
+                info!(target: "assets", "Finished loading assets");
                 return SimpleTrans::Switch(Box::new(MenuState {
                     assets: self.assets.clone().unwrap(),
                     menu: "main_menu.ron",
+                    message: None,
                 }));
             }
         }
@@ -232,69 +347,41 @@ impl SimpleState for LoadingState {
     }
 }
 
-struct ImguiDebugSystem {
-    listbox_item_current: i32,
-    box_current: i32,
-}
+const LOG_TARGETS: &'static [&'static str] = &[
+    "assets", "physics", "world", "waves", "ai", "input", "audio", "debug",
+];
 
-impl Default for ImguiDebugSystem {
-    fn default() -> Self {
-        ImguiDebugSystem {
-            listbox_item_current: 0,
-            box_current: 0,
-        }
-    }
-}
+#[cfg(feature = "dev")]
+const LOG_LEVELS: &'static [(&'static str, LevelFilter)] = &[
+    ("Error", LevelFilter::Error),
+    ("Warn", LevelFilter::Warn),
+    ("Info", LevelFilter::Info),
+    ("Debug", LevelFilter::Debug),
+    ("Trace", LevelFilter::Trace),
+];
+
+/// The dev debug window: the frame rate, and the log level to turn logging up or down while the
+/// game runs.
+#[cfg(feature = "dev")]
+struct ImguiDebugSystem;
+
+#[cfg(feature = "dev")]
 impl<'s> amethyst::ecs::System<'s> for ImguiDebugSystem {
-    type SystemData = (
-        Read<'s, FpsCounter>,
-        Option<Read<'s, SpriteStorage>>,
-        Write<'s, Physics<f32>>,
-        Entities<'s>,
-        ReadStorage<'s, PhysicsHandle>,
-        Read<'s, LazyUpdate>,
-    );
-    fn run(&mut self, (fps, sprites, mut physics, entities, handles, update): Self::SystemData) {
+    type SystemData = Read<'s, amethyst::utils::fps_counter::FpsCounter>;
+
+    fn run(&mut self, fps: Self::SystemData) {
+        use imgui::{im_str, ImString};
         amethyst_imgui::with(|ui: &imgui::Ui| {
-            let mut window = imgui::Window::new(im_str!("Test"));
-            window.build(ui, || {
-                ui.text(im_str!("This is a test!"));
+            imgui::Window::new(im_str!("Debug")).build(ui, || {
                 ui.text(im_str!("FPS: {}", fps.sampled_fps()));
                 ui.separator();
-                let mut items = Vec::new();
-                let mut item_handles = Vec::new();
-                let mut idx = 0;
-                for (entity, handle) in (&entities, &handles).join() {
-                    if let Some(body1) = physics.get_position(handle) {
-                        items.push(im_str!("Item: {}", idx,));
-                        item_handles.push(handle);
-                        idx = idx + 1;
-                    }
-                }
-                let mut base = Vec::with_capacity(items.len());
-                for item in items.iter() {
-                    base.push(item);
-                }
-                let items = base.as_slice();
-                if ui.list_box(
-                    im_str!("Test"),
-                    &mut self.listbox_item_current,
-                    items.as_ref(),
-                    15,
-                ) {
-                    if let Some(handle) = item_handles.get(self.listbox_item_current as usize) {
-                        physics.apply_impulse(handle, Vector2::new(0.0, 100.0));
-                    }
-                }
-                let mut drag = ui.drag_int(im_str!("Box"), &mut self.box_current);
-                if drag.build() {
-                    for (handle) in (&handles).join() {
-                        physics.set_rotation(
-                            handle,
-                            self.box_current as f32 / 360.0 as f32 * std::f32::consts::FRAC_PI_2,
-                        );
+                let mut level = log::max_level();
+                ui.text(im_str!("Log level"));
+                for (label, filter) in LOG_LEVELS.iter() {
+                    ui.same_line(0.0);
+                    if ui.radio_button(&ImString::new(*label), &mut level, *filter) {
+                        log::set_max_level(level);
                     }
-                    println!("{}", self.box_current);
                 }
             });
         });
@@ -302,9 +389,17 @@ impl<'s> amethyst::ecs::System<'s> for ImguiDebugSystem {
 }
 
 fn main() -> amethyst::Result<()> {
-    amethyst::start_logger(Default::default());
+    amethyst::start_logger(LoggerConfig {
+        module_levels: LOG_TARGETS
+            .iter()
+            .map(|target| (target.to_string(), LevelFilter::Trace))
+            .collect(),
+        ..Default::default()
+    });
+    // Our targets are enabled down to trace above; this is the knob the dev debug window turns.
+    log::set_max_level(LevelFilter::Info);
     let display_config_path = get_resource("display_config.ron");
-    let input_path = get_resource("input.ron");
+    let input_bindings = load_bindings(get_resource("input.ron"), get_resource(USER_INPUT_FILE))?;
 
     let game_data = GameDataBuilder::default()
         .with_system_desc(
@@ -312,7 +407,6 @@ fn main() -> amethyst::Result<()> {
             "scene_loader",
             &[],
         )
-        .with(DjSystem, "dj", &[])
         .with(Processor::<TiledMap>::new(), "tiled_map_processor", &[])
         .with_bundle(AnimationBundle::<AnimationId, SpriteRender>::new(
             "sprite_animation_control",
@@ -324,7 +418,7 @@ fn main() -> amethyst::Result<()> {
         )?
         .with_bundle(
             amethyst::input::InputBundle::<amethyst::input::StringBindings>::new()
-                .with_bindings_from_file(input_path)?,
+                .with_bindings(input_bindings),
         )?
         .with_bundle(
             RenderingBundle::<DefaultBackend>::new()
@@ -338,13 +432,24 @@ fn main() -> amethyst::Result<()> {
                 .with_plugin(RenderUi::default())
                 .with_plugin(RenderImgui::<amethyst::input::StringBindings>::default()),
         )?
-        .with_bundle(AudioBundle::default())?
+        .with_bundle(GamepadBundle)?
+        .with_bundle(MixerBundle)?
+        .with_bundle(MusicBundle)?
         .with_bundle(PhysicsBundle)?
         .with_bundle(PlayerBundle)?
         .with_bundle(EnemiesBundle)?
+        .with_bundle(PhysicsStepBundle)?
         .with_bundle(CombatBundle)?
+        .with_bundle(BuildingBundle)?
+        .with_bundle(CameraBundle)?
         .with_bundle(FpsCounterBundle)?
         .with_bundle(UiBundle::<amethyst::input::StringBindings>::new())?;
+    #[cfg(feature = "dev")]
+    let game_data = game_data.with_bundle(HotReloadBundle::default())?.with(
+        ImguiDebugSystem,
+        "imgui_debug",
+        &[],
+    );
 
     let mut game = Application::new("", LoadingState::default(), game_data)?;
     game.run();
@@ -369,35 +474,43 @@ impl IsoConvert for Isometry2<f32> {
 struct DebugDrawShapes;
 
 impl<'s> System<'s> for DebugDrawShapes {
-    type SystemData = (Write<'s, DebugLines>, Read<'s, Physics<f32>>);
+    type SystemData = (Write<'s, DebugLines>, Read<'s, Physics>);
 
     fn run(&mut self, (mut debugLines, physics): Self::SystemData) {
-        for (handle, collider) in physics.colliders.iter() {
-            if let Some(circle) = collider.shape().as_shape::<Ball<f32>>() {
-                debugLines.draw_circle(
-                    na19::geometry::Point3::<f32>::new(
-                        collider.position().pos3().x,
-                        collider.position().pos3().y,
-                        collider.position().pos3().z,
-                    ),
-                    circle.radius() as f32,
-                    16,
-                    Srgba::new(1.0, 1.0, 1.0, 1.0),
-                );
-            } else if let Some(cube) = collider.shape().as_shape::<Cuboid<f32>>() {
-                let pos = collider.position().pos2();
-                let ext = cube.half_extents();
-                debugLines.draw_rotated_rectangle(
-                    [pos.x - ext.x as f32, pos.y - ext.y as f32].into(),
-                    [pos.x + ext.x as f32, pos.y + ext.y as f32].into(),
-                    0.0,
-                    na19::UnitQuaternion::new(na19::Vector3::new(
-                        0.0,
+        for shape in physics.debug_shapes() {
+            match shape {
+                DebugShape::Circle(center, radius) => {
+                    debugLines.draw_circle(
+                        na19::geometry::Point3::<f32>::new(center.x, center.y, 0.0),
+                        radius,
+                        16,
+                        Srgba::new(1.0, 1.0, 1.0, 1.0),
+                    );
+                }
+                DebugShape::Rectangle(position, ext) => {
+                    let pos = position.pos2();
+                    debugLines.draw_rotated_rectangle(
+                        [pos.x - ext.x, pos.y - ext.y].into(),
+                        [pos.x + ext.x, pos.y + ext.y].into(),
                         0.0,
-                        collider.position().rotation.angle() as f32,
-                    )),
-                    Srgba::new(1.0, 1.0, 1.0, 1.0),
-                );
+                        na19::UnitQuaternion::new(na19::Vector3::new(
+                            0.0,
+                            0.0,
+                            position.rotation.angle(),
+                        )),
+                        Srgba::new(1.0, 1.0, 1.0, 1.0),
+                    );
+                }
+                DebugShape::Polygon(points) => {
+                    for (index, start) in points.iter().enumerate() {
+                        let end = points[(index + 1) % points.len()];
+                        debugLines.draw_line(
+                            na19::geometry::Point3::<f32>::new(start.x, start.y, 0.0),
+                            na19::geometry::Point3::<f32>::new(end.x, end.y, 0.0),
+                            Srgba::new(1.0, 1.0, 1.0, 1.0),
+                        );
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,248 @@
+use crate::assets::{get_resource, Assets};
+use crate::camera::ARENA_WIDTH;
+use amethyst::{
+    assets::{AssetStorage, Processor},
+    audio::Source,
+    config::Config,
+    core::{
+        bundle::SystemBundle,
+        shrev::{EventChannel, ReaderId},
+        transform::Transform,
+    },
+    ecs::*,
+    error::Error,
+    renderer::camera::Camera,
+};
+use log::{trace, warn};
+use na::Vector2;
+use rodio::{source::ChannelVolume, Decoder, Device, Sink, Source as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+
+/// A request to play a named sound from `sounds.ron`, optionally from a spot in the world.
+#[derive(Debug, Clone)]
+pub struct SoundEvent {
+    pub name: &'static str,
+    pub position: Option<Vector2<f32>>,
+}
+
+impl SoundEvent {
+    pub fn at(name: &'static str, position: Vector2<f32>) -> Self {
+        SoundEvent {
+            name,
+            position: Some(position),
+        }
+    }
+
+    /// A sound with no position, played centred at full volume.
+    pub fn global(name: &'static str) -> Self {
+        SoundEvent {
+            name,
+            position: None,
+        }
+    }
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+fn one_instance() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SoundDef {
+    /// Sound names from `assets.ron`; one is picked at random each time.
+    pub variations: Vec<String>,
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    /// Playback speed is randomised by up to this fraction either way.
+    #[serde(default)]
+    pub pitch_variation: f32,
+    /// How many copies may play at once; further events are dropped until one finishes.
+    #[serde(default = "one_instance")]
+    pub max_instances: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    /// Distance from the camera at which positional sounds become silent.
+    pub falloff: f32,
+    /// Horizontal distance from the camera at which a sound is entirely in one ear.
+    pub pan_width: f32,
+    pub sounds: HashMap<String, SoundDef>,
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        SoundConfig {
+            falloff: 400.0,
+            pan_width: ARENA_WIDTH,
+            sounds: HashMap::new(),
+        }
+    }
+}
+
+/// Volume for the left and right channel of a sound heard from `listener`.
+fn spatialize(
+    config: &SoundConfig,
+    volume: f32,
+    position: Option<Vector2<f32>>,
+    listener: Vector2<f32>,
+) -> (f32, f32) {
+    let offset = match position {
+        Some(position) => position - listener,
+        None => return (volume, volume),
+    };
+    let attenuation = f32::max(1.0 - offset.norm() / config.falloff, 0.0);
+    let pan = f32::max(-1.0, f32::min(1.0, offset.x / config.pan_width));
+    let volume = volume * attenuation;
+    (
+        volume * f32::min(1.0, 1.0 - pan),
+        volume * f32::min(1.0, 1.0 + pan),
+    )
+}
+
+/// The one output device that sound effects and music play through. amethyst's `Output` doesn't
+/// lend out its device, so `MixerBundle` stands in for `AudioBundle` rather than opening a second
+/// device beside it.
+#[derive(Default)]
+pub struct AudioDevice {
+    device: Option<Device>,
+}
+
+impl AudioDevice {
+    pub fn open() -> Self {
+        let device = rodio::default_output_device();
+        if device.is_none() {
+            warn!(target: "audio", "No audio output device, sound is disabled");
+        }
+        AudioDevice { device }
+    }
+
+    pub fn get(&self) -> Option<&Device> {
+        self.device.as_ref()
+    }
+}
+
+pub struct MixerSystem {
+    reader: ReaderId<SoundEvent>,
+    playing: HashMap<&'static str, Vec<Sink>>,
+    /// Sound bytes by name, copied out of the asset storage once and shared by every play.
+    bytes: HashMap<String, Arc<[u8]>>,
+}
+
+impl MixerSystem {
+    pub fn new(reader: ReaderId<SoundEvent>) -> Self {
+        MixerSystem {
+            reader,
+            playing: HashMap::new(),
+            bytes: HashMap::new(),
+        }
+    }
+}
+
+impl<'s> System<'s> for MixerSystem {
+    type SystemData = (
+        Read<'s, EventChannel<SoundEvent>>,
+        Read<'s, AudioDevice>,
+        Read<'s, SoundConfig>,
+        Option<Read<'s, Assets>>,
+        Read<'s, AssetStorage<Source>>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (events, device, config, assets, sources, cameras, transforms): Self::SystemData,
+    ) {
+        for sinks in self.playing.values_mut() {
+            sinks.retain(|sink| !sink.empty());
+        }
+        let listener = (&cameras, &transforms)
+            .join()
+            .next()
+            .map(|(_, transform)| {
+                let translation = transform.translation();
+                Vector2::new(translation.x, translation.y)
+            })
+            .unwrap_or_else(|| Vector2::new(0.0, 0.0));
+        for event in events.read(&mut self.reader) {
+            let (device, assets) = match (device.get(), assets.as_ref()) {
+                (Some(device), Some(assets)) => (device, assets),
+                _ => continue,
+            };
+            let def = match config.sounds.get(event.name) {
+                Some(def) => def,
+                None => {
+                    warn!(target: "audio", "No sound named {} in sounds.ron", event.name);
+                    continue;
+                }
+            };
+            let sinks = self.playing.entry(event.name).or_insert_with(Vec::new);
+            if sinks.len() >= def.max_instances || def.variations.is_empty() {
+                trace!(target: "audio", "Dropping {}, {} already playing", event.name, sinks.len());
+                continue;
+            }
+            let variation = &def.variations[rand::random::<usize>() % def.variations.len()];
+            let bytes = match self.bytes.get(variation) {
+                Some(bytes) => bytes.clone(),
+                None => match assets
+                    .sound(variation)
+                    .and_then(|handle| sources.get(&handle))
+                {
+                    Some(source) => {
+                        let bytes: Arc<[u8]> = Arc::from(AsRef::<[u8]>::as_ref(source));
+                        self.bytes.insert(variation.clone(), bytes.clone());
+                        bytes
+                    }
+                    None => continue,
+                },
+            };
+            let (left, right) = spatialize(&config, def.volume, event.position, listener);
+            if left <= 0.0 && right <= 0.0 {
+                continue;
+            }
+            let speed = 1.0 + def.pitch_variation * (rand::random::<f32>() * 2.0 - 1.0);
+            match Decoder::new(Cursor::new(bytes)) {
+                Ok(decoder) => {
+                    let sink = Sink::new(device);
+                    sink.append(ChannelVolume::new(decoder.speed(speed), vec![left, right]));
+                    sinks.push(sink);
+                }
+                Err(err) => warn!(target: "audio", "Could not decode {}: {}", variation, err),
+            }
+        }
+    }
+}
+
+pub struct MixerBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for MixerBundle {
+    fn build(
+        self,
+        world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        let config = SoundConfig::load(get_resource("sounds.ron")).unwrap_or_else(|err| {
+            warn!(target: "audio", "Could not read sounds.ron: {}", err);
+            SoundConfig::default()
+        });
+        world.insert(config);
+        world.insert(AudioDevice::open());
+        world.insert(EventChannel::<SoundEvent>::new());
+        let reader = world
+            .fetch_mut::<EventChannel<SoundEvent>>()
+            .register_reader();
+        // What `AudioBundle` would otherwise do for us: turn loaded wav files into sources.
+        dispatcher.add(Processor::<Source>::new(), "source_processor", &[]);
+        // Sinks are kept on the main thread, next to the device they play on.
+        dispatcher.add_thread_local(MixerSystem::new(reader));
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use crate::assets::{get_resource, Assets};
+use crate::combat::{Health, MAX_PYLON_HEALTH};
+use crate::enemies::{Goblin, Intermission, WaveState};
+use crate::mixer::AudioDevice;
+use crate::player::Pylon;
+use amethyst::{
+    assets::AssetStorage,
+    audio::Source,
+    config::Config,
+    core::{bundle::SystemBundle, timing::Time},
+    ecs::*,
+    error::Error,
+};
+use log::{debug, warn};
+use rodio::{Decoder, Device, Sink, Source as _};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MusicTrack {
+    /// Sound name from `assets.ron`.
+    pub sound: String,
+    pub volume: f32,
+}
+
+/// A layer mixed in over the combat track once the pylon's health falls to `below_health`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MusicStem {
+    pub sound: String,
+    pub volume: f32,
+    /// Fraction of the pylon's maximum health.
+    pub below_health: f32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MusicConfig {
+    pub calm: Option<MusicTrack>,
+    pub combat: Option<MusicTrack>,
+    pub stems: Vec<MusicStem>,
+    /// Goblins alive before the music switches to combat.
+    pub combat_goblins: usize,
+    /// Seconds of `WaveState::idle_time` after a wave before the music calms down again.
+    pub calm_after: f32,
+    /// Seconds of `WaveState::idle_time` after which the combat track fades in ahead of the next wave.
+    pub combat_lead_in: f32,
+    /// Seconds to fade a track fully in or out.
+    pub to_combat_fade: f32,
+    pub to_calm_fade: f32,
+    pub stem_fade: f32,
+}
+
+impl Default for MusicConfig {
+    fn default() -> Self {
+        MusicConfig {
+            calm: None,
+            combat: None,
+            stems: Vec::new(),
+            combat_goblins: 1,
+            calm_after: 2.0,
+            combat_lead_in: 4.0,
+            to_combat_fade: 1.5,
+            to_calm_fade: 4.0,
+            stem_fade: 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mood {
+    Calm,
+    Combat,
+}
+
+/// Whether the current wave state calls for combat music.
+pub fn mood(config: &MusicConfig, waves: &WaveState, goblin_count: usize) -> Mood {
+    if goblin_count >= config.combat_goblins {
+        Mood::Combat
+    } else if waves.wave_num > 0 && waves.idle_time < config.calm_after {
+        Mood::Combat
+    } else if waves.idle_time >= config.combat_lead_in {
+        Mood::Combat
+    } else {
+        Mood::Calm
+    }
+}
+
+/// A looping track whose volume glides towards a target.
+struct Layer {
+    sink: Sink,
+    volume: f32,
+    target: f32,
+}
+
+impl Layer {
+    fn fade(&mut self, full_volume: f32, seconds: f32, delta: f32) {
+        let step = if seconds > 0.0 {
+            full_volume * delta / seconds
+        } else {
+            full_volume
+        };
+        if self.volume < self.target {
+            self.volume = f32::min(self.volume + step, self.target);
+        } else {
+            self.volume = f32::max(self.volume - step, self.target);
+        }
+        self.sink.set_volume(self.volume);
+    }
+}
+
+fn start_layer(
+    device: &Device,
+    assets: &Assets,
+    sources: &AssetStorage<Source>,
+    sound: &str,
+) -> Option<Layer> {
+    let source = assets
+        .sound(sound)
+        .and_then(|handle| sources.get(&handle))?;
+    let decoder = match Decoder::new(Cursor::new(source.clone())) {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            warn!(target: "audio", "Could not decode music {}: {}", sound, err);
+            return None;
+        }
+    };
+    let sink = Sink::new(device);
+    sink.set_volume(0.0);
+    sink.append(decoder.buffered().repeat_infinite());
+    Some(Layer {
+        sink,
+        volume: 0.0,
+        target: 0.0,
+    })
+}
+
+/// Crossfades between the calm and combat tracks and layers in stems as the pylon weakens.
+pub struct MusicDirectorSystem {
+    started: bool,
+    calm: Option<Layer>,
+    combat: Option<Layer>,
+    stems: Vec<Option<Layer>>,
+    mood: Option<Mood>,
+}
+
+impl MusicDirectorSystem {
+    pub fn new() -> Self {
+        MusicDirectorSystem {
+            started: false,
+            calm: None,
+            combat: None,
+            stems: Vec::new(),
+            mood: None,
+        }
+    }
+
+    /// Starts every layer together, silent, once all of them have loaded, so the stems stay in
+    /// time with the combat track.
+    fn start(
+        &mut self,
+        device: &Device,
+        config: &MusicConfig,
+        assets: &Assets,
+        sources: &AssetStorage<Source>,
+    ) {
+        let loaded = |sound: &str| {
+            assets
+                .sound(sound)
+                .map_or(true, |handle| sources.get(&handle).is_some())
+        };
+        let sounds = config
+            .calm
+            .iter()
+            .chain(config.combat.iter())
+            .map(|track| &track.sound)
+            .chain(config.stems.iter().map(|stem| &stem.sound));
+        if !sounds.all(|sound| loaded(sound.as_str())) {
+            return;
+        }
+        self.calm = config
+            .calm
+            .as_ref()
+            .and_then(|track| start_layer(device, assets, sources, &track.sound));
+        self.combat = config
+            .combat
+            .as_ref()
+            .and_then(|track| start_layer(device, assets, sources, &track.sound));
+        self.stems = config
+            .stems
+            .iter()
+            .map(|stem| start_layer(device, assets, sources, &stem.sound))
+            .collect();
+        self.started = true;
+    }
+}
+
+impl<'s> System<'s> for MusicDirectorSystem {
+    type SystemData = (
+        Read<'s, Time>,
+        Read<'s, AudioDevice>,
+        Read<'s, MusicConfig>,
+        Option<Read<'s, Assets>>,
+        Read<'s, AssetStorage<Source>>,
+        Option<Read<'s, WaveState>>,
+        Option<Read<'s, Intermission>>,
+        ReadStorage<'s, Goblin>,
+        ReadStorage<'s, Pylon>,
+        ReadStorage<'s, Health>,
+    );
+
+    fn run(
+        &mut self,
+        (time, device, config, assets, sources, waves, intermission, goblins, pylons, healths): Self::SystemData,
+    ) {
+        if !self.started {
+            if let (Some(device), Some(assets)) = (device.get(), assets.as_ref()) {
+                self.start(device, &config, assets, &sources);
+            }
+            return;
+        }
+        let goblin_count = (&goblins).join().count();
+        let shopping = intermission.map_or(false, |intermission| intermission.shopping);
+        let mood = match waves.as_ref() {
+            Some(waves) if !shopping => mood(&config, waves, goblin_count),
+            _ => Mood::Calm,
+        };
+        if self.mood != Some(mood) {
+            debug!(target: "audio", "Music mood is now {:?}", mood);
+            self.mood = Some(mood);
+        }
+        let pylon_health = (&pylons, &healths)
+            .join()
+            .next()
+            .map(|(_, health)| health.current_health as f32 / MAX_PYLON_HEALTH as f32);
+
+        let delta = time.delta_seconds();
+        let fade = match mood {
+            Mood::Combat => config.to_combat_fade,
+            Mood::Calm => config.to_calm_fade,
+        };
+        if let (Some(layer), Some(track)) = (self.calm.as_mut(), config.calm.as_ref()) {
+            layer.target = if mood == Mood::Calm {
+                track.volume
+            } else {
+                0.0
+            };
+            layer.fade(track.volume, fade, delta);
+        }
+        if let (Some(layer), Some(track)) = (self.combat.as_mut(), config.combat.as_ref()) {
+            layer.target = if mood == Mood::Combat {
+                track.volume
+            } else {
+                0.0
+            };
+            layer.fade(track.volume, fade, delta);
+        }
+        for (layer, stem) in self.stems.iter_mut().zip(config.stems.iter()) {
+            if let Some(layer) = layer.as_mut() {
+                let threatened = pylon_health.map_or(false, |health| health <= stem.below_health);
+                layer.target = if mood == Mood::Combat && threatened {
+                    stem.volume
+                } else {
+                    0.0
+                };
+                layer.fade(stem.volume, config.stem_fade, delta);
+            }
+        }
+    }
+}
+
+pub struct MusicBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for MusicBundle {
+    fn build(
+        self,
+        world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        let config = MusicConfig::load(get_resource("music.ron")).unwrap_or_else(|err| {
+            warn!(target: "audio", "Could not read music.ron: {}", err);
+            MusicConfig::default()
+        });
+        world.insert(config);
+        dispatcher.add_thread_local(MusicDirectorSystem::new());
+        Ok(())
+    }
+}
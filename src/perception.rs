@@ -0,0 +1,195 @@
+use crate::building::Structure;
+use crate::enemies::Goblin;
+use crate::mixer::SoundEvent;
+use crate::physics::Physics;
+use crate::prelude::*;
+use crate::world::MapPart;
+use amethyst::core::shrev::{EventChannel, ReaderId};
+use log::debug;
+use std::f32::consts::FRAC_PI_3;
+
+/// Sounds that make a goblin come and look.
+const HEARD_SOUNDS: &'static [&'static str] = &["sword_slash"];
+
+/// A spot worth searching, and how much longer to bother.
+#[derive(Debug, Clone, Copy)]
+pub struct Memory {
+    pub position: Vector2<f32>,
+    pub time_left: f32,
+}
+
+#[derive(Component, Debug, Clone)]
+#[storage(VecStorage)]
+pub struct Perception {
+    pub sight_range: f32,
+    /// Half the width of the vision cone around the facing direction, in radians.
+    pub half_fov: f32,
+    pub hearing_range: f32,
+    /// Seconds spent searching where a target was last seen or heard.
+    pub search_time: f32,
+    pub memory: Option<Memory>,
+}
+
+impl Default for Perception {
+    fn default() -> Self {
+        Perception {
+            sight_range: 120.0,
+            half_fov: FRAC_PI_3,
+            hearing_range: 160.0,
+            search_time: 6.0,
+            memory: None,
+        }
+    }
+}
+
+impl Perception {
+    pub fn remember(&mut self, position: Vector2<f32>) {
+        self.memory = Some(Memory {
+            position,
+            time_left: self.search_time,
+        });
+    }
+
+    /// Whether something at `offset` and `distance` is inside the vision cone.
+    pub fn notices(&self, facing: Direction, offset: Vector2<f32>, distance: f32) -> bool {
+        distance <= self.sight_range && facing.tilts().angle(&offset) <= self.half_fov
+    }
+}
+
+/// The offset to `target` and the distance to its edge, unless fences or structures are in
+/// the way. Other actors don't block the view.
+pub fn line_of_sight(
+    physics: &Physics,
+    from: &PhysicsHandle,
+    to: &PhysicsHandle,
+    target: Entity,
+    map_parts: &ReadStorage<'_, MapPart>,
+    structures: &ReadStorage<'_, Structure>,
+) -> Option<(Vector2<f32>, f32)> {
+    let offset = physics.get_between(from, to)?;
+    if offset.norm() == 0.0 {
+        return Some((offset, 0.0));
+    }
+    let hits = physics.ray_cast(from, offset.normalize());
+    let distance = hits
+        .iter()
+        .filter(|(seen, _)| *seen == target)
+        .map(|(_, distance)| *distance)
+        .fold(None, |nearest: Option<f32>, distance| {
+            Some(nearest.map_or(distance, |nearest| f32::min(nearest, distance)))
+        })?;
+    let blocked = hits.iter().any(|(seen, hit)| {
+        *hit < distance && (map_parts.contains(*seen) || structures.contains(*seen))
+    });
+    if blocked {
+        None
+    } else {
+        Some((offset, distance))
+    }
+}
+
+/// Sends goblins without a target to look where they heard a sword.
+pub struct HearingSystem {
+    reader: ReaderId<SoundEvent>,
+}
+
+impl HearingSystem {
+    pub fn new(reader: ReaderId<SoundEvent>) -> Self {
+        HearingSystem { reader }
+    }
+}
+
+impl<'s> System<'s> for HearingSystem {
+    type SystemData = (
+        Read<'s, EventChannel<SoundEvent>>,
+        Read<'s, Physics>,
+        ReadStorage<'s, Goblin>,
+        ReadStorage<'s, PhysicsHandle>,
+        WriteStorage<'s, Perception>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (sounds, physics, goblins, handles, mut perceptions, entities): Self::SystemData,
+    ) {
+        for sound in sounds.read(&mut self.reader) {
+            let position = match sound.position {
+                Some(position) if HEARD_SOUNDS.contains(&sound.name) => position,
+                _ => continue,
+            };
+            for (entity, goblin, handle, perception) in
+                (&entities, &goblins, &handles, &mut perceptions).join()
+            {
+                if goblin.blackboard.target.is_some() {
+                    continue;
+                }
+                let heard = physics.get_location(handle).map_or(false, |location| {
+                    (position - location.coords).norm() <= perception.hearing_range
+                });
+                if heard {
+                    debug!(target: "ai", "Goblin {:?} heard {} at {:?}", entity, sound.name, position);
+                    perception.remember(position);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{GridBackend, PhysicsDesc};
+    use ncollide2d::shape::{Ball, Cuboid, ShapeHandle};
+    use nphysics2d::object::{BodyStatus, ColliderDesc, RigidBodyDesc};
+
+    fn spawn(
+        physics: &mut Physics,
+        world: &mut World,
+        collider: ColliderDesc<f32>,
+        x: f32,
+    ) -> (Entity, PhysicsHandle) {
+        let entity = world.create_entity().build();
+        let desc = PhysicsDesc::new(RigidBodyDesc::new().status(BodyStatus::Static), collider);
+        (entity, physics.spawn(entity, &desc, x, 0.0))
+    }
+
+    /// Whether a goblin at the origin sees a target 100px to its right past whatever
+    /// `between` spawns halfway, tagged as part of the map.
+    fn sees_past(between: Option<ColliderDesc<f32>>) -> bool {
+        let mut world = World::new();
+        world.register::<MapPart>();
+        world.register::<Structure>();
+        let mut physics = Physics::with_backend(Box::new(GridBackend::default()));
+        let ball = || ColliderDesc::new(ShapeHandle::new(Ball::new(8.0)));
+        let (_, from) = spawn(&mut physics, &mut world, ball(), 0.0);
+        let (target, to) = spawn(&mut physics, &mut world, ball(), 100.0);
+        if let Some(collider) = between {
+            let (part, _) = spawn(&mut physics, &mut world, collider, 50.0);
+            world
+                .write_storage::<MapPart>()
+                .insert(part, MapPart)
+                .unwrap();
+        }
+        let (map_parts, structures) =
+            world.system_data::<(ReadStorage<'_, MapPart>, ReadStorage<'_, Structure>)>();
+        line_of_sight(&physics, &from, &to, target, &map_parts, &structures).is_some()
+    }
+
+    #[test]
+    fn sees_an_open_target() {
+        assert!(sees_past(None));
+    }
+
+    #[test]
+    fn fence_blocks_the_view() {
+        let fence = ColliderDesc::new(ShapeHandle::new(Cuboid::new(Vector2::new(16.0, 16.0))));
+        assert!(!sees_past(Some(fence)));
+    }
+
+    #[test]
+    fn waypoint_sensor_does_not_block_the_view() {
+        let waypoint = ColliderDesc::new(ShapeHandle::new(Ball::new(4.0))).sensor(true);
+        assert!(sees_past(Some(waypoint)));
+    }
+}
@@ -1,36 +1,85 @@
-use crate::SoundStorage;
+use crate::error::GameError;
+use crate::spatial::{cell_of, SpatialIndexSystem, CELL_SIZE};
+use crate::world::TerrainSystem;
 use amethyst::core::bundle::SystemBundle;
+use amethyst::core::transform::{components::Parent, Transform};
+use amethyst::ecs::world::EntitiesRes;
 use amethyst::ecs::*;
 use amethyst::error::Error;
-use amethyst::{
-    assets::AssetStorage,
-    audio::{output::Output, Source, SourceHandle},
-    core::transform::{components::Parent, Transform},
-};
-use na19;
-use nalgebra::geometry::{Isometry2, Point2, Point3, UnitQuaternion};
-use nalgebra::{RealField, Vector2};
-use ncollide2d::pipeline::narrow_phase::ContactEvent;
-use ncollide2d::pipeline::object::CollisionGroups;
-use ncollide2d::query::{Proximity, Ray};
-use nphysics2d::force_generator::DefaultForceGeneratorSet;
-use nphysics2d::joint::DefaultJointConstraintSet;
-use nphysics2d::math::{Force, ForceType};
+use log::{trace, warn};
+use nalgebra::geometry::{Isometry2, Point2, Rotation2};
+use nalgebra::Vector2;
 use nphysics2d::object::*;
-use nphysics2d::world::{
-    DefaultGeometricalWorld, DefaultMechanicalWorld, GeometricalWorld, MechanicalWorld,
-};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_4;
+
+#[cfg(test)]
+mod grid;
+mod nphysics;
+
+#[cfg(test)]
+pub use self::grid::GridBackend;
+pub use self::nphysics::NphysicsBackend;
+
+/// How far `Physics::ray_cast` looks, in pixels.
+const RAY_LENGTH: f32 = 500.0;
+/// How many times a character slides along walls in one step.
+const MAX_SLIDES: usize = 3;
+/// Gap kept between a character and the wall it slid along.
+const SKIN: f32 = 0.1;
+
+/// Moves a body by its velocity outside the solver, sliding along walls and turning around
+/// corners instead of jittering against them. Characters don't collide with each other;
+/// overlaps are pushed apart, and only the one with the lower `push_priority` gives way.
+#[derive(Debug, Clone, Copy)]
+pub struct CharacterController {
+    pub push_priority: u8,
+    /// How far off its heading, in radians, a blocked character tries to walk around.
+    pub step_around: f32,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        CharacterController {
+            push_priority: 0,
+            step_around: FRAC_PI_4,
+        }
+    }
+}
 
 #[derive(Component)]
 #[storage(VecStorage)]
 pub struct PhysicsDesc {
     body: RigidBodyDesc<f32>,
     collider: ColliderDesc<f32>,
+    /// More colliders on the same body, which the handle doesn't point at.
+    extra_colliders: Vec<ColliderDesc<f32>>,
+    character: Option<CharacterController>,
 }
 
 impl PhysicsDesc {
     pub fn new(body: RigidBodyDesc<f32>, collider: ColliderDesc<f32>) -> Self {
-        PhysicsDesc { body, collider }
+        PhysicsDesc {
+            body,
+            collider,
+            extra_colliders: Vec::new(),
+            character: None,
+        }
+    }
+
+    pub fn with_collider(mut self, collider: ColliderDesc<f32>) -> Self {
+        self.extra_colliders.push(collider);
+        self
+    }
+
+    /// Makes the body kinematic and moves it with `controller`.
+    pub fn character(self, controller: CharacterController) -> Self {
+        PhysicsDesc {
+            body: self.body.status(BodyStatus::Kinematic),
+            character: Some(controller),
+            ..self
+        }
     }
 }
 
@@ -38,8 +87,7 @@ impl PhysicsDesc {
 #[storage(VecStorage)]
 pub struct AttachedSensor {
     collider: ColliderDesc<f32>,
-    pub handle: Option<(DefaultBodyHandle, DefaultColliderHandle)>,
-    fresh: bool,
+    pub handle: Option<PhysicsHandle>,
 }
 
 impl AttachedSensor {
@@ -47,306 +95,453 @@ impl AttachedSensor {
         AttachedSensor {
             collider,
             handle: None,
-            fresh: false,
         }
     }
-    pub fn set_handle(&mut self, handle: (DefaultBodyHandle, DefaultColliderHandle)) {
-        self.handle = Some(handle);
-        self.fresh = true;
-    }
     pub fn get_handle(&self) -> PhysicsHandle {
-        if let Some(handle) = self.handle {
-            PhysicsHandle {
-                body: Some(handle.0),
-                collider: Some(handle.1),
-                fresh: false,
-            }
-        } else {
-            PhysicsHandle {
-                body: None,
-                collider: None,
-                fresh: true,
-            }
-        }
+        self.handle.clone().unwrap_or(PhysicsHandle {
+            body: None,
+            collider: None,
+        })
     }
 }
 
+/// A body as the backend that spawned it knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BodyId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ColliderId(usize);
+
 #[derive(Component, Debug, Clone)]
 #[storage(VecStorage)]
 pub struct PhysicsHandle {
-    body: Option<DefaultBodyHandle>,
-    collider: Option<DefaultColliderHandle>,
-    fresh: bool,
+    body: Option<BodyId>,
+    collider: Option<ColliderId>,
 }
 
 impl PhysicsHandle {
-    pub fn new(b: DefaultBodyHandle, c: DefaultColliderHandle) -> Self {
+    pub fn new(body: BodyId, collider: ColliderId) -> Self {
         Self {
-            body: Some(b),
-            collider: Some(c),
-            fresh: true,
+            body: Some(body),
+            collider: Some(collider),
         }
     }
 }
 
-pub struct Physics<N: RealField> {
-    pub geo_world: DefaultGeometricalWorld<N>,
-    pub mech_world: DefaultMechanicalWorld<N>,
-    pub bodies: DefaultBodySet<N>,
-    pub colliders: DefaultColliderSet<N>,
-    pub joint_constraints: DefaultJointConstraintSet<N>,
-    pub force_generators: DefaultForceGeneratorSet<N>,
+/// A velocity a system wants for its body, applied by `MovementSystem` before the next step.
+/// Leaving it empty keeps whatever velocity the body already has, like a knockback.
+#[derive(Component, Debug, Clone, Default)]
+#[storage(VecStorage)]
+pub struct MovementIntent {
+    pub velocity: Option<Vector2<f32>>,
 }
 
-impl<N: RealField> Physics<N> {
-    pub fn new() -> Self {
-        Self::default()
-    }
+/// Where a moving collider would first touch a wall.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeHit {
+    pub entity: Entity,
+    /// How much of the motion is covered before touching, from 0 to 1.
+    pub toi: f32,
+    /// Points out of the wall.
+    pub normal: Vector2<f32>,
+}
 
-    pub fn step(&mut self) {
-        self.mech_world.step(
-            &mut self.geo_world,
-            &mut self.bodies,
-            &mut self.colliders,
-            &mut self.joint_constraints,
-            &mut self.force_generators,
-        )
-    }
+/// A collider outline for the debug overlay, already in world space.
+#[derive(Debug, Clone)]
+pub enum DebugShape {
+    Circle(Point2<f32>, f32),
+    Rectangle(Isometry2<f32>, Vector2<f32>),
+    /// The corners of a convex polygon, in order.
+    Polygon(Vec<Point2<f32>>),
+}
 
-    pub fn spawn(
-        &mut self,
-        body_desc: &RigidBodyDesc<N>,
-        collider_desc: &ColliderDesc<N>,
-    ) -> (DefaultBodyHandle, DefaultColliderHandle) {
-        let handle = self.bodies.insert(body_desc.build());
-        let collider_handle = self
-            .colliders
-            .insert(collider_desc.build(BodyPartHandle(handle, 0)));
-        (handle, collider_handle)
-    }
+/// The parts of a physics engine the game uses. Bodies and colliders are tagged with the
+/// entity they were spawned for, so queries and events answer in entities.
+pub trait PhysicsBackend: Send + Sync {
+    fn step(&mut self);
+    /// Seconds simulated by one `step`.
+    fn timestep(&self) -> f32;
 
-    pub fn add_child_collider(
+    /// Adds the body and collider described by `desc`, placed at `location`.
+    fn spawn(&mut self, entity: Entity, desc: &PhysicsDesc, location: Point2<f32>)
+        -> PhysicsHandle;
+
+    /// Adds a collider that moves with the body of `parent`.
+    fn attach(
         &mut self,
-        parent_handle: &PhysicsHandle,
-        collider_desc: &ColliderDesc<N>,
-    ) -> DefaultColliderHandle {
-        if let Some(handle) = parent_handle.body {
-            let collider_handle = self
-                .colliders
-                .insert(collider_desc.build(BodyPartHandle(handle, 0)));
-            collider_handle
-        } else {
-            panic!("No parent!");
-        }
-    }
+        entity: Entity,
+        parent: &PhysicsHandle,
+        collider: &ColliderDesc<f32>,
+    ) -> Result<PhysicsHandle, GameError>;
+
+    /// Drops every body and collider whose entity was deleted.
+    fn remove_dead(&mut self, entities: &EntitiesRes);
+
+    fn position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>>;
+    fn set_position(&mut self, handle: &PhysicsHandle, position: Isometry2<f32>);
+    /// Where the collider itself is, which for attached sensors is off the body's center.
+    fn collider_position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>>;
+
+    fn velocity(&self, handle: &PhysicsHandle) -> Option<Vector2<f32>>;
+    fn set_velocity(&mut self, handle: &PhysicsHandle, velocity: Vector2<f32>);
+    fn set_damping(&mut self, handle: &PhysicsHandle, damping: f32);
+    fn apply_impulse(&mut self, handle: &PhysicsHandle, impulse: Vector2<f32>);
+    fn apply_velocity_change(
+        &mut self,
+        handle: &PhysicsHandle,
+        change: Vector2<f32>,
+    ) -> Result<(), GameError>;
+
+    fn is_intersecting(&self, first: &PhysicsHandle, second: &PhysicsHandle) -> bool;
+    /// Entities whose colliders overlap the collider of `handle`.
+    fn intersections(&self, handle: &PhysicsHandle) -> Vec<Entity>;
+    /// Every solid collider the ray crosses within `max_toi`, with the distance in multiples of
+    /// `direction`. Sensors, like waypoints and attack hitboxes, don't stop rays.
+    fn ray_cast(
+        &self,
+        origin: Point2<f32>,
+        direction: Vector2<f32>,
+        max_toi: f32,
+    ) -> Vec<(Entity, f32)>;
+    /// The first solid collider the collider of `handle` hits when its body moves by `motion`
+    /// from `from`. Sensors and characters are ignored, and so is anything it is moving away from.
+    fn cast_shape(
+        &self,
+        handle: &PhysicsHandle,
+        from: &Isometry2<f32>,
+        motion: Vector2<f32>,
+    ) -> Option<ShapeHit>;
+    /// Pairs of solid colliders that started touching during the last step.
+    fn contacts_started(&self) -> Vec<(Entity, Entity)>;
+
+    fn debug_shapes(&self) -> Vec<DebugShape>;
+}
 
-    pub fn get_position(&self, handle: &PhysicsHandle) -> Option<Isometry2<N>> {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body(handle) {
-                Some(rigid_body.position().clone())
-            } else {
-                None
+struct Character {
+    handle: PhysicsHandle,
+    controller: CharacterController,
+    radius: f32,
+    speed_multiplier: f32,
+}
+
+/// Where a character moving by `motion` from `from` ends up after sliding along what it hits.
+fn slide(
+    backend: &dyn PhysicsBackend,
+    character: &Character,
+    from: &Isometry2<f32>,
+    motion: Vector2<f32>,
+) -> Isometry2<f32> {
+    let mut position = *from;
+    let mut remaining = motion;
+    for _ in 0..MAX_SLIDES {
+        if remaining.norm() <= std::f32::EPSILON {
+            break;
+        }
+        match backend.cast_shape(&character.handle, &position, remaining) {
+            Some(hit) => {
+                let travel = remaining * hit.toi;
+                position.translation.vector += travel + hit.normal * SKIN;
+                let left = remaining - travel;
+                remaining = left - hit.normal * left.dot(&hit.normal);
+            }
+            None => {
+                position.translation.vector += remaining;
+                break;
             }
-        } else {
-            None
         }
     }
+    position
+}
 
-    pub fn get_location(&self, handle: &PhysicsHandle) -> Option<Point2<N>> {
-        self.get_position(handle)
-            .map(|location| location.translation.vector)
-            .map(|vector| Point2::new(vector.x, vector.y))
+/// Turns a character stopped on a corner a little either way, if that gets it past.
+fn step_around(
+    backend: &dyn PhysicsBackend,
+    character: &Character,
+    from: &Isometry2<f32>,
+    motion: Vector2<f32>,
+) -> Option<Isometry2<f32>> {
+    let angle = character.controller.step_around;
+    [angle, -angle]
+        .iter()
+        .map(|angle| Rotation2::new(*angle) * motion)
+        .find(|turned| {
+            backend
+                .cast_shape(&character.handle, from, *turned)
+                .is_none()
+        })
+        .map(|turned| Isometry2::new(from.translation.vector + turned, from.rotation.angle()))
+}
+
+pub struct Physics {
+    backend: Box<dyn PhysicsBackend>,
+    characters: Vec<Character>,
+}
+
+impl Physics {
+    pub fn new() -> Self {
+        Self::with_backend(Box::new(NphysicsBackend::default()))
     }
 
-    pub fn set_location(&mut self, handle: &PhysicsHandle, x: N, y: N) {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body_mut(handle) {
-                rigid_body.set_position(Isometry2::new(
-                    Vector2::new(x, y),
-                    rigid_body.position().rotation.angle(),
-                ));
-            }
+    /// Runs on another backend. Tests use `GridBackend`, which only exists in test builds.
+    pub fn with_backend(backend: Box<dyn PhysicsBackend>) -> Self {
+        Physics {
+            backend,
+            characters: Vec::new(),
         }
     }
 
-    pub fn set_sensor_position(&mut self, sensor: &AttachedSensor, x: N, y: N) {
-        if let Some(handle) = sensor.handle {
-            if let (Some(parent), Some(collider)) = (
-                self.bodies.rigid_body(handle.0),
-                self.colliders.get_mut(handle.1),
-            ) {
-                collider.set_position(Isometry2::new(
-                    parent.position().translation.vector + Vector2::new(x, y),
-                    collider.position().rotation.angle(),
-                ));
+    /// Moves the characters, then steps the backend with their velocities held at zero so it
+    /// doesn't move them a second time.
+    pub fn step(&mut self) {
+        let dt = self.backend.timestep();
+        let mut velocities = Vec::with_capacity(self.characters.len());
+        for character in self.characters.iter() {
+            let velocity = self
+                .backend
+                .velocity(&character.handle)
+                .unwrap_or_else(Vector2::zeros);
+            if let Some(from) = self.backend.position(&character.handle) {
+                let motion = velocity * dt * character.speed_multiplier;
+                let mut to = slide(self.backend.as_ref(), character, &from, motion);
+                let moved = (to.translation.vector - from.translation.vector).norm();
+                if moved < motion.norm() * 0.5 {
+                    if let Some(around) =
+                        step_around(self.backend.as_ref(), character, &from, motion)
+                    {
+                        to = around;
+                    }
+                }
+                self.backend.set_position(&character.handle, to);
             }
+            self.backend
+                .set_velocity(&character.handle, Vector2::zeros());
+            velocities.push(velocity);
+        }
+        self.push_apart();
+        self.backend.step();
+        for (character, velocity) in self.characters.iter().zip(velocities) {
+            self.backend.set_velocity(&character.handle, velocity);
         }
     }
 
-    pub fn set_rotation(&mut self, handle: &PhysicsHandle, radians: N) {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body_mut(handle) {
-                rigid_body.set_position(Isometry2::new(
-                    rigid_body.position().translation.vector,
-                    radians,
-                ));
+    /// Separates overlapping characters, moving only the lower priority one unless they tie.
+    /// Each character is only checked against those in the grid cells around it.
+    fn push_apart(&mut self) {
+        let mut cells: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        let mut placed = Vec::with_capacity(self.characters.len());
+        for (index, character) in self.characters.iter().enumerate() {
+            if let Some(position) = self.backend.position(&character.handle) {
+                let cell = cell_of(position.translation.vector);
+                cells.entry(cell).or_insert_with(Vec::new).push(index);
+                placed.push((index, cell));
             }
         }
-    }
-
-    pub fn get_velocity(&mut self, handle: &PhysicsHandle) -> Option<Vector2<N>> {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body(handle) {
-                return Some(rigid_body.velocity().linear);
+        let widest = self
+            .characters
+            .iter()
+            .fold(0.0, |widest, character| f32::max(widest, character.radius));
+        // How many cells apart two characters can be and still overlap.
+        let reach = (widest * 2.0 / CELL_SIZE).ceil() as i32;
+        for (first, (x, y)) in placed {
+            let mut neighbours = Vec::new();
+            for cell_x in x - reach..=x + reach {
+                for cell_y in y - reach..=y + reach {
+                    if let Some(cell) = cells.get(&(cell_x, cell_y)) {
+                        neighbours.extend(cell.iter().filter(|second| **second > first));
+                    }
+                }
+            }
+            neighbours.sort();
+            for second in neighbours {
+                let (a, b) = (&self.characters[first], &self.characters[second]);
+                let (from_a, from_b) = match (
+                    self.backend.position(&a.handle),
+                    self.backend.position(&b.handle),
+                ) {
+                    (Some(from_a), Some(from_b)) => (from_a, from_b),
+                    _ => continue,
+                };
+                let offset = from_b.translation.vector - from_a.translation.vector;
+                let distance = offset.norm();
+                let overlap = a.radius + b.radius - distance;
+                if overlap <= 0.0 {
+                    continue;
+                }
+                let normal = if distance > std::f32::EPSILON {
+                    offset / distance
+                } else {
+                    Vector2::x()
+                };
+                let (share_a, share_b) =
+                    match a.controller.push_priority.cmp(&b.controller.push_priority) {
+                        Ordering::Greater => (0.0, 1.0),
+                        Ordering::Less => (1.0, 0.0),
+                        Ordering::Equal => (0.5, 0.5),
+                    };
+                let to_a = slide(
+                    self.backend.as_ref(),
+                    a,
+                    &from_a,
+                    -normal * overlap * share_a,
+                );
+                let to_b = slide(
+                    self.backend.as_ref(),
+                    b,
+                    &from_b,
+                    normal * overlap * share_b,
+                );
+                self.backend.set_position(&a.handle, to_a);
+                self.backend.set_position(&b.handle, to_b);
             }
         }
-        None
     }
 
-    pub fn set_velocity(&mut self, handle: &PhysicsHandle, vec: Vector2<N>) {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body_mut(handle) {
-                rigid_body.set_linear_velocity(vec);
-            }
+    pub fn spawn(&mut self, entity: Entity, desc: &PhysicsDesc, x: f32, y: f32) -> PhysicsHandle {
+        let handle = self.backend.spawn(entity, desc, Point2::new(x, y));
+        if let Some(controller) = desc.character {
+            let radius = desc
+                .collider
+                .get_shape()
+                .bounding_sphere(&Isometry2::identity())
+                .radius();
+            self.characters.push(Character {
+                handle: handle.clone(),
+                controller,
+                radius,
+                speed_multiplier: 1.0,
+            });
         }
+        handle
     }
 
-    pub fn set_damping(&mut self, handle: &PhysicsHandle, damping: N) {
-        if let Some(handle) = handle.body {
-            if let Some(rigid_body) = self.bodies.rigid_body_mut(handle) {
-                rigid_body.set_linear_damping(damping);
-            }
-        }
+    pub fn add_child_collider(
+        &mut self,
+        entity: Entity,
+        parent_handle: &PhysicsHandle,
+        collider_desc: &ColliderDesc<f32>,
+    ) -> Result<PhysicsHandle, GameError> {
+        self.backend.attach(entity, parent_handle, collider_desc)
     }
 
-    pub fn apply_velocity_change(&mut self, handle: &PhysicsHandle, vec: Vector2<N>) {
-        if let Some(handle) = handle.body {
-            if let Some(body) = self.bodies.get_mut(handle) {
-                body.apply_force(0, &Force::linear(vec), ForceType::VelocityChange, true)
-            } else {
-                panic!("HOLY SHIT");
-            }
-        } else {
-            panic!("NOLY SHIT");
-        }
+    pub fn remove_dead(&mut self, entities: &EntitiesRes) {
+        self.backend.remove_dead(entities);
+        let backend = &self.backend;
+        self.characters
+            .retain(|character| backend.position(&character.handle).is_some());
     }
 
-    pub fn apply_impulse(&mut self, handle: &PhysicsHandle, vec: Vector2<N>) {
-        if let Some(handle) = handle.body {
-            if let Some(body) = self.bodies.get_mut(handle) {
-                body.apply_force(0, &Force::linear(vec), ForceType::Impulse, true)
+    /// Scales how far a character moves for its velocity, like wading through mud.
+    pub fn set_speed_multiplier(&mut self, handle: &PhysicsHandle, multiplier: f32) {
+        for character in self.characters.iter_mut() {
+            if character.handle.body == handle.body {
+                character.speed_multiplier = multiplier;
             }
         }
     }
 
-    pub fn get_body_entity(&self, handle: DefaultBodyHandle) -> Option<&Entity> {
-        if let Some(rigid_body) = self.bodies.rigid_body(handle) {
-            if let Some(m_entity) = rigid_body.user_data() {
-                m_entity.downcast_ref::<Entity>()
-            } else {
-                None
-            }
-        } else {
-            None
+    /// How wide the character moving `handle` is, or `None` if it isn't a character.
+    pub fn character_radius(&self, handle: &PhysicsHandle) -> Option<f32> {
+        self.characters
+            .iter()
+            .find(|character| character.handle.body == handle.body)
+            .map(|character| character.radius)
+    }
+
+    pub fn get_position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>> {
+        self.backend.position(handle)
+    }
+
+    pub fn get_location(&self, handle: &PhysicsHandle) -> Option<Point2<f32>> {
+        self.get_position(handle)
+            .map(|location| location.translation.vector)
+            .map(|vector| Point2::new(vector.x, vector.y))
+    }
+
+    /// Where a handle's own collider is, which for attached sensors is offset from the body.
+    pub fn get_collider_location(&self, handle: &PhysicsHandle) -> Option<Point2<f32>> {
+        self.backend
+            .collider_position(handle)
+            .map(|position| Point2::from(position.translation.vector))
+    }
+
+    pub fn set_location(&mut self, handle: &PhysicsHandle, x: f32, y: f32) {
+        if let Some(position) = self.get_position(handle) {
+            self.backend.set_position(
+                handle,
+                Isometry2::new(Vector2::new(x, y), position.rotation.angle()),
+            );
         }
     }
 
-    pub fn get_collider_entity(&self, handle: DefaultColliderHandle) -> Option<&Entity> {
-        if let Some(collider) = self.colliders.get(handle) {
-            if let Some(m_entity) = collider.user_data() {
-                m_entity.downcast_ref::<Entity>()
-            } else {
-                None
-            }
-        } else {
-            None
+    pub fn set_rotation(&mut self, handle: &PhysicsHandle, radians: f32) {
+        if let Some(position) = self.get_position(handle) {
+            self.backend
+                .set_position(handle, Isometry2::new(position.translation.vector, radians));
         }
     }
 
+    pub fn get_velocity(&self, handle: &PhysicsHandle) -> Option<Vector2<f32>> {
+        self.backend.velocity(handle)
+    }
+
+    pub fn set_velocity(&mut self, handle: &PhysicsHandle, vec: Vector2<f32>) {
+        self.backend.set_velocity(handle, vec);
+    }
+
+    pub fn set_damping(&mut self, handle: &PhysicsHandle, damping: f32) {
+        self.backend.set_damping(handle, damping);
+    }
+
+    pub fn apply_velocity_change(
+        &mut self,
+        handle: &PhysicsHandle,
+        vec: Vector2<f32>,
+    ) -> Result<(), GameError> {
+        self.backend.apply_velocity_change(handle, vec)
+    }
+
+    pub fn apply_impulse(&mut self, handle: &PhysicsHandle, vec: Vector2<f32>) {
+        self.backend.apply_impulse(handle, vec);
+    }
+
     pub fn get_between(
         &self,
         handle1: &PhysicsHandle,
         handle2: &PhysicsHandle,
-    ) -> Option<Vector2<N>> {
-        if let (Some(collider1), Some(collider2)) = (handle1.collider, handle2.collider) {
-            if let (Some(collider1), Some(collider2)) =
-                (self.colliders.get(collider1), self.colliders.get(collider2))
-            {
-                Some(
-                    collider2.position().translation.vector
-                        - collider1.position().translation.vector,
-                )
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+    ) -> Option<Vector2<f32>> {
+        let first = self.backend.collider_position(handle1)?;
+        let second = self.backend.collider_position(handle2)?;
+        Some(second.translation.vector - first.translation.vector)
     }
 
     pub fn is_intersecting(&self, handle1: &PhysicsHandle, handle2: &PhysicsHandle) -> bool {
-        if let (Some(collider1), Some(collider2)) = (handle1.collider, handle2.collider) {
-            !self
-                .geo_world
-                .proximity_pair(&self.colliders, collider1, collider2, true)
-                .is_none()
-        } else {
-            false
-        }
+        self.backend.is_intersecting(handle1, handle2)
     }
 
     pub fn get_intersections(&self, handle: &PhysicsHandle) -> Vec<Entity> {
-        let mut found = Vec::new();
-        if let Some(collider) = handle.collider {
-            if let Some(interferences) = self
-                .geo_world
-                .colliders_in_proximity_of(&self.colliders, collider)
-            {
-                for interference in interferences {
-                    if let Some(entity) = self.get_collider_entity(interference.0) {
-                        found.push(*entity);
-                    }
-                }
-            }
-        }
-        found
+        self.backend.intersections(handle)
     }
 
-    pub fn ray_cast(
-        &self,
-        handle: &PhysicsHandle,
-        direction: Vector2<N>,
-        groups: Option<CollisionGroups>,
-    ) -> Vec<(Entity, N)> {
-        let mut found = Vec::new();
-        if let Some(center) = self.get_location(handle) {
-            for interference in self.geo_world.interferences_with_ray(
-                &self.colliders,
-                &Ray::<N>::new(center, direction),
-                N::from_f32(500.0).unwrap(),
-                &groups.unwrap_or_default(),
-            ) {
-                if let Some(entity) = self.get_collider_entity(interference.0) {
-                    found.push((*entity, interference.2.toi));
-                }
-            }
+    pub fn ray_cast(&self, handle: &PhysicsHandle, direction: Vector2<f32>) -> Vec<(Entity, f32)> {
+        match self.get_location(handle) {
+            Some(center) => self.backend.ray_cast(center, direction, RAY_LENGTH),
+            None => Vec::new(),
         }
-        found
+    }
+
+    #[cfg(test)]
+    pub fn contacts_started(&self) -> Vec<(Entity, Entity)> {
+        self.backend.contacts_started()
+    }
+
+    pub fn debug_shapes(&self) -> Vec<DebugShape> {
+        self.backend.debug_shapes()
     }
 }
 
-impl<N: RealField> Default for Physics<N> {
+impl Default for Physics {
     fn default() -> Self {
-        let mut mech_world = DefaultMechanicalWorld::new(Vector2::new(N::zero(), N::zero()));
-        // mech_world.set_timestep(N::from_f32(1.0 / 30.0).unwrap());
-        Self {
-            mech_world,
-            geo_world: DefaultGeometricalWorld::new(),
-            bodies: DefaultBodySet::new(),
-            colliders: DefaultColliderSet::new(),
-            joint_constraints: DefaultJointConstraintSet::new(),
-            force_generators: DefaultForceGeneratorSet::new(),
-        }
+        Self::new()
     }
 }
 
@@ -354,7 +549,7 @@ struct PhysicsSystem;
 
 impl<'s> System<'s> for PhysicsSystem {
     type SystemData = (
-        Write<'s, Physics<f32>>,
+        Write<'s, Physics>,
         ReadStorage<'s, PhysicsHandle>,
         WriteStorage<'s, Transform>,
     );
@@ -374,11 +569,29 @@ impl<'s> System<'s> for PhysicsSystem {
     }
 }
 
+struct MovementSystem;
+
+impl<'s> System<'s> for MovementSystem {
+    type SystemData = (
+        Write<'s, Physics>,
+        ReadStorage<'s, PhysicsHandle>,
+        WriteStorage<'s, MovementIntent>,
+    );
+
+    fn run(&mut self, (mut physics, handles, mut intents): Self::SystemData) {
+        for (handle, intent) in (&handles, &mut intents).join() {
+            if let Some(velocity) = intent.velocity.take() {
+                physics.set_velocity(handle, velocity);
+            }
+        }
+    }
+}
+
 struct PhysicsSpawningSystem;
 
 impl<'s> System<'s> for PhysicsSpawningSystem {
     type SystemData = (
-        Write<'s, Physics<f32>>,
+        Write<'s, Physics>,
         ReadStorage<'s, PhysicsDesc>,
         WriteStorage<'s, AttachedSensor>,
         ReadStorage<'s, Parent>,
@@ -393,85 +606,47 @@ impl<'s> System<'s> for PhysicsSpawningSystem {
     ) {
         for (entity, desc) in (&entities, &descs).join() {
             if !handles.contains(entity) {
-                let (handle, collider_handle) = physics.spawn(&desc.body, &desc.collider);
-                let phys_handle = PhysicsHandle::new(handle, collider_handle);
-                if let Some(transform) = transforms.get(entity) {
-                    let translation = transform.translation();
-                    println!("S {} {}", translation.x, translation.y);
-                    physics.set_location(&phys_handle, translation.x as f32, translation.y as f32);
-                } else {
-                    transforms.insert(entity, Transform::default());
-                }
-                handles.insert(entity, phys_handle);
+                let (x, y) = match transforms.get(entity) {
+                    Some(transform) => (transform.translation().x, transform.translation().y),
+                    None => {
+                        transforms.insert(entity, Transform::default());
+                        (0.0, 0.0)
+                    }
+                };
+                trace!(target: "physics", "Spawned {:?} at {} {}", entity, x, y);
+                let handle = physics.spawn(entity, desc, x, y);
+                handles.insert(entity, handle);
             }
             for (child_entity, parent, attached) in (&entities, &parent, &mut attached).join() {
                 if parent.entity == entity {
                     if let Some(handle) = handles.get(entity) {
                         if attached.handle.is_none() {
-                            println!("Adding sensor!");
-                            let sensor_handle =
-                                physics.add_child_collider(handle, &attached.collider);
-                            attached.set_handle((handle.body.unwrap(), sensor_handle));
+                            trace!(target: "physics", "Attaching sensor {:?} to {:?}", child_entity, entity);
+                            match physics.add_child_collider(
+                                child_entity,
+                                handle,
+                                &attached.collider,
+                            ) {
+                                Ok(handle) => attached.handle = Some(handle),
+                                Err(err) => {
+                                    warn!(target: "physics", "Sensor {:?} not attached: {}", child_entity, err)
+                                }
+                            }
                         }
                     }
                 }
             }
         }
-        for (entity, handle) in (&entities, &mut handles).join() {
-            if handle.fresh {
-                if let Some(body_handle) = handle.body {
-                    if let Some(body) = physics.bodies.rigid_body_mut(body_handle) {
-                        body.set_user_data(Some(Box::new(entity)));
-                    }
-                }
-                if let Some(coll_handle) = handle.collider {
-                    if let Some(collider) = physics.colliders.get_mut(coll_handle) {
-                        collider.set_user_data(Some(Box::new(entity)));
-                    }
-                }
-                handle.fresh = false;
-            }
-        }
-        for (entity, attached) in (&entities, &mut attached).join() {
-            if attached.fresh {
-                if let Some((_, coll_handle)) = attached.handle {
-                    if let Some(collider) = physics.colliders.get_mut(coll_handle) {
-                        collider.set_user_data(Some(Box::new(entity)));
-                    }
-                }
-            }
-        }
     }
 }
 
 struct PhysicsDeletionSystem;
 
 impl<'s> System<'s> for PhysicsDeletionSystem {
-    type SystemData = (Write<'s, Physics<f32>>, Entities<'s>);
+    type SystemData = (Write<'s, Physics>, Entities<'s>);
 
     fn run(&mut self, (mut physics, entities): Self::SystemData) {
-        let mut bodies_to_remove = Vec::new();
-        let mut colliders_to_remove = Vec::new();
-        for (handle, body) in physics.bodies.iter() {
-            if let Some(entity) = physics.get_body_entity(handle) {
-                if !entities.is_alive(*entity) {
-                    bodies_to_remove.push(handle);
-                }
-            }
-        }
-        for (handle, collider) in physics.colliders.iter() {
-            if let Some(entity) = physics.get_collider_entity(handle) {
-                if !entities.is_alive(*entity) {
-                    colliders_to_remove.push(handle);
-                }
-            }
-        }
-        for handle in bodies_to_remove.iter() {
-            physics.bodies.remove(*handle);
-        }
-        for handle in colliders_to_remove.iter() {
-            physics.colliders.remove(*handle);
-        }
+        physics.remove_dead(&entities);
     }
 }
 
@@ -484,9 +659,177 @@ impl<'a, 'b> SystemBundle<'a, 'b> for PhysicsBundle {
         dispatcher: &mut DispatcherBuilder<'a, 'b>,
     ) -> Result<(), Error> {
         dispatcher.add(PhysicsSpawningSystem, "physics_spawn", &[]);
-        dispatcher.add(PhysicsSystem, "physics", &["physics_spawn"]);
         dispatcher.add(PhysicsDeletionSystem, "physics_delete", &[]);
         // dispatcher.add(BounceSystem, "bounce", &[]);
         Ok(())
     }
 }
+
+/// Applies movement intents, steps the world and indexes where everything ended up. Goes after
+/// `PlayerBundle` and `EnemiesBundle` so what they decide moves bodies in the same frame; they
+/// read the index built at the end of the last one, which is where bodies still stand.
+pub struct PhysicsStepBundle;
+
+impl<'a, 'b> SystemBundle<'a, 'b> for PhysicsStepBundle {
+    fn build(
+        self,
+        _world: &mut World,
+        dispatcher: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), Error> {
+        dispatcher.add(
+            MovementSystem,
+            "movement",
+            &["physics_spawn", "player_movement", "goblin"],
+        );
+        dispatcher.add(TerrainSystem::default(), "terrain", &["movement"]);
+        dispatcher.add(
+            PhysicsSystem,
+            "physics",
+            &["physics_spawn", "movement", "terrain"],
+        );
+        dispatcher.add(SpatialIndexSystem, "spatial_index", &["physics"]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ncollide2d::shape::{Ball, Cuboid, ShapeHandle};
+
+    fn grid_physics() -> Physics {
+        Physics::with_backend(Box::new(GridBackend::default()))
+    }
+
+    fn wall(half_width: f32, half_height: f32) -> PhysicsDesc {
+        let shape = ShapeHandle::new(Cuboid::new(Vector2::new(half_width, half_height)));
+        PhysicsDesc::new(
+            RigidBodyDesc::new().status(BodyStatus::Static),
+            ColliderDesc::new(shape),
+        )
+    }
+
+    fn ball(radius: f32) -> PhysicsDesc {
+        PhysicsDesc::new(
+            RigidBodyDesc::new().status(BodyStatus::Dynamic).mass(1.0),
+            ColliderDesc::new(ShapeHandle::new(Ball::new(radius))),
+        )
+    }
+
+    #[test]
+    fn spawns_where_asked() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        let handle = physics.spawn(world.create_entity().build(), &ball(4.0), 10.0, 20.0);
+        assert_eq!(physics.get_location(&handle), Some(Point2::new(10.0, 20.0)));
+        assert_eq!(physics.get_velocity(&handle), Some(Vector2::zeros()));
+    }
+
+    #[test]
+    fn character_slides_along_wall() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        physics.spawn(world.create_entity().build(), &wall(16.0, 64.0), 0.0, 0.0);
+        let desc = ball(8.0).character(CharacterController::default());
+        let handle = physics.spawn(world.create_entity().build(), &desc, -40.0, 0.0);
+        physics.set_velocity(&handle, Vector2::new(60.0, 60.0));
+        for _ in 0..30 {
+            physics.step();
+        }
+        let location = physics.get_location(&handle).unwrap();
+        // Stopped at the wall's face less the radius, but still moving along it.
+        assert!(
+            location.x <= -24.0 + SKIN,
+            "went into the wall: {}",
+            location
+        );
+        assert!(
+            location.x > -25.0,
+            "stopped short of the wall: {}",
+            location
+        );
+        assert!(location.y > 20.0, "stuck on the wall: {}", location);
+        assert_eq!(
+            physics.get_velocity(&handle),
+            Some(Vector2::new(60.0, 60.0))
+        );
+    }
+
+    #[test]
+    fn characters_push_apart_by_priority() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        let strong = ball(8.0).character(CharacterController {
+            push_priority: 1,
+            ..CharacterController::default()
+        });
+        let weak = ball(8.0).character(CharacterController::default());
+        let stays = physics.spawn(world.create_entity().build(), &strong, 0.0, 0.0);
+        let pushed = physics.spawn(world.create_entity().build(), &weak, 10.0, 0.0);
+        let far = physics.spawn(world.create_entity().build(), &weak, 200.0, 0.0);
+        physics.step();
+        assert_eq!(physics.get_location(&stays), Some(Point2::new(0.0, 0.0)));
+        let location = physics.get_location(&pushed).unwrap();
+        assert!(
+            (location.x - 16.0).abs() < 0.01,
+            "not pushed clear: {}",
+            location
+        );
+        assert_eq!(physics.get_location(&far), Some(Point2::new(200.0, 0.0)));
+    }
+
+    #[test]
+    fn ray_cast_reports_distance() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        let target = world.create_entity().build();
+        physics.spawn(target, &wall(10.0, 10.0), 100.0, 0.0);
+        let handle = physics.spawn(world.create_entity().build(), &ball(4.0), 0.0, 0.0);
+        let hits = physics.ray_cast(&handle, Vector2::x());
+        let (_, distance) = hits
+            .iter()
+            .find(|(entity, _)| *entity == target)
+            .expect("ray missed the wall");
+        assert!((distance - 90.0).abs() < 0.01);
+        assert!(physics
+            .ray_cast(&handle, -Vector2::x())
+            .iter()
+            .all(|(entity, _)| *entity != target));
+    }
+
+    #[test]
+    fn intersections_list_overlapping_entities() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        let near = world.create_entity().build();
+        let far = world.create_entity().build();
+        physics.spawn(near, &wall(10.0, 10.0), 0.0, 0.0);
+        physics.spawn(far, &wall(10.0, 10.0), 100.0, 0.0);
+        let handle = physics.spawn(world.create_entity().build(), &ball(4.0), 12.0, 0.0);
+        assert_eq!(physics.get_intersections(&handle), vec![near]);
+    }
+
+    #[test]
+    fn contact_starts_once() {
+        let mut world = World::new();
+        let mut physics = grid_physics();
+        let wall_entity = world.create_entity().build();
+        let ball_entity = world.create_entity().build();
+        physics.spawn(wall_entity, &wall(10.0, 10.0), 0.0, 0.0);
+        let handle = physics.spawn(ball_entity, &ball(5.0), -30.0, 0.0);
+        physics.set_velocity(&handle, Vector2::new(60.0, 0.0));
+        let mut started = Vec::new();
+        for _ in 0..30 {
+            physics.step();
+            started.extend(physics.contacts_started());
+        }
+        assert_eq!(started.len(), 1);
+        let (first, second) = started[0];
+        assert!(
+            (first, second) == (wall_entity, ball_entity)
+                || (first, second) == (ball_entity, wall_entity)
+        );
+        // Blocked by the wall rather than passing through it.
+        assert!(physics.get_location(&handle).unwrap().x < -14.0);
+    }
+}
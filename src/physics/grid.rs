@@ -0,0 +1,487 @@
+use super::{BodyId, ColliderId, DebugShape, PhysicsBackend, PhysicsDesc, PhysicsHandle, ShapeHit};
+use crate::error::GameError;
+use amethyst::ecs::world::EntitiesRes;
+use amethyst::ecs::Entity;
+use nalgebra::geometry::{Isometry2, Point2};
+use nalgebra::Vector2;
+use ncollide2d::bounding_volume::{BoundingVolume, AABB};
+use ncollide2d::query::{Ray, RayCast};
+use ncollide2d::shape::{Ball, ConvexPolygon, Cuboid, ShapeHandle};
+use nphysics2d::object::{BodyStatus, ColliderDesc};
+use std::collections::{HashMap, HashSet};
+
+/// Matches the fixed step nphysics takes.
+const TIMESTEP: f32 = 1.0 / 60.0;
+/// Colliders this close count as touching for contact events.
+const CONTACT_MARGIN: f32 = 0.5;
+
+struct GridBody {
+    entity: Entity,
+    position: Isometry2<f32>,
+    velocity: Vector2<f32>,
+    damping: f32,
+    inverse_mass: f32,
+    moves: bool,
+}
+
+struct GridCollider {
+    entity: Entity,
+    body: BodyId,
+    offset: Isometry2<f32>,
+    shape: ShapeHandle<f32>,
+    sensor: bool,
+}
+
+/// A kinematic stand-in for nphysics with no solver: bodies move along their velocity one axis
+/// at a time and stop at solid colliders on static bodies, which are bucketed in a uniform grid.
+/// Moving bodies pass through each other. Good enough to run systems headless.
+pub struct GridBackend {
+    cell_size: f32,
+    bodies: HashMap<BodyId, GridBody>,
+    colliders: HashMap<ColliderId, GridCollider>,
+    cells: HashMap<(i32, i32), Vec<ColliderId>>,
+    touching: HashSet<(ColliderId, ColliderId)>,
+    started: Vec<(Entity, Entity)>,
+    next_id: usize,
+}
+
+impl GridBackend {
+    pub fn new(cell_size: f32) -> Self {
+        GridBackend {
+            cell_size,
+            bodies: HashMap::new(),
+            colliders: HashMap::new(),
+            cells: HashMap::new(),
+            touching: HashSet::new(),
+            started: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    fn cells_of(&self, aabb: &AABB<f32>) -> Vec<(i32, i32)> {
+        let min_x = (aabb.mins().x / self.cell_size).floor() as i32;
+        let min_y = (aabb.mins().y / self.cell_size).floor() as i32;
+        let max_x = (aabb.maxs().x / self.cell_size).floor() as i32;
+        let max_y = (aabb.maxs().y / self.cell_size).floor() as i32;
+        let mut cells = Vec::new();
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                cells.push((x, y));
+            }
+        }
+        cells
+    }
+
+    fn collider_at(&self, collider: &GridCollider, body: &Isometry2<f32>) -> AABB<f32> {
+        collider.shape.aabb(&(body * collider.offset))
+    }
+
+    fn aabb(&self, id: ColliderId) -> Option<AABB<f32>> {
+        let collider = self.colliders.get(&id)?;
+        let body = self.bodies.get(&collider.body)?;
+        Some(self.collider_at(collider, &body.position))
+    }
+
+    /// Files a solid collider on a static body under the cells it covers.
+    fn index(&mut self, id: ColliderId) {
+        for cell in self.cells.values_mut() {
+            cell.retain(|indexed| *indexed != id);
+        }
+        let blocks = self.colliders.get(&id).map_or(false, |collider| {
+            !collider.sensor
+                && self
+                    .bodies
+                    .get(&collider.body)
+                    .map_or(false, |body| !body.moves)
+        });
+        if let (true, Some(aabb)) = (blocks, self.aabb(id)) {
+            for cell in self.cells_of(&aabb) {
+                self.cells.entry(cell).or_insert_with(Vec::new).push(id);
+            }
+        }
+    }
+
+    fn add_collider(
+        &mut self,
+        entity: Entity,
+        body: BodyId,
+        desc: &ColliderDesc<f32>,
+    ) -> ColliderId {
+        self.next_id += 1;
+        let id = ColliderId(self.next_id);
+        self.colliders.insert(
+            id,
+            GridCollider {
+                entity,
+                body,
+                offset: *desc.get_position(),
+                shape: desc.get_shape().clone(),
+                sensor: desc.is_sensor(),
+            },
+        );
+        self.index(id);
+        id
+    }
+
+    /// Whether a body's solid colliders would overlap a wall at `position`.
+    fn blocked(&self, body: BodyId, position: &Isometry2<f32>) -> bool {
+        self.colliders
+            .values()
+            .filter(|collider| collider.body == body && !collider.sensor)
+            .any(|collider| {
+                let aabb = self.collider_at(collider, position);
+                self.cells_of(&aabb).iter().any(|cell| {
+                    self.cells.get(cell).map_or(false, |walls| {
+                        walls.iter().any(|wall| {
+                            self.aabb(*wall)
+                                .map_or(false, |wall| wall.intersects(&aabb))
+                        })
+                    })
+                })
+            })
+    }
+
+    fn move_body(&mut self, id: BodyId) {
+        let (mut position, mut velocity) = match self.bodies.get(&id) {
+            Some(body) if body.moves => (body.position, body.velocity),
+            _ => return,
+        };
+        for axis in 0..2 {
+            let mut step = Vector2::zeros();
+            step[axis] = velocity[axis] * TIMESTEP;
+            if step[axis] == 0.0 {
+                continue;
+            }
+            let moved = Isometry2::new(
+                position.translation.vector + step,
+                position.rotation.angle(),
+            );
+            if self.blocked(id, &moved) {
+                velocity[axis] = 0.0;
+            } else {
+                position = moved;
+            }
+        }
+        if let Some(body) = self.bodies.get_mut(&id) {
+            body.position = position;
+            body.velocity = velocity * f32::max(0.0, 1.0 - body.damping * TIMESTEP);
+        }
+    }
+
+    fn update_contacts(&mut self) {
+        let solid: Vec<(ColliderId, AABB<f32>)> = self
+            .colliders
+            .iter()
+            .filter(|(_, collider)| !collider.sensor)
+            .filter_map(|(id, _)| {
+                self.aabb(*id)
+                    .map(|aabb| (*id, aabb.loosened(CONTACT_MARGIN)))
+            })
+            .collect();
+        let mut touching = HashSet::new();
+        self.started.clear();
+        for (index, (first, first_aabb)) in solid.iter().enumerate() {
+            for (second, second_aabb) in solid[index + 1..].iter() {
+                let pair = if first.0 < second.0 {
+                    (*first, *second)
+                } else {
+                    (*second, *first)
+                };
+                if self.colliders[first].body == self.colliders[second].body
+                    || !first_aabb.intersects(second_aabb)
+                {
+                    continue;
+                }
+                if !self.touching.contains(&pair) {
+                    self.started
+                        .push((self.colliders[first].entity, self.colliders[second].entity));
+                }
+                touching.insert(pair);
+            }
+        }
+        self.touching = touching;
+    }
+
+    fn body_mut(&mut self, handle: &PhysicsHandle) -> Option<&mut GridBody> {
+        handle.body.and_then(move |id| self.bodies.get_mut(&id))
+    }
+}
+
+impl Default for GridBackend {
+    fn default() -> Self {
+        Self::new(64.0)
+    }
+}
+
+impl PhysicsBackend for GridBackend {
+    fn step(&mut self) {
+        let ids: Vec<BodyId> = self.bodies.keys().cloned().collect();
+        for id in ids {
+            self.move_body(id);
+        }
+        self.update_contacts();
+    }
+
+    fn timestep(&self) -> f32 {
+        TIMESTEP
+    }
+
+    fn spawn(
+        &mut self,
+        entity: Entity,
+        desc: &PhysicsDesc,
+        location: Point2<f32>,
+    ) -> PhysicsHandle {
+        self.next_id += 1;
+        let id = BodyId(self.next_id);
+        let mass = desc.body.get_mass();
+        self.bodies.insert(
+            id,
+            GridBody {
+                entity,
+                position: Isometry2::new(location.coords, 0.0),
+                velocity: Vector2::zeros(),
+                damping: desc.body.get_linear_damping(),
+                inverse_mass: if mass > 0.0 { 1.0 / mass } else { 0.0 },
+                moves: desc.body.get_status() != BodyStatus::Static,
+            },
+        );
+        let collider = self.add_collider(entity, id, &desc.collider);
+        for extra in desc.extra_colliders.iter() {
+            self.add_collider(entity, id, extra);
+        }
+        PhysicsHandle::new(id, collider)
+    }
+
+    fn attach(
+        &mut self,
+        entity: Entity,
+        parent: &PhysicsHandle,
+        collider: &ColliderDesc<f32>,
+    ) -> Result<PhysicsHandle, GameError> {
+        let body = parent
+            .body
+            .filter(|body| self.bodies.contains_key(body))
+            .ok_or(GameError::MissingBody)?;
+        let collider = self.add_collider(entity, body, collider);
+        Ok(PhysicsHandle::new(body, collider))
+    }
+
+    fn remove_dead(&mut self, entities: &EntitiesRes) {
+        self.bodies.retain(|_, body| entities.is_alive(body.entity));
+        let bodies = &self.bodies;
+        self.colliders.retain(|_, collider| {
+            entities.is_alive(collider.entity) && bodies.contains_key(&collider.body)
+        });
+        let colliders = &self.colliders;
+        for cell in self.cells.values_mut() {
+            cell.retain(|id| colliders.contains_key(id));
+        }
+        self.touching.retain(|(first, second)| {
+            colliders.contains_key(first) && colliders.contains_key(second)
+        });
+    }
+
+    fn position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>> {
+        handle
+            .body
+            .and_then(|id| self.bodies.get(&id))
+            .map(|body| body.position)
+    }
+
+    fn set_position(&mut self, handle: &PhysicsHandle, position: Isometry2<f32>) {
+        let id = match handle.body {
+            Some(id) => id,
+            None => return,
+        };
+        let moves = match self.bodies.get_mut(&id) {
+            Some(body) => {
+                body.position = position;
+                body.moves
+            }
+            None => return,
+        };
+        if !moves {
+            let attached: Vec<ColliderId> = self
+                .colliders
+                .iter()
+                .filter(|(_, collider)| collider.body == id)
+                .map(|(collider, _)| *collider)
+                .collect();
+            for collider in attached {
+                self.index(collider);
+            }
+        }
+    }
+
+    fn collider_position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>> {
+        let collider = handle.collider.and_then(|id| self.colliders.get(&id))?;
+        let body = self.bodies.get(&collider.body)?;
+        Some(body.position * collider.offset)
+    }
+
+    fn velocity(&self, handle: &PhysicsHandle) -> Option<Vector2<f32>> {
+        handle
+            .body
+            .and_then(|id| self.bodies.get(&id))
+            .map(|body| body.velocity)
+    }
+
+    fn set_velocity(&mut self, handle: &PhysicsHandle, velocity: Vector2<f32>) {
+        if let Some(body) = self.body_mut(handle) {
+            body.velocity = velocity;
+        }
+    }
+
+    fn set_damping(&mut self, handle: &PhysicsHandle, damping: f32) {
+        if let Some(body) = self.body_mut(handle) {
+            body.damping = damping;
+        }
+    }
+
+    fn apply_impulse(&mut self, handle: &PhysicsHandle, impulse: Vector2<f32>) {
+        if let Some(body) = self.body_mut(handle) {
+            body.velocity += impulse * body.inverse_mass;
+        }
+    }
+
+    fn apply_velocity_change(
+        &mut self,
+        handle: &PhysicsHandle,
+        change: Vector2<f32>,
+    ) -> Result<(), GameError> {
+        let body = self.body_mut(handle).ok_or(GameError::MissingBody)?;
+        body.velocity += change;
+        Ok(())
+    }
+
+    fn is_intersecting(&self, first: &PhysicsHandle, second: &PhysicsHandle) -> bool {
+        match (
+            first.collider.and_then(|id| self.aabb(id)),
+            second.collider.and_then(|id| self.aabb(id)),
+        ) {
+            (Some(first), Some(second)) => first.intersects(&second),
+            _ => false,
+        }
+    }
+
+    fn intersections(&self, handle: &PhysicsHandle) -> Vec<Entity> {
+        let (id, aabb) = match handle
+            .collider
+            .and_then(|id| self.aabb(id).map(|aabb| (id, aabb)))
+        {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        let body = self.colliders[&id].body;
+        self.colliders
+            .iter()
+            .filter(|(other, collider)| **other != id && collider.body != body)
+            .filter(|(other, _)| {
+                self.aabb(**other)
+                    .map_or(false, |other| other.intersects(&aabb))
+            })
+            .map(|(_, collider)| collider.entity)
+            .collect()
+    }
+
+    fn ray_cast(
+        &self,
+        origin: Point2<f32>,
+        direction: Vector2<f32>,
+        max_toi: f32,
+    ) -> Vec<(Entity, f32)> {
+        let ray = Ray::new(origin, direction);
+        self.colliders
+            .iter()
+            .filter(|(_, collider)| !collider.sensor)
+            .filter_map(|(id, collider)| {
+                self.aabb(*id)?
+                    .toi_with_ray(&Isometry2::identity(), &ray, max_toi, true)
+                    .map(|toi| (collider.entity, toi))
+            })
+            .collect()
+    }
+
+    fn cast_shape(
+        &self,
+        handle: &PhysicsHandle,
+        from: &Isometry2<f32>,
+        motion: Vector2<f32>,
+    ) -> Option<ShapeHit> {
+        let collider = handle.collider.and_then(|id| self.colliders.get(&id))?;
+        let start = self.collider_at(collider, from);
+        let end = AABB::new(start.mins() + motion, start.maxs() + motion);
+        let half_extents = start.half_extents();
+        let ray = Ray::new(start.center(), motion);
+        let mut walls: Vec<ColliderId> = self
+            .cells_of(&start.merged(&end))
+            .iter()
+            .filter_map(|cell| self.cells.get(cell))
+            .flatten()
+            .cloned()
+            .collect();
+        walls.sort_by_key(|wall| wall.0);
+        walls.dedup();
+        walls
+            .into_iter()
+            .filter(|wall| self.colliders[wall].body != collider.body)
+            .filter_map(|wall| {
+                // Sweeping a box against a box is a ray against the box grown by the mover.
+                let aabb = self.aabb(wall)?;
+                let grown = AABB::new(aabb.mins() - half_extents, aabb.maxs() + half_extents);
+                let hit = grown.toi_and_normal_with_ray(&Isometry2::identity(), &ray, 1.0, true)?;
+                if motion.dot(&hit.normal) >= 0.0 {
+                    return None;
+                }
+                Some(ShapeHit {
+                    entity: self.colliders[&wall].entity,
+                    toi: hit.toi,
+                    normal: hit.normal,
+                })
+            })
+            .min_by(|a, b| {
+                a.toi
+                    .partial_cmp(&b.toi)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+
+    fn contacts_started(&self) -> Vec<(Entity, Entity)> {
+        self.started.clone()
+    }
+
+    fn debug_shapes(&self) -> Vec<DebugShape> {
+        let mut shapes = Vec::new();
+        for collider in self.colliders.values() {
+            let body = match self.bodies.get(&collider.body) {
+                Some(body) => body,
+                None => continue,
+            };
+            let position = body.position * collider.offset;
+            if let Some(circle) = collider.shape.as_shape::<Ball<f32>>() {
+                shapes.push(DebugShape::Circle(
+                    Point2::from(position.translation.vector),
+                    circle.radius(),
+                ));
+            } else if let Some(cube) = collider.shape.as_shape::<Cuboid<f32>>() {
+                shapes.push(DebugShape::Rectangle(position, *cube.half_extents()));
+            } else if let Some(polygon) = collider.shape.as_shape::<ConvexPolygon<f32>>() {
+                shapes.push(DebugShape::Polygon(
+                    polygon
+                        .points()
+                        .iter()
+                        .map(|point| position * point)
+                        .collect(),
+                ));
+            } else {
+                let aabb = self.collider_at(collider, &body.position);
+                shapes.push(DebugShape::Rectangle(
+                    Isometry2::new(aabb.center().coords, 0.0),
+                    aabb.half_extents(),
+                ));
+            }
+        }
+        shapes
+    }
+}
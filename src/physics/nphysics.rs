@@ -0,0 +1,353 @@
+use super::{BodyId, ColliderId, DebugShape, PhysicsBackend, PhysicsDesc, PhysicsHandle, ShapeHit};
+use crate::error::GameError;
+use amethyst::ecs::world::EntitiesRes;
+use amethyst::ecs::Entity;
+use nalgebra::geometry::{Isometry2, Point2};
+use nalgebra::Vector2;
+use ncollide2d::bounding_volume::BoundingVolume;
+use ncollide2d::pipeline::narrow_phase::ContactEvent;
+use ncollide2d::pipeline::object::CollisionGroups;
+use ncollide2d::query::{self, Ray};
+use ncollide2d::shape::{Ball, ConvexPolygon, Cuboid};
+use nphysics2d::force_generator::DefaultForceGeneratorSet;
+use nphysics2d::joint::DefaultJointConstraintSet;
+use nphysics2d::math::{Force, ForceType};
+use nphysics2d::object::*;
+use nphysics2d::world::{DefaultGeometricalWorld, DefaultMechanicalWorld};
+use std::any::Any;
+use std::collections::HashMap;
+
+/// The full rigid body simulation the game ships with.
+pub struct NphysicsBackend {
+    geo_world: DefaultGeometricalWorld<f32>,
+    mech_world: DefaultMechanicalWorld<f32>,
+    bodies: DefaultBodySet<f32>,
+    colliders: DefaultColliderSet<f32>,
+    joint_constraints: DefaultJointConstraintSet<f32>,
+    force_generators: DefaultForceGeneratorSet<f32>,
+    body_ids: HashMap<BodyId, DefaultBodyHandle>,
+    collider_ids: HashMap<ColliderId, DefaultColliderHandle>,
+    next_id: usize,
+}
+
+impl Default for NphysicsBackend {
+    fn default() -> Self {
+        let mech_world = DefaultMechanicalWorld::new(Vector2::new(0.0, 0.0));
+        // mech_world.set_timestep(1.0 / 30.0);
+        NphysicsBackend {
+            mech_world,
+            geo_world: DefaultGeometricalWorld::new(),
+            bodies: DefaultBodySet::new(),
+            colliders: DefaultColliderSet::new(),
+            joint_constraints: DefaultJointConstraintSet::new(),
+            force_generators: DefaultForceGeneratorSet::new(),
+            body_ids: HashMap::new(),
+            collider_ids: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+fn user_entity(data: Option<&(dyn Any + Send + Sync)>) -> Option<Entity> {
+    data.and_then(|data| data.downcast_ref::<Entity>()).cloned()
+}
+
+impl NphysicsBackend {
+    fn body(&self, handle: &PhysicsHandle) -> Option<&RigidBody<f32>> {
+        handle
+            .body
+            .and_then(|id| self.body_ids.get(&id))
+            .and_then(|body| self.bodies.rigid_body(*body))
+    }
+
+    fn body_mut(&mut self, handle: &PhysicsHandle) -> Option<&mut RigidBody<f32>> {
+        let body = *handle.body.and_then(|id| self.body_ids.get(&id))?;
+        self.bodies.rigid_body_mut(body)
+    }
+
+    fn collider_handle(&self, handle: &PhysicsHandle) -> Option<DefaultColliderHandle> {
+        handle
+            .collider
+            .and_then(|id| self.collider_ids.get(&id))
+            .cloned()
+    }
+
+    fn collider_entity(&self, handle: DefaultColliderHandle) -> Option<Entity> {
+        self.colliders
+            .get(handle)
+            .and_then(|collider| user_entity(collider.user_data()))
+    }
+
+    fn add_collider(
+        &mut self,
+        entity: Entity,
+        body: DefaultBodyHandle,
+        desc: &ColliderDesc<f32>,
+    ) -> ColliderId {
+        let mut collider = desc.build(BodyPartHandle(body, 0));
+        collider.set_user_data(Some(Box::new(entity)));
+        let collider = self.colliders.insert(collider);
+        self.next_id += 1;
+        let id = ColliderId(self.next_id);
+        self.collider_ids.insert(id, collider);
+        id
+    }
+}
+
+impl PhysicsBackend for NphysicsBackend {
+    fn step(&mut self) {
+        self.mech_world.step(
+            &mut self.geo_world,
+            &mut self.bodies,
+            &mut self.colliders,
+            &mut self.joint_constraints,
+            &mut self.force_generators,
+        )
+    }
+
+    fn timestep(&self) -> f32 {
+        self.mech_world.timestep()
+    }
+
+    fn spawn(
+        &mut self,
+        entity: Entity,
+        desc: &PhysicsDesc,
+        location: Point2<f32>,
+    ) -> PhysicsHandle {
+        let mut body = desc.body.build();
+        body.set_user_data(Some(Box::new(entity)));
+        let angle = body.position().rotation.angle();
+        body.set_position(Isometry2::new(location.coords, angle));
+        let body = self.bodies.insert(body);
+        self.next_id += 1;
+        let body_id = BodyId(self.next_id);
+        self.body_ids.insert(body_id, body);
+        let collider_id = self.add_collider(entity, body, &desc.collider);
+        for collider in desc.extra_colliders.iter() {
+            self.add_collider(entity, body, collider);
+        }
+        PhysicsHandle::new(body_id, collider_id)
+    }
+
+    fn attach(
+        &mut self,
+        entity: Entity,
+        parent: &PhysicsHandle,
+        collider: &ColliderDesc<f32>,
+    ) -> Result<PhysicsHandle, GameError> {
+        let body_id = parent.body.ok_or(GameError::MissingBody)?;
+        let body = *self.body_ids.get(&body_id).ok_or(GameError::MissingBody)?;
+        let collider_id = self.add_collider(entity, body, collider);
+        Ok(PhysicsHandle::new(body_id, collider_id))
+    }
+
+    fn remove_dead(&mut self, entities: &EntitiesRes) {
+        let dead = |data: Option<&(dyn Any + Send + Sync)>| {
+            user_entity(data).map_or(false, |entity| !entities.is_alive(entity))
+        };
+        let bodies_to_remove: Vec<_> = self
+            .bodies
+            .iter()
+            .filter(|(_, body)| dead(body.user_data()))
+            .map(|(handle, _)| handle)
+            .collect();
+        let colliders_to_remove: Vec<_> = self
+            .colliders
+            .iter()
+            .filter(|(_, collider)| dead(collider.user_data()))
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in bodies_to_remove.iter() {
+            self.bodies.remove(*handle);
+        }
+        for handle in colliders_to_remove.iter() {
+            self.colliders.remove(*handle);
+        }
+        let (bodies, colliders) = (&self.bodies, &self.colliders);
+        self.body_ids
+            .retain(|_, handle| bodies.get(*handle).is_some());
+        self.collider_ids
+            .retain(|_, handle| colliders.get(*handle).is_some());
+    }
+
+    fn position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>> {
+        self.body(handle).map(|body| body.position().clone())
+    }
+
+    fn set_position(&mut self, handle: &PhysicsHandle, position: Isometry2<f32>) {
+        if let Some(body) = self.body_mut(handle) {
+            body.set_position(position);
+        }
+    }
+
+    fn collider_position(&self, handle: &PhysicsHandle) -> Option<Isometry2<f32>> {
+        self.collider_handle(handle)
+            .and_then(|collider| self.colliders.get(collider))
+            .map(|collider| collider.position().clone())
+    }
+
+    fn velocity(&self, handle: &PhysicsHandle) -> Option<Vector2<f32>> {
+        self.body(handle).map(|body| body.velocity().linear)
+    }
+
+    fn set_velocity(&mut self, handle: &PhysicsHandle, velocity: Vector2<f32>) {
+        if let Some(body) = self.body_mut(handle) {
+            body.set_linear_velocity(velocity);
+        }
+    }
+
+    fn set_damping(&mut self, handle: &PhysicsHandle, damping: f32) {
+        if let Some(body) = self.body_mut(handle) {
+            body.set_linear_damping(damping);
+        }
+    }
+
+    fn apply_impulse(&mut self, handle: &PhysicsHandle, impulse: Vector2<f32>) {
+        if let Some(body) = self.body_mut(handle) {
+            body.apply_force(0, &Force::linear(impulse), ForceType::Impulse, true);
+        }
+    }
+
+    fn apply_velocity_change(
+        &mut self,
+        handle: &PhysicsHandle,
+        change: Vector2<f32>,
+    ) -> Result<(), GameError> {
+        let body = self.body_mut(handle).ok_or(GameError::MissingBody)?;
+        body.apply_force(0, &Force::linear(change), ForceType::VelocityChange, true);
+        Ok(())
+    }
+
+    fn is_intersecting(&self, first: &PhysicsHandle, second: &PhysicsHandle) -> bool {
+        match (self.collider_handle(first), self.collider_handle(second)) {
+            (Some(first), Some(second)) => self
+                .geo_world
+                .proximity_pair(&self.colliders, first, second, true)
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    fn intersections(&self, handle: &PhysicsHandle) -> Vec<Entity> {
+        self.collider_handle(handle)
+            .and_then(|collider| {
+                self.geo_world
+                    .colliders_in_proximity_of(&self.colliders, collider)
+            })
+            .map(|interferences| {
+                interferences
+                    .filter_map(|interference| self.collider_entity(interference.0))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn ray_cast(
+        &self,
+        origin: Point2<f32>,
+        direction: Vector2<f32>,
+        max_toi: f32,
+    ) -> Vec<(Entity, f32)> {
+        self.geo_world
+            .interferences_with_ray(
+                &self.colliders,
+                &Ray::new(origin, direction),
+                max_toi,
+                &CollisionGroups::default(),
+            )
+            .filter(|interference| !interference.1.is_sensor())
+            .filter_map(|interference| {
+                self.collider_entity(interference.0)
+                    .map(|entity| (entity, interference.2.toi))
+            })
+            .collect()
+    }
+
+    fn cast_shape(
+        &self,
+        handle: &PhysicsHandle,
+        from: &Isometry2<f32>,
+        motion: Vector2<f32>,
+    ) -> Option<ShapeHit> {
+        let collider = self
+            .collider_handle(handle)
+            .and_then(|collider| self.colliders.get(collider))?;
+        let start = from * collider.position_wrt_body();
+        let shape = collider.shape().as_ref();
+        let end = Isometry2::new(start.translation.vector + motion, start.rotation.angle());
+        let swept = shape.aabb(&start).merged(&shape.aabb(&end));
+        self.geo_world
+            .interferences_with_aabb(&self.colliders, &swept, &CollisionGroups::default())
+            .filter(|(_, other)| !other.is_sensor() && other.body() != collider.body())
+            .filter(|(_, other)| {
+                self.bodies
+                    .rigid_body(other.body())
+                    .map_or(true, |body| body.status() != BodyStatus::Kinematic)
+            })
+            .filter_map(|(_, other)| {
+                let toi = query::time_of_impact(
+                    &start,
+                    &motion,
+                    shape,
+                    other.position(),
+                    &Vector2::zeros(),
+                    other.shape().as_ref(),
+                    1.0,
+                    0.0,
+                )?;
+                let normal = -toi.normal1.into_inner();
+                if motion.dot(&normal) >= 0.0 {
+                    return None;
+                }
+                Some(ShapeHit {
+                    entity: user_entity(other.user_data())?,
+                    toi: toi.toi,
+                    normal,
+                })
+            })
+            .min_by(|a, b| {
+                a.toi
+                    .partial_cmp(&b.toi)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+
+    fn contacts_started(&self) -> Vec<(Entity, Entity)> {
+        self.geo_world
+            .contact_events()
+            .iter()
+            .filter_map(|event| match event {
+                ContactEvent::Started(first, second) => Some((
+                    self.collider_entity(*first)?,
+                    self.collider_entity(*second)?,
+                )),
+                ContactEvent::Stopped(_, _) => None,
+            })
+            .collect()
+    }
+
+    fn debug_shapes(&self) -> Vec<DebugShape> {
+        let mut shapes = Vec::new();
+        for (_, collider) in self.colliders.iter() {
+            let position = collider.position();
+            if let Some(circle) = collider.shape().as_shape::<Ball<f32>>() {
+                shapes.push(DebugShape::Circle(
+                    Point2::from(position.translation.vector),
+                    circle.radius(),
+                ));
+            } else if let Some(cube) = collider.shape().as_shape::<Cuboid<f32>>() {
+                shapes.push(DebugShape::Rectangle(*position, *cube.half_extents()));
+            } else if let Some(polygon) = collider.shape().as_shape::<ConvexPolygon<f32>>() {
+                shapes.push(DebugShape::Polygon(
+                    polygon
+                        .points()
+                        .iter()
+                        .map(|point| position * point)
+                        .collect(),
+                ));
+            }
+        }
+        shapes
+    }
+}
@@ -1,11 +1,15 @@
-use crate::assets::{AnimationId, Direction, PrefabStorage};
+use crate::assets::{AnimationId, Assets, Direction};
+use crate::building::BuildMode;
+use crate::camera::initialize_camera;
 use crate::combat::*;
+use crate::mixer::SoundEvent;
 use crate::physics::*;
 use crate::prelude::*;
+use crate::spatial::SpatialIndex;
 use amethyst::{
     animation::*,
     assets::Handle,
-    core::{bundle::SystemBundle, transform::*},
+    core::{bundle::SystemBundle, shrev::EventChannel, transform::*},
     ecs::world::LazyBuilder,
     ecs::*,
     error::Error,
@@ -13,28 +17,11 @@ use amethyst::{
     prelude::*,
     renderer::{camera::*, SpriteRender},
 };
+use log::warn;
 use na::{Isometry2, Vector2};
 use ncollide2d::shape::*;
 use nphysics2d::object::*;
 
-const ARENA_WIDTH: f32 = 240.0;
-const ARENA_HEIGHT: f32 = 160.0;
-fn standard_camera() -> Camera {
-    Camera::standard_2d(ARENA_WIDTH, ARENA_HEIGHT)
-}
-
-pub fn initialize_camera(builder: impl Builder, player: &Entity) -> Entity {
-    // Setup camera in a way that our screen covers whole arena and (0, 0) is in the bottom left.
-    let mut transform = Transform::default();
-    transform.set_translation_xyz(0.0, 0.0, 1.0);
-
-    builder
-        .with(standard_camera())
-        .with(Parent { entity: *player })
-        .with(transform)
-        .build()
-}
-
 #[derive(Debug, PartialEq)]
 pub enum PlayerState {
     Moving,
@@ -45,40 +32,68 @@ pub enum PlayerState {
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
 pub struct Player {
-    pub walk_speed: f32,
     pub state: PlayerState,
     pub facing: Direction,
+    pub aiming: Option<Direction>,
 }
 
-fn spawn_player(prefabs: &PrefabStorage, player_builder: LazyBuilder, x: f32, y: f32) -> Entity {
+/// Goblins use the default of 0, so they step aside for the player instead of shoving it.
+const PLAYER_PUSH_PRIORITY: u8 = 1;
+
+fn spawn_player(assets: &Assets, mut player_builder: LazyBuilder, x: f32, y: f32) -> Entity {
     let shape = ShapeHandle::new(Ball::new(8.0));
-    let body = RigidBodyDesc::new()
-        .status(BodyStatus::Dynamic)
-        .mass(10.0)
-        .linear_damping(0.0);
+    let body = RigidBodyDesc::new().mass(10.0).linear_damping(0.0);
     let collider = ColliderDesc::new(shape);
     let mut transform = Transform::default();
     transform.set_translation_xyz(x, y, 1.0);
+    match assets.prefab("player") {
+        Some(prefab) => player_builder = player_builder.with(prefab),
+        None => warn!(target: "assets", "No \"player\" prefab in assets.ron"),
+    }
     player_builder
-        .with(PhysicsDesc::new(body, collider))
-        .with(prefabs.player.clone())
+        .with(
+            PhysicsDesc::new(body, collider).character(CharacterController {
+                push_priority: PLAYER_PUSH_PRIORITY,
+                ..CharacterController::default()
+            }),
+        )
         .with(transform)
         .with(Player {
-            walk_speed: 100.0,
             state: PlayerState::Moving,
             facing: Direction::South,
+            aiming: None,
         })
         .with(Health::new(true, MAX_PLAYER_HEALTH))
         .named("player")
         .build()
 }
 
+/// Stats bought in the shop, kept for the rest of the run.
+#[derive(Debug, Clone)]
+pub struct PlayerUpgrades {
+    pub walk_speed: f32,
+    pub sword_damage: usize,
+    pub max_health: usize,
+}
+
+impl Default for PlayerUpgrades {
+    fn default() -> Self {
+        PlayerUpgrades {
+            walk_speed: 100.0,
+            sword_damage: 1,
+            max_health: MAX_PLAYER_HEALTH,
+        }
+    }
+}
+
 #[derive(Component, Debug)]
 #[storage(VecStorage)]
 pub struct Pylon;
 
-fn spawn_pylon(prefabs: &PrefabStorage, player_builder: LazyBuilder, x: f32, y: f32) -> Entity {
-    let shape = ShapeHandle::new(Ball::new(44.0));
+pub const PYLON_RADIUS: f32 = 44.0;
+
+fn spawn_pylon(_assets: &Assets, player_builder: LazyBuilder, x: f32, y: f32) -> Entity {
+    let shape = ShapeHandle::new(Ball::new(PYLON_RADIUS));
     let body = RigidBodyDesc::new().status(BodyStatus::Static);
     let collider = ColliderDesc::new(shape);
     let mut transform = Transform::default();
@@ -94,18 +109,23 @@ fn spawn_pylon(prefabs: &PrefabStorage, player_builder: LazyBuilder, x: f32, y:
 
 const ATTACK_SENSOR_NAME: &'static str = "player_attack_sensor";
 
-fn spawn_attack_sensor(builder: LazyBuilder, player: Entity, direction: Direction) -> Entity {
+fn spawn_attack_sensor(
+    builder: LazyBuilder,
+    player: Entity,
+    direction: Direction,
+    damage: usize,
+) -> Entity {
     let offset = direction.tilts() * 8.0 + direction.clockwise().tilts() * 4.0;
     let shape = ShapeHandle::new(Cuboid::new(Vector2::new(8.0, 8.0)));
     let collider = ColliderDesc::new(shape)
         .sensor(true)
-        .position(Isometry2::new(offset, 0.0));
+        .position(Isometry2::new(offset, direction.angle()));
     builder
         .with(AttachedSensor::new(collider))
         .with(AttackHitbox {
             id: rand::random(),
             hit_type: HitType::FriendlyAttack,
-            damage: 1,
+            damage,
         })
         .with(Parent { entity: player })
         .named(ATTACK_SENSOR_NAME)
@@ -116,10 +136,10 @@ pub fn spawn_player_world(world: &mut World, x: f32, y: f32) -> Entity {
     let entities = world.entities();
     let update = world.write_resource::<LazyUpdate>();
     let builder = update.create_entity(&entities);
-    let prefabs = world.read_resource::<PrefabStorage>();
-    let player = spawn_player(&prefabs, builder, x, y);
+    let assets = world.read_resource::<Assets>();
+    let player = spawn_player(&assets, builder, x, y);
     let builder = update.create_entity(&entities);
-    initialize_camera(builder, &player);
+    initialize_camera(builder, &player, x, y);
     player
 }
 
@@ -127,8 +147,8 @@ pub fn spawn_pylon_world(world: &mut World, x: f32, y: f32) -> Entity {
     let entities = world.entities();
     let update = world.write_resource::<LazyUpdate>();
     let builder = update.create_entity(&entities);
-    let prefabs = world.read_resource::<PrefabStorage>();
-    spawn_pylon(&prefabs, builder, x, y)
+    let assets = world.read_resource::<Assets>();
+    spawn_pylon(&assets, builder, x, y)
 }
 
 struct PlayerAnimationSystem;
@@ -173,16 +193,18 @@ impl<'s> System<'s> for PlayerAttackSystem {
     type SystemData = (
         Read<'s, InputHandler<StringBindings>>,
         Read<'s, Time>,
-        Write<'s, Physics<f32>>,
+        Write<'s, Physics>,
         ReadStorage<'s, AttachedSensor>,
         ReadStorage<'s, PhysicsHandle>,
-        ReadStorage<'s, Named>,
+        Read<'s, SpatialIndex>,
         ReadStorage<'s, AnimationSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, AnimationControlSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, Player>,
         WriteStorage<'s, AttackHitbox>,
         Read<'s, LazyUpdate>,
-        SoundPlayer<'s>,
+        Write<'s, EventChannel<SoundEvent>>,
+        Read<'s, BuildMode>,
+        Read<'s, PlayerUpgrades>,
         Entities<'s>,
     );
 
@@ -194,13 +216,15 @@ impl<'s> System<'s> for PlayerAttackSystem {
             mut physics,
             sensors,
             handles,
-            names,
+            index,
             animation_sets,
             mut control_sets,
             mut player,
             mut attacks,
             lazy,
-            sounds,
+            mut sounds,
+            build,
+            upgrades,
             entities,
         ): Self::SystemData,
     ) {
@@ -211,20 +235,28 @@ impl<'s> System<'s> for PlayerAttackSystem {
             ) {
                 match player.state {
                     PlayerState::Moving => {
-                        if let Some(sensor) =
-                            get_named_entity(&entities, &names, ATTACK_SENSOR_NAME)
-                        {
+                        if let Some(sensor) = index.named(ATTACK_SENSOR_NAME) {
                             lazy.exec(move |world| {
                                 world.delete_entity(sensor);
                             });
                         }
-                        if Some(true) == input.action_is_down("attack") {
+                        if Some(true) == input.action_is_down("attack") && !build.is_active() {
+                            if let Some(aiming) = player.aiming {
+                                player.facing = aiming;
+                            }
                             player.state = PlayerState::Attacking(rand::random());
-                            sounds.sword_slash();
+                            let position = physics
+                                .get_position(handle)
+                                .map(|position| position.translation.vector);
+                            sounds.single_write(SoundEvent {
+                                name: "sword_slash",
+                                position,
+                            });
                             spawn_attack_sensor(
                                 lazy.create_entity(&entities),
                                 entity,
                                 player.facing,
+                                upgrades.sword_damage,
                             );
                             physics.set_velocity(handle, Vector2::new(0.0, 0.0));
                             set_active_animation(
@@ -250,9 +282,7 @@ impl<'s> System<'s> for PlayerAttackSystem {
                         }
                     }
                     PlayerState::Hit(size) => {
-                        if let Some(sensor) =
-                            get_named_entity(&entities, &names, ATTACK_SENSOR_NAME)
-                        {
+                        if let Some(sensor) = index.named(ATTACK_SENSOR_NAME) {
                             lazy.exec(move |world| {
                                 world.delete_entity(sensor);
                             });
@@ -277,70 +307,86 @@ impl<'s> System<'s> for PlayerAttackSystem {
     }
 }
 
+const AIM_THRESHOLD: f32 = 0.5;
+
+fn axis_pair(input: &InputHandler<StringBindings>, x_axis: &str, y_axis: &str) -> Vector2<f32> {
+    Vector2::new(
+        input.axis_value(x_axis).unwrap_or(0.0),
+        input.axis_value(y_axis).unwrap_or(0.0),
+    )
+}
+
 struct PlayerMovementSystem;
 impl<'s> System<'s> for PlayerMovementSystem {
     type SystemData = (
         Read<'s, InputHandler<StringBindings>>,
-        Write<'s, Physics<f32>>,
+        Write<'s, Physics>,
         ReadStorage<'s, PhysicsHandle>,
         ReadStorage<'s, AnimationSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, AnimationControlSet<AnimationId, SpriteRender>>,
         WriteStorage<'s, Player>,
+        Read<'s, PlayerUpgrades>,
         Entities<'s>,
     );
 
     fn run(
         &mut self,
-        (input, mut physics, handles, animation_sets, mut control_sets, mut player, entities): Self::SystemData,
+        (
+            input,
+            mut physics,
+            handles,
+            animation_sets,
+            mut control_sets,
+            mut player,
+            upgrades,
+            entities,
+        ): Self::SystemData,
     ) {
-        let x_tilt = input.axis_value("leftright");
-        let y_tilt = input.axis_value("updown");
-        if let (Some(x_tilt), Some(y_tilt)) = (x_tilt, y_tilt) {
-            if let Some((entity, handle, player)) = (&entities, &handles, &mut player).join().next()
-            {
-                if player.state != PlayerState::Moving {
-                    return;
-                }
-                physics.set_velocity(
-                    handle,
-                    Vector2::new(x_tilt * player.walk_speed, y_tilt * player.walk_speed),
+        let keys = axis_pair(&input, "leftright", "updown");
+        let stick = axis_pair(&input, "pad_leftright", "pad_updown");
+        let aim = axis_pair(&input, "aim_leftright", "aim_updown");
+        // Whichever device is pushed harder wins, so the keyboard and pad can coexist.
+        let tilt = if stick.norm() > keys.norm() {
+            stick
+        } else {
+            keys
+        };
+        let magnitude = f32::min(tilt.norm(), 1.0);
+        if let Some((entity, handle, player)) = (&entities, &handles, &mut player).join().next() {
+            player.aiming = if aim.norm() >= AIM_THRESHOLD {
+                Some(Direction::long_seek(aim))
+            } else {
+                None
+            };
+            if player.state != PlayerState::Moving {
+                return;
+            }
+            if magnitude > 0.0 {
+                physics.set_velocity(handle, tilt.normalize() * magnitude * upgrades.walk_speed);
+            } else {
+                physics.set_velocity(handle, Vector2::zeros());
+            }
+            if let (Some(animation_set), Some(control_set)) = (
+                animation_sets.get(entity),
+                get_animation_set(&mut control_sets, entity),
+            ) {
+                let direction = if magnitude > 0.0 {
+                    Direction::long_seek(tilt)
+                } else {
+                    player.facing
+                };
+                set_active_animation(
+                    control_set,
+                    if magnitude > 0.0 {
+                        AnimationId::Walk(direction)
+                    } else {
+                        AnimationId::Idle(direction)
+                    },
+                    &animation_set,
+                    EndControl::Loop(None),
+                    1.0,
                 );
-                if let (Some(animation_set), Some(control_set)) = (
-                    animation_sets.get(entity),
-                    get_animation_set(&mut control_sets, entity),
-                ) {
-                    let direction = {
-                        if x_tilt != 0.0 || y_tilt != 0.0 {
-                            if f32::abs(x_tilt) >= f32::abs(y_tilt) {
-                                if x_tilt >= 0.0 {
-                                    Direction::East
-                                } else {
-                                    Direction::West
-                                }
-                            } else {
-                                if y_tilt >= 0.0 {
-                                    Direction::North
-                                } else {
-                                    Direction::South
-                                }
-                            }
-                        } else {
-                            player.facing
-                        }
-                    };
-                    set_active_animation(
-                        control_set,
-                        if x_tilt != 0.0 || y_tilt != 0.0 {
-                            AnimationId::Walk(direction)
-                        } else {
-                            AnimationId::Idle(direction)
-                        },
-                        &animation_set,
-                        EndControl::Loop(None),
-                        1.0,
-                    );
-                    player.facing = direction;
-                }
+                player.facing = direction;
             }
         }
     }
@@ -1,10 +1,9 @@
-pub use crate::assets::{AnimationId, Direction, SoundStorage};
+pub use crate::assets::{AnimationId, Assets, Direction};
 pub use crate::physics::{AttachedSensor, PhysicsHandle};
-use amethyst::shred::{ResourceId, SystemData};
 pub use amethyst::{
     animation::*,
     assets::AssetStorage,
-    audio::{output::Output, Source, SourceHandle},
+    audio::{Source, SourceHandle},
     core::{bundle::SystemBundle, timing::Time, transform::components::Parent, Named},
     ecs::*,
     error::Error,
@@ -17,7 +16,7 @@ pub use rand::prelude::*;
 pub fn get_named_entity<'s>(
     entities: &Entities<'s>,
     names: &ReadStorage<'s, Named>,
-    name: &'static str,
+    name: &str,
 ) -> Option<Entity> {
     for (entity, entity_name) in (entities, names).join() {
         if name.eq_ignore_ascii_case(&entity_name.name) {
@@ -27,23 +26,6 @@ pub fn get_named_entity<'s>(
     None
 }
 
-pub fn get_sensors<'s>(
-    entities: &Entities<'s>,
-    sensors: &ReadStorage<'s, AttachedSensor>,
-    parents: &ReadStorage<'s, Parent>,
-    parent_entity: Entity,
-) -> Vec<(PhysicsHandle, Entity)> {
-    let mut found = Vec::new();
-    for (entity, sensor, parent) in (entities, sensors, parents).join() {
-        if parent.entity == parent_entity {
-            if let Some(handles) = sensor.handle {
-                found.push((PhysicsHandle::new(handles.0, handles.1), entity));
-            }
-        }
-    }
-    found
-}
-
 pub fn get_active_animation(
     control_set: &AnimationControlSet<AnimationId, SpriteRender>,
 ) -> Option<AnimationId> {
@@ -79,6 +61,16 @@ pub fn set_active_animation(
     end: EndControl,
     rate_multiplier: f32,
 ) {
+    let id = if animation_set.get(&id).is_some() {
+        id
+    } else {
+        id.fallback()
+    };
+    // Sprites without this animation, or without a sprite at all, just don't animate.
+    let handle = match animation_set.get(&id) {
+        Some(handle) => handle.clone(),
+        None => return,
+    };
     let mut actives = Vec::new();
     for (active_id, animation) in control_set.animations.iter() {
         if animation.state.is_running() && *active_id != id {
@@ -88,81 +80,5 @@ pub fn set_active_animation(
     for active in actives {
         control_set.abort(active);
     }
-    control_set.add_animation(
-        id,
-        &animation_set.get(&id).unwrap(),
-        end,
-        rate_multiplier,
-        AnimationCommand::Start,
-    );
-}
-
-#[derive(SystemData)]
-pub struct SoundPlayer<'a> {
-    storage: Option<Read<'a, SoundStorage>>,
-    output: Option<Read<'a, Output>>,
-    sources: Read<'a, AssetStorage<Source>>,
-}
-
-impl<'a> SoundPlayer<'a> {
-    pub fn player_hit(&self) {
-        if let Some(ref output) = self.output.as_ref() {
-            if let Some(ref sounds) = self.storage.as_ref() {
-                if let Some(sound) = self.sources.get(&sounds.player_hit.clone()) {
-                    output.play_once(sound, 0.75);
-                }
-            }
-        }
-    }
-    pub fn goblin_hit(&self) {
-        if let Some(ref output) = self.output.as_ref() {
-            if let Some(ref sounds) = self.storage.as_ref() {
-                if let Some(sound) = self.sources.get(&sounds.goblin_hit.clone()) {
-                    output.play_once(sound, 0.20);
-                }
-            }
-        }
-    }
-    pub fn pylon_hit(&self) {
-        if let Some(ref output) = self.output.as_ref() {
-            if let Some(ref sounds) = self.storage.as_ref() {
-                if let Some(sound) = self.sources.get(&sounds.pylon_hit.clone()) {
-                    output.play_once(sound, 0.5);
-                }
-            }
-        }
-    }
-    pub fn sword_slash(&self) {
-        if let Some(ref output) = self.output.as_ref() {
-            if let Some(ref sounds) = self.storage.as_ref() {
-                if let Some(sound) = self.sources.get(&sounds.sword_slash.clone()) {
-                    output.play_once(sound, 0.20);
-                }
-            }
-        }
-    }
-    pub fn play_main_theme(&self, sink: &amethyst::audio::AudioSink) {
-        if let Some(ref sounds) = self.storage.as_ref() {
-            if let Some(sound) = self.sources.get(&sounds.main_theme.clone()) {
-                sink.append(sound);
-            }
-        }
-    }
-}
-
-pub struct DjSystem;
-
-impl<'a> System<'a> for DjSystem {
-    type SystemData = (
-        Option<Read<'a, amethyst::audio::AudioSink>>,
-        SoundPlayer<'a>,
-    );
-
-    fn run(&mut self, (sink, player): Self::SystemData) {
-        if let Some(ref sink) = sink {
-            if sink.empty() {
-                player.play_main_theme(sink);
-            }
-        }
-    }
+    control_set.add_animation(id, &handle, end, rate_multiplier, AnimationCommand::Start);
 }
@@ -0,0 +1,192 @@
+use crate::assets::{get_resource, AnimationId, AssetKind, AssetManifest, MyPrefabData};
+use crate::enemies::{Goblin, WaveData};
+use crate::world::{current_map_revision, reload_tile_world, LoadedMap};
+use amethyst::{
+    animation::{AnimationControlSet, AnimationSet},
+    assets::{Handle, Prefab},
+    config::Config,
+    core::Hidden,
+    ecs::*,
+    prelude::*,
+    renderer::SpriteRender,
+};
+use log::{info, warn};
+use std::fs;
+use std::time::{Instant, SystemTime};
+
+const WAVE_FILE: &'static str = "waves.ron";
+/// How long to wait after a prefab file changes before re-applying it, so the asset storage
+/// has picked up the new version.
+const PREFAB_SETTLE_SECS: f32 = 0.5;
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(get_resource(path))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Remembers when the files we reload by hand last changed.
+pub struct ReloadWatcher {
+    prefab_files: Vec<String>,
+    prefabs: Vec<Option<SystemTime>>,
+    waves: Option<SystemTime>,
+    prefab_changed_at: Option<Instant>,
+    /// Hidden entities given a changed goblin prefab, to collect its new animations from.
+    probes: Vec<Entity>,
+}
+
+impl Default for ReloadWatcher {
+    fn default() -> Self {
+        let prefab_files: Vec<String> = AssetManifest::load(get_resource("assets.ron"))
+            .unwrap_or_default()
+            .assets
+            .into_iter()
+            .filter(|(_, entry)| entry.kind == AssetKind::Prefab)
+            .map(|(_, entry)| entry.path)
+            .collect();
+        ReloadWatcher {
+            prefabs: prefab_files.iter().map(|path| modified(path)).collect(),
+            prefab_files,
+            waves: modified(WAVE_FILE),
+            prefab_changed_at: None,
+            probes: Vec::new(),
+        }
+    }
+}
+
+fn reload_map(world: &mut World) {
+    let revision = match current_map_revision(world) {
+        Some(revision) => revision,
+        None => return,
+    };
+    let loaded = world.try_fetch::<LoadedMap>().map(|loaded| loaded.revision);
+    if loaded.map_or(true, |loaded| loaded == revision) {
+        return;
+    }
+    match reload_tile_world(world) {
+        Ok(()) => info!(target: "assets", "Reloaded map (revision {})", revision),
+        Err(err) => {
+            warn!(target: "assets", "Keeping the old map, the new one is broken: {}", err);
+            // Don't retry the broken revision every frame.
+            world.insert(LoadedMap { revision });
+        }
+    }
+}
+
+fn reload_waves(world: &mut World) {
+    let changed = modified(WAVE_FILE);
+    {
+        let mut watcher = world.write_resource::<ReloadWatcher>();
+        if changed == watcher.waves {
+            return;
+        }
+        watcher.waves = changed;
+    }
+    match WaveData::load(get_resource(WAVE_FILE)) {
+        Ok(wave_data) => {
+            info!(target: "assets", "Reloaded waves: {:?}", wave_data.spawns);
+            world.insert(wave_data);
+        }
+        Err(err) => warn!(target: "assets", "Keeping the old waves: {}", err),
+    }
+}
+
+fn reload_prefabs(world: &mut World) {
+    let ready = {
+        let mut watcher = world.write_resource::<ReloadWatcher>();
+        let changed: Vec<Option<SystemTime>> = watcher
+            .prefab_files
+            .iter()
+            .map(|path| modified(path))
+            .collect();
+        if changed != watcher.prefabs {
+            watcher.prefabs = changed;
+            watcher.prefab_changed_at = Some(Instant::now());
+        }
+        match watcher.prefab_changed_at {
+            Some(at) if at.elapsed().as_secs_f32() > PREFAB_SETTLE_SECS => {
+                watcher.prefab_changed_at = None;
+                true
+            }
+            _ => false,
+        }
+    };
+    if ready {
+        probe_goblin_prefabs(world);
+    }
+    swap_goblin_animations(world);
+}
+
+/// Spawns a hidden entity for each goblin prefab in play. The prefab loader applies the new
+/// data to it instead of to the goblins, which would lose their place and state.
+fn probe_goblin_prefabs(world: &mut World) {
+    let mut handles: Vec<Handle<Prefab<MyPrefabData>>> = Vec::new();
+    {
+        let prefabs = world.read_storage::<Handle<Prefab<MyPrefabData>>>();
+        let goblins = world.read_storage::<Goblin>();
+        for (prefab, _) in (&prefabs, &goblins).join() {
+            if !handles.contains(prefab) {
+                handles.push(prefab.clone());
+            }
+        }
+    }
+    let probes: Vec<Entity> = handles
+        .into_iter()
+        .map(|prefab| world.create_entity().with(prefab).with(Hidden).build())
+        .collect();
+    world
+        .write_resource::<ReloadWatcher>()
+        .probes
+        .extend(probes);
+}
+
+/// Hands the animations of every probe the prefab loader has filled in to the goblins sharing
+/// its prefab, then deletes the probe.
+fn swap_goblin_animations(world: &mut World) {
+    let probes = world.read_resource::<ReloadWatcher>().probes.clone();
+    if probes.is_empty() {
+        return;
+    }
+    let done = world.exec(
+        |(entities, prefabs, goblins, mut animation_sets, mut control_sets): (
+            Entities<'_>,
+            ReadStorage<'_, Handle<Prefab<MyPrefabData>>>,
+            ReadStorage<'_, Goblin>,
+            WriteStorage<'_, AnimationSet<AnimationId, SpriteRender>>,
+            WriteStorage<'_, AnimationControlSet<AnimationId, SpriteRender>>,
+        )| {
+            let mut done = Vec::new();
+            for probe in probes.iter() {
+                let (prefab, animations) =
+                    match (prefabs.get(*probe), animation_sets.get(*probe).cloned()) {
+                        (Some(prefab), Some(animations)) => (prefab, animations),
+                        _ => continue,
+                    };
+                for (entity, goblin_prefab, _) in (&entities, &prefabs, &goblins).join() {
+                    if goblin_prefab == prefab {
+                        animation_sets.insert(entity, animations.clone()).ok();
+                        // The animation systems start a fresh control set from the new animations.
+                        control_sets.remove(entity);
+                    }
+                }
+                entities.delete(*probe).ok();
+                done.push(*probe);
+            }
+            done
+        },
+    );
+    if !done.is_empty() {
+        world
+            .write_resource::<ReloadWatcher>()
+            .probes
+            .retain(|probe| !done.contains(probe));
+        info!(target: "assets", "Swapped in new goblin animations");
+    }
+}
+
+/// Picks up edited maps, goblin animations and wave data while the game runs.
+pub fn apply_hot_reloads(world: &mut World) {
+    reload_map(world);
+    reload_waves(world);
+    reload_prefabs(world);
+}
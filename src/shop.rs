@@ -0,0 +1,257 @@
+use crate::assets::get_resource;
+use crate::building::Wallet;
+use crate::combat::{Health, MAX_PYLON_HEALTH};
+use crate::enemies::{Intermission, WaveState};
+use crate::player::{Player, PlayerUpgrades, Pylon};
+use crate::world::{Fence, FENCE_HEALTH};
+use amethyst::{
+    config::Config,
+    core::ParentHierarchy,
+    ecs::*,
+    input::is_close_requested,
+    prelude::*,
+    ui::{UiCreator, UiEventType, UiFinder, UiText, UiTransform},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Seconds left on the wave countdown once the player leaves the shop.
+const COUNTDOWN_AFTER_SHOP: f32 = 3.0;
+const WAVE_DELAY: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ShopItem {
+    pub cost: usize,
+    pub amount: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ShopConfig {
+    pub walk_speed: ShopItem,
+    pub sword_damage: ShopItem,
+    pub max_health: ShopItem,
+    pub pylon_repair: ShopItem,
+    pub fence_repair: ShopItem,
+}
+
+impl Default for ShopConfig {
+    fn default() -> Self {
+        ShopConfig {
+            walk_speed: ShopItem {
+                cost: 5,
+                amount: 15,
+            },
+            sword_damage: ShopItem { cost: 8, amount: 1 },
+            max_health: ShopItem { cost: 6, amount: 4 },
+            pylon_repair: ShopItem { cost: 4, amount: 8 },
+            fence_repair: ShopItem { cost: 3, amount: 4 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Upgrade {
+    WalkSpeed,
+    SwordDamage,
+    MaxHealth,
+    PylonRepair,
+    FenceRepair,
+}
+
+const UPGRADES: &'static [(&'static str, Upgrade)] = &[
+    ("buy_walk_speed", Upgrade::WalkSpeed),
+    ("buy_sword_damage", Upgrade::SwordDamage),
+    ("buy_max_health", Upgrade::MaxHealth),
+    ("buy_pylon_repair", Upgrade::PylonRepair),
+    ("buy_fence_repair", Upgrade::FenceRepair),
+];
+
+impl Upgrade {
+    fn item(&self, config: &ShopConfig) -> ShopItem {
+        match self {
+            Upgrade::WalkSpeed => config.walk_speed,
+            Upgrade::SwordDamage => config.sword_damage,
+            Upgrade::MaxHealth => config.max_health,
+            Upgrade::PylonRepair => config.pylon_repair,
+            Upgrade::FenceRepair => config.fence_repair,
+        }
+    }
+}
+
+/// Applies an upgrade if the wallet can pay for it, returning what happened.
+fn buy(world: &mut World, upgrade: Upgrade) -> String {
+    world.exec(
+        |(config, mut wallet, mut upgrades, players, pylons, fences, mut healths): (
+            Read<'_, ShopConfig>,
+            Write<'_, Wallet>,
+            Write<'_, PlayerUpgrades>,
+            ReadStorage<'_, Player>,
+            ReadStorage<'_, Pylon>,
+            ReadStorage<'_, Fence>,
+            WriteStorage<'_, Health>,
+        )| {
+            let item = upgrade.item(&config);
+            if wallet.coins < item.cost {
+                return format!("Need {} coins", item.cost);
+            }
+            match upgrade {
+                Upgrade::WalkSpeed => upgrades.walk_speed += item.amount as f32,
+                Upgrade::SwordDamage => upgrades.sword_damage += item.amount,
+                Upgrade::MaxHealth => {
+                    upgrades.max_health += item.amount;
+                    for (_, health) in (&players, &mut healths).join() {
+                        health.current_health += item.amount;
+                    }
+                }
+                Upgrade::PylonRepair => {
+                    let repaired = (&pylons, &mut healths).join().any(|(_, health)| {
+                        let before = health.current_health;
+                        health.current_health =
+                            usize::min(health.current_health + item.amount, MAX_PYLON_HEALTH);
+                        health.current_health > before
+                    });
+                    if !repaired {
+                        return "The pylon is already at full health".to_string();
+                    }
+                }
+                // The fences go back up once the next wave starts running systems again.
+                Upgrade::FenceRepair => {
+                    let mut repaired = 0;
+                    for (fence, health) in (&fences, &mut healths).join() {
+                        if repaired == item.amount {
+                            break;
+                        }
+                        if fence.is_broken() {
+                            *health = Health::new(true, FENCE_HEALTH);
+                            repaired += 1;
+                        }
+                    }
+                    if repaired == 0 {
+                        return "No fences need repairing".to_string();
+                    }
+                }
+            }
+            wallet.coins -= item.cost;
+            info!(target: "waves", "Bought {:?} for {}", upgrade, item.cost);
+            format!("Bought {:?}", upgrade)
+        },
+    )
+}
+
+pub fn load_shop_config() -> ShopConfig {
+    ShopConfig::load(get_resource("upgrades.ron")).unwrap_or_else(|err| {
+        warn!(target: "waves", "Could not read upgrades.ron: {}", err);
+        ShopConfig::default()
+    })
+}
+
+/// The upgrade shop, pushed over gameplay between waves. The next wave waits until it's closed.
+#[derive(Default)]
+pub struct ShopState {
+    root: Option<Entity>,
+    status: String,
+}
+
+impl ShopState {
+    fn refresh_labels(&self, world: &mut World) {
+        let status = self.status.clone();
+        world.exec(
+            |(config, wallet, transforms, mut texts): (
+                Read<'_, ShopConfig>,
+                Read<'_, Wallet>,
+                ReadStorage<'_, UiTransform>,
+                WriteStorage<'_, UiText>,
+            )| {
+                for (transform, text) in (&transforms, &mut texts).join() {
+                    if transform.id.eq("shop_coins") {
+                        text.text = format!("Coins: {}", wallet.coins);
+                    } else if transform.id.eq("shop_status") {
+                        text.text = status.clone();
+                    }
+                    for (name, upgrade) in UPGRADES.iter() {
+                        if transform.id.eq(&format!("{}_cost", name)) {
+                            text.text = format!("{}", upgrade.item(&config).cost);
+                        }
+                    }
+                }
+            },
+        );
+    }
+}
+
+impl SimpleState for ShopState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        {
+            let wave_num = data.world.read_resource::<WaveState>().wave_num;
+            let mut intermission = data.world.write_resource::<Intermission>();
+            intermission.shopping = true;
+            intermission.shopped_wave = wave_num;
+        }
+        self.root = Some(
+            data.world
+                .exec(|mut creator: UiCreator<'_>| creator.create(get_resource("shop.ron"), ())),
+        );
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        data.world.write_resource::<Intermission>().shopping = false;
+        {
+            let mut waves = data.world.write_resource::<WaveState>();
+            waves.idle_time = f32::min(waves.idle_time, WAVE_DELAY - COUNTDOWN_AFTER_SHOP);
+        }
+        if let Some(root) = self.root.take() {
+            let mut shop_entities: Vec<Entity> = data
+                .world
+                .read_resource::<ParentHierarchy>()
+                .all_children_iter(root)
+                .collect();
+            shop_entities.push(root);
+            data.world.delete_entities(&shop_entities).ok();
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        data: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        match &event {
+            StateEvent::Window(event) => {
+                if is_close_requested(&event) {
+                    Trans::Quit
+                } else {
+                    Trans::None
+                }
+            }
+            StateEvent::Ui(ui_event) => {
+                if ui_event.event_type != UiEventType::Click {
+                    return Trans::None;
+                }
+                let clicked = data.world.exec(|finder: UiFinder<'_>| {
+                    if finder.find("shop_done") == Some(ui_event.target) {
+                        return Err(());
+                    }
+                    Ok(UPGRADES
+                        .iter()
+                        .find(|(name, _)| finder.find(name) == Some(ui_event.target))
+                        .map(|(_, upgrade)| *upgrade))
+                });
+                match clicked {
+                    Err(()) => Trans::Pop,
+                    Ok(Some(upgrade)) => {
+                        self.status = buy(data.world, upgrade);
+                        Trans::None
+                    }
+                    Ok(None) => Trans::None,
+                }
+            }
+            _ => Trans::None,
+        }
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        self.refresh_labels(data.world);
+        SimpleTrans::None
+    }
+}
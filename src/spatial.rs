@@ -0,0 +1,107 @@
+use crate::physics::{AttachedSensor, Physics, PhysicsHandle};
+use crate::prelude::*;
+use std::collections::HashMap;
+
+/// Width of a grid cell, about the reach of a goblin attack.
+pub const CELL_SIZE: f32 = 64.0;
+
+pub fn cell_of(position: Vector2<f32>) -> (i32, i32) {
+    (
+        (position.x / CELL_SIZE).floor() as i32,
+        (position.y / CELL_SIZE).floor() as i32,
+    )
+}
+
+/// Lookups rebuilt once a frame so systems don't scan every entity for each goblin.
+#[derive(Debug, Default)]
+pub struct SpatialIndex {
+    /// Lower-cased names, matching `get_named_entity`.
+    names: HashMap<String, Entity>,
+    sensors: HashMap<Entity, Vec<(PhysicsHandle, Entity)>>,
+    cells: HashMap<(i32, i32), Vec<(Entity, Vector2<f32>)>>,
+}
+
+impl SpatialIndex {
+    pub fn named(&self, name: &str) -> Option<Entity> {
+        self.names.get(&name.to_ascii_lowercase()).cloned()
+    }
+
+    /// The sensors attached to `parent`.
+    pub fn sensors_of(&self, parent: Entity) -> &[(PhysicsHandle, Entity)] {
+        self.sensors
+            .get(&parent)
+            .map(|sensors| sensors.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Bodies within `radius` of `center`, with their positions.
+    pub fn within(&self, center: Vector2<f32>, radius: f32) -> Vec<(Entity, Vector2<f32>)> {
+        let (min_x, min_y) = cell_of(center - Vector2::new(radius, radius));
+        let (max_x, max_y) = cell_of(center + Vector2::new(radius, radius));
+        let mut found = Vec::new();
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    found.extend(
+                        cell.iter()
+                            .filter(|(_, position)| (position - center).norm() <= radius),
+                    );
+                }
+            }
+        }
+        found
+    }
+
+    fn clear(&mut self) {
+        self.names.clear();
+        self.sensors.clear();
+        for cell in self.cells.values_mut() {
+            cell.clear();
+        }
+    }
+}
+
+pub struct SpatialIndexSystem;
+
+impl<'s> System<'s> for SpatialIndexSystem {
+    type SystemData = (
+        Write<'s, SpatialIndex>,
+        Read<'s, Physics>,
+        ReadStorage<'s, Named>,
+        ReadStorage<'s, PhysicsHandle>,
+        ReadStorage<'s, AttachedSensor>,
+        ReadStorage<'s, Parent>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (mut index, physics, names, handles, sensors, parents, entities): Self::SystemData,
+    ) {
+        index.clear();
+        for (entity, name) in (&entities, &names).join() {
+            index
+                .names
+                .entry(name.name.to_ascii_lowercase())
+                .or_insert(entity);
+        }
+        for (entity, sensor, parent) in (&entities, &sensors, &parents).join() {
+            if let Some(handle) = &sensor.handle {
+                index
+                    .sensors
+                    .entry(parent.entity)
+                    .or_insert_with(Vec::new)
+                    .push((handle.clone(), entity));
+            }
+        }
+        for (entity, handle) in (&entities, &handles).join() {
+            if let Some(location) = physics.get_location(handle) {
+                index
+                    .cells
+                    .entry(cell_of(location.coords))
+                    .or_insert_with(Vec::new)
+                    .push((entity, location.coords));
+            }
+        }
+    }
+}
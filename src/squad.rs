@@ -0,0 +1,173 @@
+use crate::enemies::Goblin;
+use crate::physics::Physics;
+use crate::player::PYLON_RADIUS;
+use crate::prelude::*;
+use crate::spatial::SpatialIndex;
+use log::debug;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+/// Attack positions spaced evenly around the pylon.
+const SLOT_COUNT: usize = 8;
+/// How far outside the pylon's collider an attack slot sits.
+const SLOT_MARGIN: f32 = 14.0;
+/// How close to its slot a goblin has to be before it swings.
+const SLOT_ARRIVAL: f32 = 12.0;
+const MAX_ATTACKERS: usize = 4;
+const CIRCLE_RADIUS: f32 = PYLON_RADIUS + 46.0;
+/// Pixels per second the circling point moves, a little slower than a goblin walks.
+const CIRCLE_SPEED: f32 = 30.0;
+
+/// Where a goblin going for the pylon should head.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SquadOrder {
+    Attack(Vector2<f32>),
+    Circle(Vector2<f32>),
+}
+
+impl SquadOrder {
+    pub fn point(&self) -> Vector2<f32> {
+        match self {
+            SquadOrder::Attack(point) => *point,
+            SquadOrder::Circle(point) => *point,
+        }
+    }
+
+    /// Whether a goblin standing at `location` is close enough to its slot to attack.
+    pub fn in_position(&self, location: Vector2<f32>) -> bool {
+        match self {
+            SquadOrder::Attack(point) => (point - location).norm() < SLOT_ARRIVAL,
+            SquadOrder::Circle(_) => false,
+        }
+    }
+}
+
+fn angle_of(offset: Vector2<f32>) -> f32 {
+    f32::atan2(offset.y, offset.x)
+}
+
+fn on_ring(center: Vector2<f32>, radius: f32, angle: f32) -> Vector2<f32> {
+    center + Vector2::new(f32::cos(angle), f32::sin(angle)) * radius
+}
+
+fn slot_angle(slot: usize) -> f32 {
+    slot as f32 * 2.0 * PI / SLOT_COUNT as f32
+}
+
+/// Shortest angle between two headings, in radians.
+fn angle_between(a: f32, b: f32) -> f32 {
+    let difference = (a - b).rem_euclid(2.0 * PI);
+    f32::min(difference, 2.0 * PI - difference)
+}
+
+/// Hands out attack slots around the pylon so goblins spread out instead of queueing on one
+/// side, and keeps the ones without a slot circling until one frees up.
+#[derive(Debug, Default)]
+pub struct SquadCoordinator {
+    center: Option<Vector2<f32>>,
+    slots: HashMap<Entity, usize>,
+    /// Angle around the pylon each waiting goblin is circling towards.
+    waiting: HashMap<Entity, f32>,
+}
+
+impl SquadCoordinator {
+    pub fn order(&self, goblin: Entity) -> Option<SquadOrder> {
+        let center = self.center?;
+        if let Some(slot) = self.slots.get(&goblin) {
+            Some(SquadOrder::Attack(on_ring(
+                center,
+                PYLON_RADIUS + SLOT_MARGIN,
+                slot_angle(*slot),
+            )))
+        } else {
+            self.waiting
+                .get(&goblin)
+                .map(|angle| SquadOrder::Circle(on_ring(center, CIRCLE_RADIUS, *angle)))
+        }
+    }
+
+    /// The free slot closest to where the goblin already is.
+    fn nearest_free_slot(&self, angle: f32) -> Option<usize> {
+        (0..SLOT_COUNT)
+            .filter(|slot| !self.slots.values().any(|taken| taken == slot))
+            .min_by(|a, b| {
+                angle_between(slot_angle(*a), angle)
+                    .partial_cmp(&angle_between(slot_angle(*b), angle))
+                    .unwrap()
+            })
+    }
+}
+
+pub struct SquadSystem;
+
+impl<'s> System<'s> for SquadSystem {
+    type SystemData = (
+        Write<'s, SquadCoordinator>,
+        Read<'s, Physics>,
+        Read<'s, Time>,
+        Read<'s, SpatialIndex>,
+        ReadStorage<'s, PhysicsHandle>,
+        ReadStorage<'s, Goblin>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (mut squad, physics, time, index, handles, goblins, entities): Self::SystemData,
+    ) {
+        let pylon = index.named("pylon");
+        let center = pylon
+            .and_then(|pylon| handles.get(pylon))
+            .and_then(|handle| physics.get_location(handle))
+            .map(|location| location.coords);
+        squad.center = center;
+        let (pylon, center) = match (pylon, center) {
+            (Some(pylon), Some(center)) => (pylon, center),
+            _ => {
+                squad.slots.clear();
+                squad.waiting.clear();
+                return;
+            }
+        };
+
+        // The target stays set through a swing, so attackers keep their slot until they back
+        // off to rest.
+        let mut members = Vec::new();
+        for (entity, goblin, handle) in (&entities, &goblins, &handles).join() {
+            let member = goblin.blackboard.target == Some(pylon);
+            if let (true, Some(location)) = (member, physics.get_location(handle)) {
+                members.push((entity, angle_of(location.coords - center)));
+            }
+        }
+        squad
+            .slots
+            .retain(|entity, _| members.iter().any(|(member, _)| member == entity));
+        squad
+            .waiting
+            .retain(|entity, _| members.iter().any(|(member, _)| member == entity));
+
+        for (entity, angle) in members.into_iter() {
+            if squad.slots.contains_key(&entity) {
+                continue;
+            }
+            let free = if squad.slots.len() < MAX_ATTACKERS {
+                squad.nearest_free_slot(angle)
+            } else {
+                None
+            };
+            if let Some(slot) = free {
+                debug!(target: "ai", "Goblin {:?} takes attack slot {}", entity, slot);
+                squad.waiting.remove(&entity);
+                squad.slots.insert(entity, slot);
+            } else if !squad.waiting.contains_key(&entity) {
+                debug!(target: "ai", "Goblin {:?} circles the pylon", entity);
+                squad.waiting.insert(entity, angle);
+            }
+        }
+
+        let step = CIRCLE_SPEED / CIRCLE_RADIUS * time.delta_seconds();
+        for angle in squad.waiting.values_mut() {
+            *angle = (*angle + step).rem_euclid(2.0 * PI);
+        }
+    }
+}
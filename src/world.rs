@@ -1,10 +1,15 @@
-use crate::assets::{MapStorage, SpriteStorage, TiledMap};
-use crate::enemies::{spawn_spawner_world, spawn_waypoint_world, Waypoint};
+use crate::ai::Blackboard;
+use crate::assets::{Assets, TiledMap};
+use crate::combat::Health;
+use crate::enemies::{spawn_spawner_world, spawn_waypoint_world, Goblin, Waypoint};
+use crate::error::GameError;
+use crate::level::*;
 use crate::physics::*;
-use crate::player::{spawn_player_world, spawn_pylon_world};
+use crate::player::{spawn_player_world, spawn_pylon_world, Player};
+use crate::prelude::get_named_entity;
 use amethyst::{
     assets::{AssetStorage, Format},
-    core::{bundle::SystemBundle, math::Point3, transform::*},
+    core::{bundle::SystemBundle, math::Point3, transform::*, Named, Time},
     ecs::*,
     error::Error,
     input::{InputHandler, StringBindings},
@@ -12,27 +17,167 @@ use amethyst::{
     renderer::{camera::*, SpriteRender},
     tiles::{MortonEncoder, Tile, TileMap},
 };
+use log::{debug, info, warn};
 use ncollide2d::shape::*;
 use nphysics2d::object::*;
+use std::collections::{HashMap, HashSet};
 
 pub struct WorldTiles {
     layer0: Vec<Vec<tiled::LayerTile>>,
+    fences: HashSet<(usize, usize)>,
+    shaped: HashMap<(usize, usize), Vec<TileCollider>>,
+    /// Terrain by tile id, for the tiles that aren't plain ground.
+    terrain: HashMap<usize, Terrain>,
+    /// What each fence tile id looks like knocked down.
+    broken_variants: HashMap<usize, usize>,
+    /// The original tile ids of fences that are knocked down.
+    broken: HashMap<(usize, usize), usize>,
     map_size: (u32, u32),
     tile_size: (u32, u32),
 }
 
 impl WorldTiles {
     pub fn get_id(&self, x: usize, y: usize) -> Option<usize> {
-        self.layer0
-            .get(y)
-            .and_then(|row| row.get(x))
-            .and_then(|tile| {
-                if tile.gid > 0 {
-                    Some(tile.gid as usize - 1)
-                } else {
-                    None
-                }
+        layer_tile_id(&self.layer0, x, y)
+    }
+
+    pub fn tile_at(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        tile_at(x, y, self.map_size, self.tile_size)
+    }
+
+    pub fn tile_center(&self, tile: (usize, usize)) -> (f32, f32) {
+        tile_position(tile.0 as u32, tile.1 as u32, self.map_size, self.tile_size)
+    }
+
+    pub fn tile_size(&self) -> (u32, u32) {
+        self.tile_size
+    }
+
+    /// The terrain under a world position. Off the map is plain ground.
+    pub fn terrain_at(&self, x: f32, y: f32) -> Terrain {
+        self.tile_at(x, y)
+            .and_then(|(tx, ty)| self.get_id(tx, ty))
+            .and_then(|id| self.terrain.get(&id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether a tile has ground and no fence on it.
+    pub fn is_open(&self, tile: (usize, usize)) -> bool {
+        self.get_id(tile.0, tile.1).is_some() && !self.fences.contains(&tile)
+    }
+
+    /// Whether a tile has a standing fence on it.
+    pub fn is_fence(&self, tile: (usize, usize)) -> bool {
+        self.fences.contains(&tile)
+    }
+
+    fn set_id(&mut self, tile: (usize, usize), id: usize) {
+        if let Some(layer_tile) = self
+            .layer0
+            .get_mut(tile.1)
+            .and_then(|row| row.get_mut(tile.0))
+        {
+            layer_tile.gid = id as u32 + 1;
+        }
+    }
+
+    /// Swaps a fence for its broken variant, which no longer blocks.
+    pub fn break_fence(&mut self, tile: (usize, usize)) {
+        if let (true, Some(id)) = (self.fences.remove(&tile), self.get_id(tile.0, tile.1)) {
+            let broken = self.broken_variants.get(&id).cloned().unwrap_or(id);
+            self.broken.insert(tile, id);
+            self.set_id(tile, broken);
+        }
+    }
+
+    pub fn repair_fence(&mut self, tile: (usize, usize)) {
+        if let Some(id) = self.broken.remove(&tile) {
+            self.set_id(tile, id);
+            self.fences.insert(tile);
+        }
+    }
+
+    /// Colliders for every standing fence and shaped tile, in world space.
+    fn fence_colliders(&self) -> Vec<ColliderDesc<f32>> {
+        let blocks = fence_rects(&self.fences, &self.shaped)
+            .into_iter()
+            .map(|rect| {
+                let ((x, y), (half_width, half_height)) =
+                    rect.bounds(self.map_size, self.tile_size);
+                let shape =
+                    ShapeHandle::new(Cuboid::new(na::Vector2::new(half_width, half_height)));
+                ColliderDesc::new(shape).position(na::Isometry2::translation(x, y))
+            });
+        let shaped = self
+            .shaped
+            .iter()
+            .filter(|(tile, _)| !self.broken.contains_key(tile))
+            .flat_map(|(tile, colliders)| {
+                let (tx, ty) = self.tile_center(*tile);
+                colliders.iter().map(move |collider| {
+                    ColliderDesc::new(collider.shape.clone())
+                        .position(na::Isometry2::translation(tx, ty) * collider.position)
+                })
+            });
+        blocks.chain(shaped).collect()
+    }
+
+    /// World-space corners of the map, from the bottom-left tile edge to the top-right one.
+    pub fn bounds(&self) -> (na::Vector2<f32>, na::Vector2<f32>) {
+        let (tile_width, tile_height) = (self.tile_size.0 as f32, self.tile_size.1 as f32);
+        let (map_width, map_height) = (self.map_size.0 as f32, self.map_size.1 as f32);
+        let left = -(tile_width * map_width / 2.0) - tile_width / 2.0;
+        let top = (tile_height * map_height / 2.0) + tile_height / 2.0;
+        (
+            na::Vector2::new(left, top - tile_height * map_height),
+            na::Vector2::new(left + tile_width * map_width, top),
+        )
+    }
+}
+
+#[cfg(test)]
+impl WorldTiles {
+    /// Tile id of ground in `from_rows`.
+    pub const TEST_GROUND: usize = 0;
+    /// Tile id of a standing fence in `from_rows`.
+    pub const TEST_FENCE: usize = 5;
+    /// Tile id a `TEST_FENCE` turns into when it's knocked down.
+    pub const TEST_BROKEN: usize = 9;
+
+    /// A map of 32 pixel tiles drawn as text, `.` for ground and `f` for fence.
+    pub fn from_rows(rows: &[&str]) -> Self {
+        let mut fences = HashSet::new();
+        let layer0 = rows
+            .iter()
+            .enumerate()
+            .map(|(y, row)| {
+                row.chars()
+                    .enumerate()
+                    .map(|(x, tile)| {
+                        let id = if tile == 'f' {
+                            fences.insert((x, y));
+                            Self::TEST_FENCE
+                        } else {
+                            Self::TEST_GROUND
+                        };
+                        tiled::LayerTile::new(id as u32 + 1)
+                    })
+                    .collect()
             })
+            .collect();
+        let mut broken_variants = HashMap::new();
+        broken_variants.insert(Self::TEST_FENCE, Self::TEST_BROKEN);
+        WorldTiles {
+            layer0,
+            fences,
+            shaped: HashMap::new(),
+            terrain: HashMap::new(),
+            broken_variants,
+            broken: HashMap::new(),
+            map_size: (rows[0].len() as u32, rows.len() as u32),
+            tile_size: (32, 32),
+        }
     }
 }
 
@@ -46,90 +191,235 @@ impl Tile for WorldTile {
     }
 }
 
-fn initialize_fence_post(world: &mut World, transform: Transform, parent: Entity) {
+/// Puts every fence on one static body, with a collider per merged block of fence tiles
+/// instead of a body per tile, so there are no seams for characters to snag on. Tiles with
+/// shapes drawn in Tiled get those instead of a box. Rebuilt whenever a fence breaks or is
+/// repaired.
+fn build_fence_body<B: Builder>(builder: B, tiles: &WorldTiles, parent: Entity) -> Option<Entity> {
+    let mut colliders = tiles.fence_colliders().into_iter();
+    let first = colliders.next()?;
     let body = RigidBodyDesc::new().status(BodyStatus::Static);
-    let shape = ShapeHandle::new(Cuboid::new(na::Vector2::new(16.0, 16.0)));
-    let collider = ColliderDesc::new(shape);
-    world
-        .create_entity()
-        .with(PhysicsDesc::new(body, collider))
-        .with(transform)
-        .with(Parent { entity: parent })
-        .build();
+    let desc = colliders.fold(PhysicsDesc::new(body, first), |desc, collider| {
+        desc.with_collider(collider)
+    });
+    Some(
+        builder
+            .with(desc)
+            .with(Transform::default())
+            .with(Parent { entity: parent })
+            .with(MapPart)
+            .with(FenceBody)
+            .build(),
+    )
 }
 
-fn get_map(
-    world: &World,
-) -> (
-    WorldTiles,
-    tiled::Tileset,
-    WorldTiles,
-    (u32, u32),
-    (u32, u32),
-) {
-    let maps = world.read_resource::<MapStorage>();
-    let map_assets = world.read_resource::<AssetStorage<TiledMap>>();
-    let tiled_map = map_assets.get(&maps.village_map).unwrap();
-    let map_size = (tiled_map.0.width, tiled_map.0.height);
-    let tile_size = (tiled_map.0.tile_width, tiled_map.0.tile_height);
-    (
-        WorldTiles {
-            layer0: tiled_map.0.layers.get(0).unwrap().tiles.clone(),
-            map_size,
-            tile_size,
-        },
-        tiled_map.0.tilesets.get(0).unwrap().clone(),
-        WorldTiles {
-            layer0: tiled_map.0.layers.get(1).unwrap().tiles.clone(),
-            map_size,
-            tile_size,
-        },
-        map_size,
-        tile_size,
-    )
+/// Fence hit points; goblin swings take one each.
+pub const FENCE_HEALTH: usize = 4;
+/// How far from a fence tile's centre a swing still reaches it.
+const FENCE_REACH: f32 = 32.0;
+
+/// A fence tile that can be knocked down. Its collision lives on the shared `FenceBody`.
+#[derive(Component, Debug)]
+#[storage(VecStorage)]
+pub struct Fence {
+    pub tile: (usize, usize),
+    pub position: na::Vector2<f32>,
+    broken: bool,
+}
+
+impl Fence {
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
 }
 
-fn tile_type(tileset: &tiled::Tileset, tile_id: usize) -> String {
-    for tile in tileset.tiles.iter() {
-        if tile.id == tile_id as u32 {
-            if let Some(tile_type) = &tile.tile_type {
-                return tile_type.to_string();
+/// The static body holding every fence collider.
+#[derive(Component, Debug, Default)]
+#[storage(NullStorage)]
+pub struct FenceBody;
+
+/// The standing fence nearest `location`, if a swing there would reach one.
+pub fn fence_near(
+    location: na::Vector2<f32>,
+    entities: &Entities<'_>,
+    fences: &ReadStorage<'_, Fence>,
+) -> Option<Entity> {
+    (entities, fences)
+        .join()
+        .filter(|(_, fence)| !fence.broken)
+        .map(|(entity, fence)| (entity, (fence.position - location).norm()))
+        .filter(|(_, distance)| *distance <= FENCE_REACH)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(entity, _)| entity)
+}
+
+/// Knocks fences down when their health runs out and puts them back once it's restored, then
+/// rebuilds the fence body to match.
+pub struct FenceSystem;
+
+impl<'s> System<'s> for FenceSystem {
+    type SystemData = (
+        Option<Write<'s, WorldTiles>>,
+        WriteStorage<'s, Fence>,
+        ReadStorage<'s, Health>,
+        ReadStorage<'s, FenceBody>,
+        ReadStorage<'s, TileMap<WorldTile, MortonEncoder>>,
+        Read<'s, LazyUpdate>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (tiles, mut fences, healths, bodies, maps, lazy, entities): Self::SystemData,
+    ) {
+        let mut tiles = match tiles {
+            Some(tiles) => tiles,
+            None => return,
+        };
+        let mut changed = false;
+        for (fence, health) in (&mut fences, &healths).join() {
+            let broken = health.current_health == 0;
+            if broken == fence.broken {
+                continue;
+            }
+            fence.broken = broken;
+            changed = true;
+            if broken {
+                debug!(target: "world", "Fence at {:?} knocked down", fence.tile);
+                tiles.break_fence(fence.tile);
             } else {
-                return "".to_string();
+                debug!(target: "world", "Fence at {:?} repaired", fence.tile);
+                tiles.repair_fence(fence.tile);
             }
         }
+        if !changed {
+            return;
+        }
+        for (old, _) in (&entities, &bodies).join() {
+            entities.delete(old).ok();
+        }
+        if let Some((map, _)) = (&entities, &maps).join().next() {
+            build_fence_body(lazy.create_entity(&entities), &tiles, map);
+        }
     }
-    "".to_string()
 }
 
-fn is_fence(tileset: &tiled::Tileset, tile_id: usize) -> bool {
-    tile_type(tileset, tile_id).eq("f")
+/// How far ahead of a goblin's centre to look for a tile it may not walk into, about its radius.
+const ENEMY_LOOKAHEAD: f32 = 10.0;
+
+/// Applies the terrain under the player and goblins: mud slows them and roads speed them up,
+/// goblins stop at tiles they can't cross, and standing in something harmful hurts.
+#[derive(Default)]
+pub struct TerrainSystem {
+    /// Damage taken that hasn't added up to a whole point yet.
+    burns: HashMap<Entity, f32>,
 }
 
-fn closest_waypoint(
-    tx: f32,
-    ty: f32,
-    waypoints: &Vec<(f32, f32, usize)>,
-    of_type: Option<usize>,
-) -> (usize, f32) {
-    let mut best = 0;
-    let mut best_distance = 999999999999.0;
-    for i in 0..waypoints.len() {
-        let (wx, wy, wt) = waypoints.get(i).unwrap();
-        let dx = tx - wx;
-        let dy = ty - wy;
-        let distance = dx * dx + dy * dy;
-        if let Some(waypoint_type) = of_type {
-            if *wt != waypoint_type - 1 {
+impl<'s> System<'s> for TerrainSystem {
+    type SystemData = (
+        Option<Read<'s, WorldTiles>>,
+        Write<'s, Physics>,
+        Read<'s, Time>,
+        ReadStorage<'s, PhysicsHandle>,
+        ReadStorage<'s, Player>,
+        ReadStorage<'s, Goblin>,
+        WriteStorage<'s, Health>,
+        Entities<'s>,
+    );
+
+    fn run(
+        &mut self,
+        (tiles, mut physics, time, handles, players, goblins, mut healths, entities): Self::SystemData,
+    ) {
+        let tiles = match tiles {
+            Some(tiles) => tiles,
+            None => return,
+        };
+        for (entity, handle) in (&entities, &handles).join() {
+            let enemy = goblins.contains(entity);
+            if !enemy && !players.contains(entity) {
                 continue;
             }
+            let location = match physics.get_location(handle) {
+                Some(location) => location.coords,
+                None => continue,
+            };
+            let terrain = tiles.terrain_at(location.x, location.y);
+            physics.set_speed_multiplier(handle, terrain.speed_multiplier);
+            // A goblin already standing on such a tile is let out rather than trapped.
+            if enemy && !terrain.impassable_for_enemies {
+                if let Some(mut velocity) = physics.get_velocity(handle) {
+                    for axis in 0..2 {
+                        let mut ahead = location;
+                        ahead[axis] += velocity[axis].signum() * ENEMY_LOOKAHEAD;
+                        if velocity[axis] != 0.0
+                            && tiles.terrain_at(ahead.x, ahead.y).impassable_for_enemies
+                        {
+                            velocity[axis] = 0.0;
+                        }
+                    }
+                    physics.set_velocity(handle, velocity);
+                }
+            }
+            if terrain.damage_per_second > 0.0 {
+                let burn = self.burns.entry(entity).or_insert(0.0);
+                *burn += terrain.damage_per_second * time.delta_seconds();
+                if let Some(health) = healths.get_mut(entity) {
+                    let points = burn.floor();
+                    health.current_health = health.current_health.saturating_sub(points as usize);
+                    *burn -= points;
+                }
+            } else {
+                self.burns.remove(&entity);
+            }
         }
-        if distance < best_distance {
-            best = i;
-            best_distance = distance;
-        }
+        self.burns.retain(|entity, _| entities.is_alive(*entity));
     }
-    (best, best_distance)
+}
+
+/// Marks entities that belong to the loaded map, so a reload can clear them.
+#[derive(Component, Debug, Default)]
+#[storage(NullStorage)]
+pub struct MapPart;
+
+/// The revision of the `TiledMap` asset the current world was built from.
+pub struct LoadedMap {
+    pub revision: usize,
+}
+
+/// Names of the level map and its tile sheet in `assets.ron`.
+const LEVEL_MAP: &'static str = "village";
+const LEVEL_TILES: &'static str = "tiles";
+
+pub fn current_map_revision(world: &World) -> Option<usize> {
+    let handle = world.read_resource::<Assets>().map(LEVEL_MAP)?;
+    let map_assets = world.read_resource::<AssetStorage<TiledMap>>();
+    map_assets.get(&handle).map(|tiled_map| tiled_map.1)
+}
+
+fn get_map(world: &World) -> Result<(WorldTiles, LevelLayout, usize), GameError> {
+    let handle = world
+        .read_resource::<Assets>()
+        .map(LEVEL_MAP)
+        .ok_or_else(|| GameError::MissingAsset(LEVEL_MAP.to_string()))?;
+    let map_assets = world.read_resource::<AssetStorage<TiledMap>>();
+    let tiled_map = map_assets.get(&handle).ok_or(GameError::MapNotLoaded)?;
+    let (tiles, tileset, _) = map_layers(&tiled_map.0)?;
+    let layout = scan_level(&tiled_map.0)?;
+    Ok((
+        WorldTiles {
+            layer0: tiles.clone(),
+            fences: layout.fences.clone(),
+            shaped: layout.shaped.clone(),
+            terrain: tileset_terrain(tileset),
+            broken_variants: broken_variants(tileset),
+            broken: HashMap::new(),
+            map_size: layout.map_size,
+            tile_size: layout.tile_size,
+        },
+        layout,
+        tiled_map.1,
+    ))
 }
 
 fn follow_waypoints(
@@ -143,9 +433,14 @@ fn follow_waypoints(
             let (wx, wy, wt) = waypoints.get(i).unwrap();
             if *wt > 1 {
                 let (best, distance) = closest_waypoint(*wx, *wy, waypoints, Some(*wt));
-                println!("{} to {}", i, best);
+                debug!(
+                    target: "world",
+                    "Waypoint {} leads to {} ({} away)",
+                    i,
+                    best,
+                    distance.sqrt()
+                );
                 if let Some(mut waypoint_obj) = waypoints_store.get_mut(*ent) {
-                    println!("{}", distance);
                     waypoint_obj.next = waypoint_entities.get(best).cloned();
                 }
             }
@@ -153,12 +448,84 @@ fn follow_waypoints(
     });
 }
 
-pub fn initialize_tile_world(world: &mut World) {
-    let (map, tileset, obj_map, map_size, tile_size) = get_map(world);
-    let tile_spritesheet = {
-        let sprites = world.read_resource::<SpriteStorage>();
-        sprites.tile_spritesheet.clone()
-    };
+/// Where the pylon and the player start, or which marker the level is missing.
+fn actor_positions(layout: &LevelLayout) -> Result<((f32, f32), (f32, f32)), GameError> {
+    let pylon = layout
+        .of_kind(MarkerKind::Pylon)
+        .first()
+        .ok_or(GameError::MissingMarker("Pylon"))?
+        .position;
+    let player = layout
+        .of_kind(MarkerKind::Player)
+        .first()
+        .ok_or(GameError::MissingMarker("Player"))?
+        .position;
+    if !layout.of_kind(MarkerKind::Goblin).is_empty() && layout.waypoints().is_empty() {
+        return Err(GameError::MissingMarker("Waypoint"));
+    }
+    Ok((pylon, player))
+}
+
+pub fn initialize_tile_world(world: &mut World) -> Result<(), GameError> {
+    build_tile_world(world, true).map(|_| ())
+}
+
+/// Rebuilds the map from a changed asset. The player, pylon, goblins and wave progress are kept;
+/// goblins are sent to the nearest of the new waypoints.
+pub fn reload_tile_world(world: &mut World) -> Result<(), GameError> {
+    // Check the new map before tearing the old one down.
+    let (_, layout, _) = get_map(world)?;
+    actor_positions(&layout)?;
+    if let Some(problem) = validate_layout(&layout)
+        .into_iter()
+        .find(|problem| problem.severity == Severity::Error)
+    {
+        return Err(GameError::InvalidMap(problem.message));
+    }
+    let parts: Vec<Entity> = world.exec(
+        |(entities, parts): (Entities<'_>, ReadStorage<'_, MapPart>)| {
+            (&entities, &parts)
+                .join()
+                .map(|(entity, _)| entity)
+                .collect()
+        },
+    );
+    world.delete_entities(&parts).ok();
+    let waypoints = build_tile_world(world, false)?;
+    world.exec(
+        |(transforms, mut goblins): (ReadStorage<'_, Transform>, WriteStorage<'_, Goblin>)| {
+            for (transform, goblin) in (&transforms, &mut goblins).join() {
+                let translation = transform.translation();
+                let positions: Vec<(f32, f32, usize)> =
+                    waypoints.iter().map(|(_, waypoint)| *waypoint).collect();
+                let (closest, _) = closest_waypoint(translation.x, translation.y, &positions, None);
+                if let Some((waypoint, _)) = waypoints.get(closest) {
+                    goblin.blackboard = Blackboard::resting_at(*waypoint, 1.0);
+                }
+            }
+        },
+    );
+    Ok(())
+}
+
+fn build_tile_world(
+    world: &mut World,
+    spawn_actors: bool,
+) -> Result<Vec<(Entity, (f32, f32, usize))>, GameError> {
+    let (map, layout, revision) = get_map(world)?;
+    for problem in validate_layout(&layout).iter() {
+        warn!(target: "world", "{:?}: {}", problem.severity, problem.message);
+    }
+    // Nothing is spawned until the map is known to have everything the level needs.
+    let ((pylon_x, pylon_y), (player_x, player_y)) = actor_positions(&layout)?;
+    world.register::<MapPart>();
+    world.register::<Fence>();
+    world.register::<FenceBody>();
+    let (map_size, tile_size) = (layout.map_size, layout.tile_size);
+    let tile_spritesheet = world
+        .read_resource::<Assets>()
+        .sprite_sheet(LEVEL_TILES)
+        .ok_or_else(|| GameError::MissingAsset(LEVEL_TILES.to_string()))?;
     let map_entity = world
         .create_entity()
         .with(TileMap::<WorldTile, MortonEncoder>::new(
@@ -167,64 +534,66 @@ pub fn initialize_tile_world(world: &mut World) {
             Some(tile_spritesheet),
         ))
         .with(Transform::default())
+        .with(MapPart)
         .build();
-    let mut waypoints = Vec::new();
-    let mut goblins = Vec::new();
-    let mut pylons = Vec::new();
-    for y in 0..map_size.1 {
-        for x in 0..map_size.0 {
-            let tx = (x * tile_size.0) as f32 - (tile_size.0 as f32 * map_size.0 as f32 / 2.0);
-            let ty = (tile_size.1 as f32 * map_size.1 as f32 / 2.0) - (y * tile_size.1) as f32;
-            if let Some(tile_id) = map.get_id(x as usize, y as usize) {
-                if is_fence(&tileset, tile_id) {
-                    let mut transform = Transform::default();
-                    transform.set_translation_xyz(tx, ty, 0.0);
-                    initialize_fence_post(world, transform, map_entity);
-                }
-            }
-            if let Some(obj_id) = obj_map.get_id(x as usize, y as usize) {
-                match tile_type(&tileset, obj_id).as_ref() {
-                    "Goblin" => {
-                        goblins.push((tx, ty));
-                    }
-                    "Waypoint1" => {
-                        waypoints.push((tx, ty, 1));
-                    }
-                    "Waypoint2" => {
-                        waypoints.push((tx, ty, 2));
-                    }
-                    "Waypoint3" => {
-                        waypoints.push((tx, ty, 3));
-                    }
-                    "Waypoint4" => {
-                        waypoints.push((tx, ty, 4));
-                    }
-                    "Waypoint5" => {
-                        waypoints.push((tx, ty, 5));
-                    }
-                    "Pylon" => {
-                        pylons.push((tx, ty));
-                    }
-                    "Player" => {
-                        spawn_player_world(world, tx, ty);
-                    }
-                    _ => {}
-                }
-            }
-        }
+    for (x, y) in layout.fences.iter() {
+        let (tx, ty) = tile_position(*x as u32, *y as u32, map_size, tile_size);
+        world
+            .create_entity()
+            .with(Fence {
+                tile: (*x, *y),
+                position: na::Vector2::new(tx, ty),
+                broken: false,
+            })
+            .with(Health::new(true, FENCE_HEALTH))
+            .with(MapPart)
+            .build();
+    }
+    if build_fence_body(world.create_entity(), &map, map_entity).is_some() {
+        info!(
+            target: "physics",
+            "Fences: {} bodies before merging, 1 after, with {} colliders",
+            layout.fences.union(&layout.shaped.keys().cloned().collect()).count(),
+            map.fence_colliders().len()
+        );
     }
+    let waypoints = layout.waypoints();
+    let goblins: Vec<(f32, f32)> = layout
+        .of_kind(MarkerKind::Goblin)
+        .iter()
+        .map(|marker| marker.position)
+        .collect();
     world.insert::<WorldTiles>(map);
-    let pylon = spawn_pylon_world(world, pylons.get(0).unwrap().0, pylons.get(0).unwrap().1);
-    let mut waypoint_entities: Vec<Entity> = waypoints
+    world.insert(LoadedMap { revision });
+    if spawn_actors {
+        spawn_player_world(world, player_x, player_y);
+        spawn_pylon_world(world, pylon_x, pylon_y);
+    } else {
+        world.exec(
+            |(entities, names, handles, mut physics): (
+                Entities<'_>,
+                ReadStorage<'_, Named>,
+                ReadStorage<'_, PhysicsHandle>,
+                Write<'_, Physics>,
+            )| {
+                if let Some(handle) = get_named_entity(&entities, &names, "pylon")
+                    .and_then(|pylon| handles.get(pylon))
+                {
+                    physics.set_location(handle, pylon_x, pylon_y);
+                }
+            },
+        );
+    }
+    let waypoint_entities: Vec<Entity> = waypoints
         .clone()
         .into_iter()
         .map(|(tx, ty, _)| spawn_waypoint_world(world, tx, ty))
         .collect();
     world.maintain();
     follow_waypoints(world, &waypoints, &waypoint_entities);
-    let mut goblin_entities: Vec<Entity> = goblins
+    let spawner_entities: Vec<Entity> = goblins
         .into_iter()
-        .map(move |(tx, ty)| {
+        .map(|(tx, ty)| {
             spawn_spawner_world(
                 world,
                 tx,
@@ -235,4 +604,73 @@ pub fn initialize_tile_world(world: &mut World) {
             )
         })
         .collect();
+    {
+        let mut parts = world.write_storage::<MapPart>();
+        for entity in waypoint_entities.iter().chain(spawner_entities.iter()) {
+            parts.insert(*entity, MapPart).ok();
+        }
+    }
+    Ok(waypoint_entities
+        .into_iter()
+        .zip(waypoints.into_iter())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terrain_at_looks_up_the_tile_underfoot() {
+        let tsx = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/terrain.tsx"
+        ));
+        let tileset = tiled::parse_tileset(tsx.as_bytes(), 1).expect("terrain.tsx should parse");
+        let mut tiles = WorldTiles::from_rows(&["....."]);
+        tiles.terrain = tileset_terrain(&tileset);
+        // Mud, water, lava, then a tile whose properties match plain ground.
+        for x in 1..5 {
+            tiles.layer0[0][x] = tiled::LayerTile::new(x as u32 + 1);
+        }
+        let at = |tiles: &WorldTiles, x: usize| {
+            let (x, y) = tiles.tile_center((x, 0));
+            tiles.terrain_at(x, y)
+        };
+        assert_eq!(at(&tiles, 0), Terrain::default());
+        assert_eq!(at(&tiles, 1).speed_multiplier, 0.5);
+        assert!(at(&tiles, 2).impassable_for_enemies);
+        assert_eq!(at(&tiles, 3).damage_per_second, 4.0);
+        assert_eq!(at(&tiles, 4), Terrain::default());
+        assert_eq!(tiles.terrain_at(10_000.0, 0.0), Terrain::default());
+    }
+
+    #[test]
+    fn break_and_repair_round_trip() {
+        let mut tiles = WorldTiles::from_rows(&["...", ".f.", "..."]);
+        let fence = (1, 1);
+        assert!(tiles.is_fence(fence));
+        assert_eq!(tiles.fence_colliders().len(), 1);
+
+        tiles.break_fence(fence);
+        assert!(!tiles.is_fence(fence));
+        assert!(tiles.is_open(fence));
+        assert_eq!(tiles.get_id(1, 1), Some(WorldTiles::TEST_BROKEN));
+        assert!(tiles.fence_colliders().is_empty());
+
+        tiles.repair_fence(fence);
+        assert!(tiles.is_fence(fence));
+        assert_eq!(tiles.get_id(1, 1), Some(WorldTiles::TEST_FENCE));
+        assert_eq!(tiles.fence_colliders().len(), 1);
+    }
+
+    #[test]
+    fn only_fences_break() {
+        let mut tiles = WorldTiles::from_rows(&["..", ".f"]);
+        tiles.break_fence((0, 0));
+        assert_eq!(tiles.get_id(0, 0), Some(WorldTiles::TEST_GROUND));
+        tiles.repair_fence((0, 0));
+        assert_eq!(tiles.get_id(0, 0), Some(WorldTiles::TEST_GROUND));
+        assert!(!tiles.is_fence((0, 0)));
+    }
 }
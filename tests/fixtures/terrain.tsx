@@ -0,0 +1,26 @@
+<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.2" tiledversion="1.3.4" name="Terrain" tilewidth="32" tileheight="32" tilecount="8" columns="8">
+ <image source="Tiles.png" width="256" height="32"/>
+ <tile id="1">
+  <properties>
+   <property name="speed_multiplier" type="float" value="0.5"/>
+  </properties>
+ </tile>
+ <tile id="2">
+  <properties>
+   <property name="impassable_for_enemies" type="bool" value="true"/>
+  </properties>
+ </tile>
+ <tile id="3">
+  <properties>
+   <property name="damage_per_second" type="int" value="4"/>
+   <property name="speed_multiplier" type="float" value="0.75"/>
+  </properties>
+ </tile>
+ <tile id="4">
+  <properties>
+   <property name="speed_multiplier" type="float" value="1"/>
+   <property name="label" value="grass"/>
+  </properties>
+ </tile>
+</tileset>